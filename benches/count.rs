@@ -0,0 +1,57 @@
+use rsdb::{Cursor, LEAF_NODE_MAX_CELLS, Pager, Row, Table, db_open_with_pager, deserialize_row};
+use std::hint::black_box;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const ITERATIONS: u32 = 100_000;
+
+fn main() {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let path = std::env::temp_dir()
+        .join(format!("rsdb_bench_count_{}.db", timestamp))
+        .to_string_lossy()
+        .into_owned();
+
+    // a single leaf is as large as the tree gets until splits land. row_count against
+    // parallel_count needs a multi-leaf tree, so that comparison is a #[bench] in
+    // src/lib.rs, where the tree can be built by hand
+    let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+    {
+        let mut cursor = Cursor::table_end(&mut table).unwrap();
+        for id in 1..=LEAF_NODE_MAX_CELLS as u32 {
+            let row = Row {
+                id,
+                username: format!("user{}", id),
+                email: format!("person{}@example.com", id),
+            };
+            Table::insert_at_cursor(&mut cursor, &row).unwrap();
+        }
+    }
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut cursor = Cursor::table_start(&mut table).unwrap();
+        black_box(cursor.total_cells().unwrap());
+    }
+    let total_cells = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut count = 0u64;
+        let mut cursor = Cursor::table_start(&mut table).unwrap();
+        while !cursor.end_of_table {
+            black_box(deserialize_row(cursor.value_ref().unwrap()));
+            count += 1;
+            cursor.advance().unwrap();
+        }
+        black_box(count);
+    }
+    let scan = start.elapsed();
+
+    println!("total_cells: {} counts in {:?}", ITERATIONS, total_cells);
+    println!("full scan:   {} counts in {:?}", ITERATIONS, scan);
+
+    let _ = std::fs::remove_file(&path);
+}
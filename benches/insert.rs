@@ -0,0 +1,68 @@
+use rsdb::{LEAF_NODE_MAX_CELLS, Pager, Row, Table, db_open_with_pager, table_find};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const ROUNDS: usize = 2_000;
+
+fn temp_table(name: &str, round: usize) -> (Table, String) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let path = std::env::temp_dir()
+        .join(format!(
+            "rsdb_bench_insert_{}_{}_{}.db",
+            name, round, timestamp
+        ))
+        .to_string_lossy()
+        .into_owned();
+    let table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+    (table, path)
+}
+
+fn main() {
+    // a full leaf, in descending order so every one-by-one insert shifts the whole leaf
+    let rows: Vec<Row> = (1..=LEAF_NODE_MAX_CELLS as u32)
+        .rev()
+        .map(|id| Row {
+            id,
+            username: format!("user{}", id),
+            email: format!("person{}@example.com", id),
+        })
+        .collect();
+
+    let mut one_by_one = Duration::ZERO;
+    let mut sorted_batch = Duration::ZERO;
+    for round in 0..ROUNDS {
+        // fresh tables each round; only the inserts themselves are timed
+        let (mut table, path) = temp_table("single", round);
+        let start = Instant::now();
+        for row in &rows {
+            let (mut cursor, _) = table_find(&mut table, row.id).unwrap();
+            Table::insert_at_cursor(&mut cursor, row).unwrap();
+        }
+        one_by_one += start.elapsed();
+        drop(table);
+        let _ = std::fs::remove_file(&path);
+
+        let (mut table, path) = temp_table("batch", round);
+        let batch = rows.clone();
+        let start = Instant::now();
+        table.insert_sorted_batch(batch).unwrap();
+        sorted_batch += start.elapsed();
+        drop(table);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    println!(
+        "one by one:         {} x {} rows in {:?}",
+        ROUNDS,
+        rows.len(),
+        one_by_one
+    );
+    println!(
+        "insert_sorted_batch: {} x {} rows in {:?}",
+        ROUNDS,
+        rows.len(),
+        sorted_batch
+    );
+}
@@ -0,0 +1,72 @@
+use rsdb::{Cursor, LEAF_NODE_MAX_CELLS, Pager, Row, Table, db_open_with_pager};
+use std::hint::black_box;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const LOOKUPS: u32 = 100_000;
+const BATCH: usize = 10_000;
+
+fn main() {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let path = std::env::temp_dir()
+        .join(format!("rsdb_bench_lookup_{}.db", timestamp))
+        .to_string_lossy()
+        .into_owned();
+
+    let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+    {
+        let mut cursor = Cursor::table_end(&mut table).unwrap();
+        for id in 1..=LEAF_NODE_MAX_CELLS as u32 {
+            let row = Row {
+                id,
+                username: format!("user{}", id),
+                email: format!("person{}@example.com", id),
+            };
+            Table::insert_at_cursor(&mut cursor, &row).unwrap();
+        }
+    }
+
+    let keys = |i: u32| i % LEAF_NODE_MAX_CELLS as u32 + 1;
+
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        black_box(table.contains(keys(i)).unwrap());
+    }
+    let contains = start.elapsed();
+
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        black_box(table.get(keys(i)).unwrap());
+    }
+    let get = start.elapsed();
+
+    // xorshift, so the batch isn't already sorted; a few ids miss on purpose
+    let mut state = 0x2545_f491u32;
+    let ids: Vec<u32> = (0..BATCH)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state % (LEAF_NODE_MAX_CELLS as u32 + 2)
+        })
+        .collect();
+
+    let start = Instant::now();
+    for &id in &ids {
+        black_box(table.get(id).unwrap());
+    }
+    let get_each = start.elapsed();
+
+    let start = Instant::now();
+    black_box(table.get_many(&ids).unwrap());
+    let get_many = start.elapsed();
+
+    println!("contains: {} lookups in {:?}", LOOKUPS, contains);
+    println!("get:      {} lookups in {:?}", LOOKUPS, get);
+    println!("get x{}:      {:?}", BATCH, get_each);
+    println!("get_many x{}: {:?}", BATCH, get_many);
+
+    let _ = std::fs::remove_file(&path);
+}
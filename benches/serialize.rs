@@ -0,0 +1,42 @@
+use rsdb::{ROW_SIZE, Row, deserialize_row, serialize_row};
+use std::hint::black_box;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 1_000_000;
+
+fn main() {
+    let row = Row {
+        id: 1,
+        username: "user1".to_string(),
+        email: "person1@example.com".to_string(),
+    };
+    let mut buf = [0u8; ROW_SIZE];
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        serialize_row(black_box(&row), black_box(&mut buf)).unwrap();
+    }
+    let serialize = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(deserialize_row(black_box(&buf)));
+    }
+    let deserialize = start.elapsed();
+
+    let mb = |elapsed: std::time::Duration| {
+        (ITERATIONS as f64 * ROW_SIZE as f64) / elapsed.as_secs_f64() / 1e6
+    };
+    println!(
+        "serialize_row:   {} rows in {:?} ({:.0} MB/s)",
+        ITERATIONS,
+        serialize,
+        mb(serialize)
+    );
+    println!(
+        "deserialize_row: {} rows in {:?} ({:.0} MB/s)",
+        ITERATIONS,
+        deserialize,
+        mb(deserialize)
+    );
+}
@@ -1,73 +1,320 @@
-use std::fs::{File, OpenOptions};
+#![cfg_attr(feature = "nightly", feature(test))]
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions, TryLockError};
 use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
 
 pub const COLUMN_USERNAME_SIZE: usize = 32;
 pub const COLUMN_EMAIL_SIZE: usize = 255;
 pub const PAGE_SIZE: usize = 4096;
 pub const TABLE_MAX_PAGES: usize = 100;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Row {
     pub id: u32,
     pub username: String,
     pub email: String,
 }
 
+impl Row {
+    pub fn builder() -> RowBuilder {
+        RowBuilder::default()
+    }
+
+    // text search over both string columns, no index involved
+    pub fn matches_prefix(&self, prefix: &str) -> bool {
+        self.username.starts_with(prefix) || self.email.starts_with(prefix)
+    }
+
+    pub fn matches_suffix(&self, suffix: &str) -> bool {
+        self.username.ends_with(suffix) || self.email.ends_with(suffix)
+    }
+
+    pub fn matches_contains(&self, needle: &str) -> bool {
+        self.username.contains(needle) || self.email.contains(needle)
+    }
+
+    // both text fields without trailing whitespace or ascii control characters, which
+    // untrusted input tends to drag along and which compare and print confusingly
+    pub fn normalized(&self) -> Row {
+        let trim = |field: &str| {
+            field
+                .trim_end_matches(|c: char| c.is_whitespace() || c.is_ascii_control())
+                .to_string()
+        };
+        Row {
+            id: self.id,
+            username: trim(&self.username),
+            email: trim(&self.email),
+        }
+    }
+}
+
+// validates each field as it is set, so an oversized string fails at its own setter
+#[derive(Debug, Default)]
+pub struct RowBuilder {
+    id: Option<u32>,
+    username: Option<String>,
+    email: Option<String>,
+}
+
+impl RowBuilder {
+    pub fn id(mut self, id: u32) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn username(mut self, username: &str) -> Result<Self> {
+        if username.len() > COLUMN_USERNAME_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "username is too long"));
+        }
+        self.username = Some(username.to_string());
+        Ok(self)
+    }
+
+    pub fn email(mut self, email: &str) -> Result<Self> {
+        if email.len() > COLUMN_EMAIL_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "email is too long"));
+        }
+        self.email = Some(email.to_string());
+        Ok(self)
+    }
+
+    pub fn build(self) -> Result<Row> {
+        let missing = |field| Error::new(ErrorKind::InvalidInput, format!("missing {}", field));
+
+        Ok(Row {
+            id: self.id.ok_or_else(|| missing("id"))?,
+            username: self.username.ok_or_else(|| missing("username"))?,
+            email: self.email.ok_or_else(|| missing("email"))?,
+        })
+    }
+}
+
+// row layout: id, then each text field as a length byte followed by its zero-padded
+// bytes. the length, not a NUL terminator, ends the value, so NULs inside it survive
 const ID_SIZE: usize = 4;
+const FIELD_LEN_SIZE: usize = 1;
 const USERNAME_SIZE: usize = COLUMN_USERNAME_SIZE;
 const EMAIL_SIZE: usize = COLUMN_EMAIL_SIZE;
 const ID_OFFSET: usize = 0;
 const USERNAME_OFFSET: usize = ID_OFFSET + ID_SIZE;
-const EMAIL_OFFSET: usize = USERNAME_OFFSET + USERNAME_SIZE;
-pub const ROW_SIZE: usize = ID_SIZE + USERNAME_SIZE + EMAIL_SIZE;
+const EMAIL_OFFSET: usize = USERNAME_OFFSET + FIELD_LEN_SIZE + USERNAME_SIZE;
+pub const ROW_SIZE: usize = EMAIL_OFFSET + FIELD_LEN_SIZE + EMAIL_SIZE;
 
 pub const ROOT_PAGE_NUM: usize = 0;
 
+// wal entry layout: page_num (4 bytes, le) followed by the full page image
+const WAL_PAGE_NUM_SIZE: usize = 4;
+const WAL_ENTRY_SIZE: usize = WAL_PAGE_NUM_SIZE + PAGE_SIZE;
+
 // node page layout:
 //   [0]     node_type   (1 byte)
 //   [1]     is_root     (1 byte)
 //   [2..5]  parent_ptr  (4 bytes)
-//   [6..9]  num_cells   (4 bytes, leaf only)
+// leaf:
+//   [6..9]  num_cells   (4 bytes)
 //   [10..]  cells       (key + value each)
+// internal:
+//   [6..9]  num_keys    (4 bytes)
+//   [10..13] right_child (4 bytes)
+//   [14..]  cells       (child page + key each)
 const NODE_TYPE_SIZE: usize = 1;
 const NODE_TYPE_OFFSET: usize = 0;
 const IS_ROOT_SIZE: usize = 1;
 const IS_ROOT_OFFSET: usize = NODE_TYPE_SIZE;
 const PARENT_POINTER_SIZE: usize = 4;
+const PARENT_POINTER_OFFSET: usize = IS_ROOT_OFFSET + IS_ROOT_SIZE;
 const COMMON_NODE_HEADER_SIZE: usize = NODE_TYPE_SIZE + IS_ROOT_SIZE + PARENT_POINTER_SIZE;
 
 const LEAF_NODE_NUM_CELLS_SIZE: usize = 4;
 const LEAF_NODE_NUM_CELLS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
-const LEAF_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE + LEAF_NODE_NUM_CELLS_SIZE;
+pub const LEAF_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE + LEAF_NODE_NUM_CELLS_SIZE;
 
 const LEAF_NODE_KEY_SIZE: usize = 4;
 const LEAF_NODE_VALUE_OFFSET: usize = LEAF_NODE_KEY_SIZE;
 const LEAF_NODE_VALUE_SIZE: usize = ROW_SIZE;
-const LEAF_NODE_CELL_SIZE: usize = LEAF_NODE_KEY_SIZE + LEAF_NODE_VALUE_SIZE;
-pub const LEAF_NODE_MAX_CELLS: usize = (PAGE_SIZE - LEAF_NODE_HEADER_SIZE) / LEAF_NODE_CELL_SIZE;
+pub const LEAF_NODE_CELL_SIZE: usize = LEAF_NODE_KEY_SIZE + LEAF_NODE_VALUE_SIZE;
+pub const LEAF_NODE_SPACE_FOR_CELLS: usize = PAGE_SIZE - LEAF_NODE_HEADER_SIZE;
+pub const LEAF_NODE_MAX_CELLS: usize = LEAF_NODE_SPACE_FOR_CELLS / LEAF_NODE_CELL_SIZE;
+
+const INTERNAL_NODE_NUM_KEYS_SIZE: usize = 4;
+const INTERNAL_NODE_NUM_KEYS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
+const INTERNAL_NODE_RIGHT_CHILD_SIZE: usize = 4;
+const INTERNAL_NODE_RIGHT_CHILD_OFFSET: usize =
+    INTERNAL_NODE_NUM_KEYS_OFFSET + INTERNAL_NODE_NUM_KEYS_SIZE;
+// every child pointer is paired with the number of rows under that child, so a rank
+// lookup can descend without visiting the leaves; the right child's count sits in the header
+const INTERNAL_NODE_SUBTREE_SIZE_SIZE: usize = 4;
+const INTERNAL_NODE_RIGHT_SUBTREE_SIZE_OFFSET: usize =
+    INTERNAL_NODE_RIGHT_CHILD_OFFSET + INTERNAL_NODE_RIGHT_CHILD_SIZE;
+const INTERNAL_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE
+    + INTERNAL_NODE_NUM_KEYS_SIZE
+    + INTERNAL_NODE_RIGHT_CHILD_SIZE
+    + INTERNAL_NODE_SUBTREE_SIZE_SIZE;
 
-#[allow(dead_code)]
-enum NodeType {
+const INTERNAL_NODE_CHILD_SIZE: usize = 4;
+const INTERNAL_NODE_KEY_SIZE: usize = 4;
+const INTERNAL_NODE_SUBTREE_SIZE_OFFSET: usize = INTERNAL_NODE_CHILD_SIZE + INTERNAL_NODE_KEY_SIZE;
+const INTERNAL_NODE_CELL_SIZE: usize =
+    INTERNAL_NODE_CHILD_SIZE + INTERNAL_NODE_KEY_SIZE + INTERNAL_NODE_SUBTREE_SIZE_SIZE;
+
+// file header: page 0 ends with the format version (u32, le) and then the last-modified
+// time, nanoseconds since the unix epoch (u64, le), 0 if never stamped. no node fills
+// its page far enough to reach either
+const FORMAT_VERSION_SIZE: usize = 4;
+const LAST_MODIFIED_SIZE: usize = 8;
+const LAST_MODIFIED_OFFSET: usize = PAGE_SIZE - LAST_MODIFIED_SIZE;
+const FORMAT_VERSION_OFFSET: usize = LAST_MODIFIED_OFFSET - FORMAT_VERSION_SIZE;
+const _: () = assert!(
+    LEAF_NODE_HEADER_SIZE + LEAF_NODE_MAX_CELLS * LEAF_NODE_CELL_SIZE <= FORMAT_VERSION_OFFSET
+);
+const _: () = assert!(
+    INTERNAL_NODE_HEADER_SIZE + TABLE_MAX_PAGES * INTERNAL_NODE_CELL_SIZE <= FORMAT_VERSION_OFFSET
+);
+
+// bumped whenever the on-disk layout changes. files from before the marker existed read
+// as 0: they end text fields with a NUL instead of a length byte and carry no subtree
+// sizes, and are refused rather than misread
+pub const FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeType {
     Leaf,
     Internal,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FileAccess {
+    // one handle (and lock) for the life of the pager
+    #[default]
+    Persistent,
+    // the handle is reopened for each read or commit and closed in between, so
+    // external tools can rotate the file while the table sits idle
+    PerTransaction,
+}
+
+// canonical paths of every file a Pager in this process has open. the file lock alone
+// doesn't cover a pager in PerTransaction mode, which drops its handle between uses.
+static OPEN_FILES: LazyLock<Mutex<HashSet<PathBuf>>> = LazyLock::new(Default::default);
+
+fn already_open_error() -> Error {
+    Error::new(
+        ErrorKind::ResourceBusy,
+        "database is already open in this process",
+    )
+}
+
+fn open_locked(filename: &str) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(filename)?;
+
+    // held while the handle is open so a wal is only ever replayed by its owner
+    file.try_lock().map_err(lock_error)?;
+
+    Ok(file)
+}
+
+// readers share the lock with each other but not with a writer. the file must exist
+fn open_shared(filename: &str) -> Result<File> {
+    let file = OpenOptions::new().read(true).open(filename)?;
+    file.try_lock_shared().map_err(lock_error)?;
+
+    Ok(file)
+}
+
+fn lock_error(e: TryLockError) -> Error {
+    match e {
+        TryLockError::WouldBlock => Error::new(
+            ErrorKind::WouldBlock,
+            "database is locked by another process",
+        ),
+        TryLockError::Error(e) => e,
+    }
+}
+
+fn read_only_error() -> Error {
+    Error::new(ErrorKind::PermissionDenied, "database is open read-only")
+}
+
 pub struct Pager {
-    file: File,
+    // key in OPEN_FILES, released on drop
+    registered_path: Option<PathBuf>,
+    file: Option<File>,
+    file_access: FileAccess,
+    filename: String,
+    // opened without write access: Table refuses writes and nothing is ever flushed
+    read_only: bool,
+    pub fsync_on_close: bool,
     file_length: u64,
     num_pages: usize,
+    // pages, dirty and pin_counts grow together as higher pages are touched, up to
+    // TABLE_MAX_PAGES slots
     pages: Vec<Option<Box<[u8; PAGE_SIZE]>>>,
+    dirty: Vec<bool>,
+    // held by PinnedPage; a pinned page stays resident
+    pin_counts: Vec<u32>,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub pages_written: u64,
+    // page -> callbacks run with its contents each time it is written to the file
+    hooks: HashMap<usize, Vec<PageHook>>,
+    // set while Table::with_transaction runs, to undo its writes
+    shadow: Option<ShadowPager>,
+}
+
+type PageHook = Box<dyn Fn(&[u8; PAGE_SIZE])>;
+
+// snapshot of the pager's counters; subtract two to get what happened in between
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PageIo {
+    // every page access, cached or not
+    pub reads: u64,
+    // the accesses that had to go to the file
+    pub disk_reads: u64,
+    pub writes: u64,
+}
+
+impl PageIo {
+    pub fn since(&self, earlier: &PageIo) -> PageIo {
+        PageIo {
+            reads: self.reads - earlier.reads,
+            disk_reads: self.disk_reads - earlier.disk_reads,
+            writes: self.writes - earlier.writes,
+        }
+    }
 }
 
 impl Pager {
-    fn new(filename: &str) -> Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(filename)?;
+    pub fn new(filename: &str) -> Result<Self> {
+        Pager::open(filename, false)
+    }
+
+    // for inspecting a file safely; it has to exist already
+    pub fn new_read_only(filename: &str) -> Result<Self> {
+        Pager::open(filename, true)
+    }
 
+    fn open(filename: &str, read_only: bool) -> Result<Self> {
+        if let Ok(path) = fs::canonicalize(filename)
+            && OPEN_FILES.lock().unwrap().contains(&path)
+        {
+            return Err(already_open_error());
+        }
+        let file = if read_only {
+            open_shared(filename)?
+        } else {
+            open_locked(filename)?
+        };
         let file_length = file.metadata()?.len();
 
         if file_length != 0 && file_length % PAGE_SIZE as u64 != 0 {
@@ -77,24 +324,197 @@ impl Pager {
             ));
         }
 
+        // registered last: nothing after this can fail, so the Pager built below always
+        // exists to unregister it on drop
+        let registered_path = fs::canonicalize(filename)?;
+        if !OPEN_FILES.lock().unwrap().insert(registered_path.clone()) {
+            return Err(already_open_error());
+        }
+
         let num_pages = file_length / PAGE_SIZE as u64;
+        let fsync_on_close = std::env::var("RSDB_NO_FSYNC").map_or(true, |v| v != "1");
 
         Ok(Pager {
-            file,
+            registered_path: Some(registered_path),
+            file: Some(file),
+            file_access: FileAccess::Persistent,
+            filename: filename.to_string(),
+            read_only,
+            fsync_on_close,
             file_length,
             num_pages: num_pages as usize,
-            pages: vec![None; TABLE_MAX_PAGES],
+            pages: vec![],
+            dirty: vec![],
+            pin_counts: vec![],
+            cache_hits: 0,
+            cache_misses: 0,
+            hooks: HashMap::new(),
+            shadow: None,
+            pages_written: 0,
         })
     }
 
+    // lets another Pager open the same file, e.g. once this one's file was replaced
+    fn unregister(&mut self) {
+        if let Some(path) = self.registered_path.take() {
+            OPEN_FILES.lock().unwrap().remove(&path);
+        }
+    }
+
+    // skips the fsync in db_close, only meant for tests where durability doesn't matter
+    pub fn new_no_sync(filename: &str) -> Result<Self> {
+        let mut pager = Pager::new(filename)?;
+        pager.fsync_on_close = false;
+        Ok(pager)
+    }
+
+    // mutable access, so the page is assumed modified and will be written on flush.
+    // every change to a page goes through here, so this is where a read-only pager
+    // refuses them, before anything in the cache is touched
     pub fn get_page(&mut self, page_num: usize) -> Result<&mut [u8; PAGE_SIZE]> {
-        if self.pages[page_num].is_none() {
+        if self.read_only {
+            return Err(read_only_error());
+        }
+        self.load(page_num)?;
+        if let Some(shadow) = &mut self.shadow {
+            shadow
+                .originals
+                .entry(page_num)
+                .or_insert_with(|| self.pages[page_num].clone());
+        }
+        self.dirty[page_num] = true;
+        Ok(self.pages[page_num].as_mut().unwrap())
+    }
+
+    // read-only access: loads the page if needed but leaves its dirty flag alone
+    pub fn page_ref(&mut self, page_num: usize) -> Result<&[u8; PAGE_SIZE]> {
+        self.load(page_num)?;
+        Ok(self.pages[page_num].as_ref().unwrap())
+    }
+
+    pub fn page_io(&self) -> PageIo {
+        PageIo {
+            reads: self.cache_hits + self.cache_misses,
+            disk_reads: self.cache_misses,
+            writes: self.pages_written,
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // pages the next flush would write
+    pub fn dirty_page_count(&self) -> usize {
+        self.dirty.iter().filter(|&&dirty| dirty).count()
+    }
+
+    pub fn is_dirty(&self, page_num: usize) -> bool {
+        self.dirty.get(page_num).copied().unwrap_or(false)
+    }
+
+    pub fn is_pinned(&self, page_num: usize) -> bool {
+        self.pin_counts.get(page_num).is_some_and(|&pins| pins > 0)
+    }
+
+    // releases the slots past the highest cached or pinned page, e.g. after a large
+    // table was compacted down to a few pages
+    pub fn shrink_to_fit(&mut self) {
+        let used = (0..self.pages.len())
+            .rev()
+            .find(|&page_num| self.pages[page_num].is_some() || self.is_pinned(page_num))
+            .map_or(0, |page_num| page_num + 1);
+        self.pages.truncate(used);
+        self.dirty.truncate(used);
+        self.pin_counts.truncate(used);
+        self.pages.shrink_to_fit();
+        self.dirty.shrink_to_fit();
+        self.pin_counts.shrink_to_fit();
+    }
+
+    // runs `hook` after every write of `page_num` to the file, with the bytes written.
+    // get_page hands out the page before the caller changes it, so flush is the first
+    // point where the new contents are known
+    pub fn watch_page(&mut self, page_num: usize, hook: impl Fn(&[u8; PAGE_SIZE]) + 'static) {
+        self.hooks.entry(page_num).or_default().push(Box::new(hook));
+    }
+
+    // writes the page back if it is dirty and drops it from the cache, so the next
+    // access reads it from disk again. a pinned page has to stay resident
+    pub fn evict(&mut self, page_num: usize) -> Result<()> {
+        if self.is_pinned(page_num) {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                format!("page {} is pinned", page_num),
+            ));
+        }
+        if page_num >= self.pages.len() {
+            return Ok(());
+        }
+
+        let flushed = self.flush(page_num).and_then(|()| self.checkpoint_wal());
+        self.release_file();
+        flushed?;
+        self.pages[page_num] = None;
+        Ok(())
+    }
+
+    fn ensure_slot(&mut self, page_num: usize) -> Result<()> {
+        if page_num >= TABLE_MAX_PAGES {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} is out of bounds", page_num),
+            ));
+        }
+        if page_num >= self.pages.len() {
+            self.pages.resize(page_num + 1, None);
+            self.dirty.resize(page_num + 1, false);
+            self.pin_counts.resize(page_num + 1, 0);
+        }
+        Ok(())
+    }
+
+    // page numbers currently resident in the cache, ascending
+    pub fn allocated_pages(&self) -> Vec<usize> {
+        (0..self.pages.len())
+            .filter(|&page_num| self.pages[page_num].is_some())
+            .collect()
+    }
+
+    fn load(&mut self, page_num: usize) -> Result<()> {
+        self.ensure_slot(page_num)?;
+        if self.pages[page_num].is_some() {
+            self.cache_hits += 1;
+        } else {
+            self.cache_misses += 1;
             let mut page = Box::new([0; PAGE_SIZE]);
 
+            // opened first so a reopen has refreshed file_length before it's compared
+            if let Err(e) = self.file() {
+                self.release_file();
+                return Err(e);
+            }
             if (page_num as u64) < (self.file_length / PAGE_SIZE as u64) {
-                self.file
-                    .seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))?;
-                self.file.read_exact(&mut page[..])?;
+                let read = self.file().and_then(|file| {
+                    file.seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))?;
+                    file.read_exact(&mut page[..])
+                });
+                self.release_file();
+                // the file was this long when opened, so a short read means someone
+                // else truncated it underneath us
+                read.map_err(|e| match e.kind() {
+                    ErrorKind::UnexpectedEof => Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "corruption: page {} is past the end of the file, which is shorter than expected",
+                            page_num
+                        ),
+                    ),
+                    _ => e,
+                })?;
+            } else {
+                // a brand new page only exists in memory until it is flushed
+                self.dirty[page_num] = true;
             }
             self.pages[page_num] = Some(page);
 
@@ -103,356 +523,7178 @@ impl Pager {
             }
         }
 
-        Ok(self.pages[page_num].as_mut().unwrap())
+        Ok(())
     }
 
-    fn flush(&mut self, page_num: usize) -> Result<()> {
-        if let Some(page) = &self.pages[page_num] {
-            self.file
-                .seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))?;
-            self.file.write_all(&page[..])?;
+    // the open handle, reopening the file if release_file dropped it. a reopened file
+    // is stat'ed again: another process may have appended pages while we held no lock.
+    // a file that lost pages we had written, or stopped being whole pages, is refused
+    fn file(&mut self) -> Result<&mut File> {
+        if self.file.is_none() {
+            let file = if self.read_only {
+                open_shared(&self.filename)?
+            } else {
+                open_locked(&self.filename)?
+            };
+            let file_length = file.metadata()?.len();
+            if file_length % PAGE_SIZE as u64 != 0 || file_length < self.file_length {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "db file changed from {} to {} bytes while it was closed",
+                        self.file_length, file_length
+                    ),
+                ));
+            }
+            self.file_length = file_length;
+            self.num_pages = self
+                .num_pages
+                .max((file_length / PAGE_SIZE as u64) as usize);
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    // drops the handle between operations in per-transaction mode
+    fn release_file(&mut self) {
+        if self.file_access == FileAccess::PerTransaction {
+            self.file = None;
+        }
+    }
+
+    fn wal_path(&self) -> String {
+        format!("{}.wal", self.filename)
+    }
+
+    // drops every page from `num_pages` on, both from the cache and the file
+    fn truncate(&mut self, num_pages: usize) -> Result<()> {
+        if self.read_only {
+            return Err(read_only_error());
+        }
+        self.pages.truncate(num_pages);
+        self.dirty.truncate(num_pages);
+        self.pin_counts.truncate(num_pages);
+        self.num_pages = self.num_pages.min(num_pages);
+
+        let length = (num_pages * PAGE_SIZE) as u64;
+        if self.file_length > length {
+            self.file()?.set_len(length)?;
+            self.file_length = length;
         }
+        self.release_file();
+
         Ok(())
     }
-}
 
-pub struct Table {
-    pub root_page_num: usize,
-    pub pager: Pager,
-}
+    // logs the page to the wal, then writes it to the main file
+    fn flush(&mut self, page_num: usize) -> Result<()> {
+        self.log_dirty_pages(&[page_num])?;
+        if self.is_dirty(page_num) {
+            self.write_through(page_num)?;
+        }
+        Ok(())
+    }
 
-pub struct Cursor<'a> {
-    pub table: &'a mut Table,
-    pub page_num: usize,
-    pub cell_num: usize,
-    pub end_of_table: bool,
-}
+    // logs every dirty page in `page_nums` to the wal in one append and one sync, so the
+    // whole set is durable before write_through touches the main file. the wal holds every
+    // page written since the last checkpoint, so a crash partway through applying them is
+    // replayed by restore_from_wal on the next open
+    fn log_dirty_pages(&self, page_nums: &[usize]) -> Result<()> {
+        let entries: Vec<(usize, &[u8; PAGE_SIZE])> = page_nums
+            .iter()
+            .filter(|&&page_num| self.is_dirty(page_num))
+            .filter_map(|&page_num| Some((page_num, &**self.pages[page_num].as_ref()?)))
+            .collect();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        if self.read_only {
+            return Err(read_only_error());
+        }
+        self.append_wal(&entries)
+    }
 
-impl<'a> Cursor<'a> {
-    pub fn table_start(table: &'a mut Table) -> Result<Self> {
-        let page_num = table.root_page_num;
-        let num_cells = {
-            let root_page = table.pager.get_page(table.root_page_num)?;
-            leaf_node_num_cells(root_page)
-        };
+    // appends `(page_num, page_bytes)` entries, the layout restore_from_wal reads, with a
+    // single write and at most one sync
+    fn append_wal(&self, entries: &[(usize, &[u8; PAGE_SIZE])]) -> Result<()> {
+        let mut wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.wal_path())?;
+        let mut buf = Vec::with_capacity(entries.len() * WAL_ENTRY_SIZE);
+        for &(page_num, data) in entries {
+            buf.extend_from_slice(&(page_num as u32).to_le_bytes());
+            buf.extend_from_slice(data);
+        }
+        wal.write_all(&buf)?;
+        if self.fsync_on_close {
+            wal.sync_data()?;
+        }
+        Ok(())
+    }
 
-        Ok(Cursor {
-            table,
-            page_num,
-            cell_num: 0,
-            end_of_table: num_cells == 0,
-        })
+    // the main file has caught up with everything logged, so the wal can go. with fsync
+    // on, the main file is synced first so nothing is only in the page cache when the
+    // log that could replay it disappears. a read-only pager never logs anything, and any
+    // wal next to its file belongs to a writer
+    fn checkpoint_wal(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        if self.fsync_on_close
+            && let Some(file) = &self.file
+        {
+            file.sync_data()?;
+        }
+        match fs::remove_file(self.wal_path()) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
     }
 
-    pub fn table_end(table: &'a mut Table) -> Result<Self> {
-        let page_num = table.root_page_num;
-        let num_cells = {
-            let root_page = table.pager.get_page(table.root_page_num)?;
-            leaf_node_num_cells(root_page)
-        };
+    // the main-file half of flush, without logging
+    fn write_through(&mut self, page_num: usize) -> Result<()> {
+        if self.pages[page_num].is_some() {
+            self.file()?;
+        }
+        if let (Some(page), Some(file)) = (&self.pages[page_num], &mut self.file) {
+            file.seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))?;
+            file.write_all(&page[..])?;
+            self.pages_written += 1;
+            self.file_length = self.file_length.max(((page_num + 1) * PAGE_SIZE) as u64);
+            for hook in self.hooks.get(&page_num).into_iter().flatten() {
+                hook(page);
+            }
+        }
+        self.dirty[page_num] = false;
+        Ok(())
+    }
+}
 
-        Ok(Cursor {
-            table,
-            page_num,
-            cell_num: num_cells as usize,
-            end_of_table: true,
-        })
+impl Drop for Pager {
+    fn drop(&mut self) {
+        self.unregister();
     }
+}
 
-    pub fn value(&mut self) -> Result<&mut [u8]> {
-        let page = self.table.pager.get_page(self.page_num)?;
-        Ok(leaf_node_value(page, self.cell_num as u32))
+// the pager as a transaction found it: each page's image from before its first write
+// in the transaction, copied on demand, plus the bookkeeping that decides what a
+// flush would write. nothing is flushed while a transaction runs, so putting these back
+// is a complete rollback
+struct ShadowPager {
+    originals: HashMap<usize, Option<Box<[u8; PAGE_SIZE]>>>,
+    dirty: Vec<bool>,
+    num_pages: usize,
+}
+
+impl Pager {
+    fn begin_shadow(&mut self) {
+        self.shadow = Some(ShadowPager {
+            originals: HashMap::new(),
+            dirty: self.dirty.clone(),
+            num_pages: self.num_pages,
+        });
     }
 
-    pub fn advance(&mut self) -> Result<()> {
-        self.cell_num += 1;
+    // keeps the transaction's writes; they are dirty and go out with the next flush
+    fn promote_shadow(&mut self) {
+        self.shadow = None;
+    }
 
-        let num_cells = {
-            let page = self.table.pager.get_page(self.page_num)?;
-            leaf_node_num_cells(page)
+    fn discard_shadow(&mut self) {
+        let Some(shadow) = self.shadow.take() else {
+            return;
         };
-
-        if self.cell_num >= num_cells as usize {
-            self.end_of_table = true;
+        for (page_num, original) in shadow.originals {
+            self.pages[page_num] = original;
         }
-
-        Ok(())
+        // pages first allocated inside the transaction never existed
+        for page in self.pages.iter_mut().skip(shadow.num_pages) {
+            *page = None;
+        }
+        self.dirty = shadow.dirty;
+        self.dirty.resize(self.pages.len(), false);
+        self.num_pages = shadow.num_pages;
     }
 }
 
-pub fn db_open(filename: &str) -> Result<Table> {
-    let mut pager = Pager::new(filename)?;
+// a loaded page that the cache must keep resident until this is dropped. the pager stays
+// reachable through it, so a loop can keep reading other pages while holding the pin.
+pub struct PinnedPage<'a> {
+    pager: &'a mut Pager,
+    page_num: usize,
+}
 
-    if pager.num_pages == 0 {
-        let page = pager.get_page(0)?;
-        initialize_leaf_node(page);
+impl PinnedPage<'_> {
+    pub fn page_num(&self) -> usize {
+        self.page_num
     }
 
-    Ok(Table {
-        root_page_num: ROOT_PAGE_NUM,
-        pager,
-    })
+    pub fn pager(&mut self) -> &mut Pager {
+        self.pager
+    }
 }
 
-pub fn db_close(table: &mut Table) -> Result<()> {
-    for i in 0..table.pager.num_pages {
-        table.pager.flush(i)?;
+impl Deref for PinnedPage<'_> {
+    type Target = [u8; PAGE_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        self.pager.pages[self.page_num].as_ref().unwrap()
     }
+}
 
-    Ok(())
+impl Drop for PinnedPage<'_> {
+    fn drop(&mut self) {
+        self.pager.pin_counts[self.page_num] -= 1;
+    }
 }
 
-pub fn serialize_row(row: &Row, destination: &mut [u8]) {
-    destination[ID_OFFSET..ID_OFFSET + ID_SIZE].copy_from_slice(&row.id.to_le_bytes());
+#[derive(Debug, Clone, Default)]
+pub struct DbOptions {
+    // 0 disables the row cache
+    pub row_cache_size: usize,
+    pub file_access: FileAccess,
+    // share of the file's pages that may sit outside the tree before db_close, or every
+    // AUTO_COMPACT_INTERVAL mutations, compacts on its own. 0 disables it
+    pub auto_compact_ratio: f64,
+}
 
-    let mut username_bytes = [0u8; USERNAME_SIZE];
-    let username_data = row.username.as_bytes();
-    let username_len = username_data.len().min(USERNAME_SIZE);
-    username_bytes[..username_len].copy_from_slice(&username_data[..username_len]);
-    destination[USERNAME_OFFSET..USERNAME_OFFSET + USERNAME_SIZE].copy_from_slice(&username_bytes);
+const AUTO_COMPACT_INTERVAL: usize = 64;
 
-    let mut email_bytes = [0u8; EMAIL_SIZE];
-    let email_data = row.email.as_bytes();
-    let email_len = email_data.len().min(EMAIL_SIZE);
-    email_bytes[..email_len].copy_from_slice(&email_data[..email_len]);
-    destination[EMAIL_OFFSET..EMAIL_OFFSET + EMAIL_SIZE].copy_from_slice(&email_bytes);
+// small lru of deserialized rows keyed by id, consulted by point lookups before the pager
+struct RowCache {
+    capacity: usize,
+    rows: HashMap<u32, Row>,
+    order: VecDeque<u32>,
 }
 
-pub fn deserialize_row(source: &[u8]) -> Row {
-    let id = u32::from_le_bytes([source[0], source[1], source[2], source[3]]);
+impl RowCache {
+    fn new(capacity: usize) -> Self {
+        RowCache {
+            capacity,
+            rows: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
 
-    let username_bytes = &source[USERNAME_OFFSET..USERNAME_OFFSET + USERNAME_SIZE];
-    let username_end = username_bytes
-        .iter()
-        .position(|&b| b == 0)
-        .unwrap_or(USERNAME_SIZE);
-    let username = String::from_utf8_lossy(&username_bytes[..username_end]).to_string();
+    fn get(&mut self, id: u32) -> Option<Row> {
+        let row = self.rows.get(&id)?.clone();
+        self.touch(id);
+        Some(row)
+    }
 
-    let email_bytes = &source[EMAIL_OFFSET..EMAIL_OFFSET + EMAIL_SIZE];
-    let email_end = email_bytes
-        .iter()
-        .position(|&b| b == 0)
-        .unwrap_or(EMAIL_SIZE);
-    let email = String::from_utf8_lossy(&email_bytes[..email_end]).to_string();
+    fn put(&mut self, row: Row) {
+        if self.capacity == 0 {
+            return;
+        }
 
-    Row {
-        id,
-        username,
-        email,
+        let id = row.id;
+        if self.rows.insert(id, row).is_some() {
+            self.touch(id);
+            return;
+        }
+
+        self.order.push_back(id);
+        if self.order.len() > self.capacity
+            && let Some(evicted) = self.order.pop_front()
+        {
+            self.rows.remove(&evicted);
+        }
     }
-}
 
-// --- leaf node accessors ---
+    fn invalidate(&mut self, id: u32) {
+        if self.rows.remove(&id).is_some() {
+            self.order.retain(|&cached| cached != id);
+        }
+    }
 
-fn leaf_node_num_cells(node: &[u8; PAGE_SIZE]) -> u32 {
-    u32::from_le_bytes(
-        node[LEAF_NODE_NUM_CELLS_OFFSET..LEAF_NODE_NUM_CELLS_OFFSET + LEAF_NODE_NUM_CELLS_SIZE]
-            .try_into()
-            .unwrap(),
-    )
+    fn touch(&mut self, id: u32) {
+        self.order.retain(|&cached| cached != id);
+        self.order.push_back(id);
+    }
 }
 
-fn set_leaf_node_num_cells(node: &mut [u8; PAGE_SIZE], num_cells: u32) {
-    node[LEAF_NODE_NUM_CELLS_OFFSET..LEAF_NODE_NUM_CELLS_OFFSET + LEAF_NODE_NUM_CELLS_SIZE]
-        .copy_from_slice(&num_cells.to_le_bytes());
+#[derive(Debug, Clone, PartialEq)]
+pub enum RowDiff {
+    OnlyInSelf(Row),
+    OnlyInOther(Row),
+    Changed { ours: Row, theirs: Row },
 }
 
-fn leaf_node_cell_offset(cell_num: u32) -> usize {
-    LEAF_NODE_HEADER_SIZE + (cell_num as usize * LEAF_NODE_CELL_SIZE)
+// what merge_from does with a row whose id is already in the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeConflict {
+    Skip,
+    Replace,
+    Error,
 }
 
-fn leaf_node_cell(node: &mut [u8; PAGE_SIZE], cell_num: u32) -> &mut [u8] {
-    let offset = leaf_node_cell_offset(cell_num);
-    &mut node[offset..offset + LEAF_NODE_CELL_SIZE]
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntegrityIssue {
+    UnsortedKey {
+        page: usize,
+        cell: usize,
+        key: u32,
+        prev_key: u32,
+    },
+    InvalidNodeType {
+        page: usize,
+        byte: u8,
+    },
+    OrphanPage {
+        page: usize,
+    },
+    // `actual` is the most cells the page can physically hold
+    InconsistentNumCells {
+        page: usize,
+        stored: u32,
+        actual: u32,
+    },
+    // the is_root byte should be set on root_page_num and on no other page in the tree
+    RootFlagMismatch {
+        page: usize,
+        is_root: bool,
+    },
+    // every leaf of a b-tree sits at the same depth
+    UnbalancedLeaves {
+        min_depth: usize,
+        max_depth: usize,
+    },
+    // the row count stored for child `child` of internal node `page` is off; fixed by
+    // repair_subtree_sizes
+    SubtreeSizeMismatch {
+        page: usize,
+        child: u32,
+        stored: u32,
+        actual: u32,
+    },
 }
 
-fn leaf_node_key(node: &[u8; PAGE_SIZE], cell_num: u32) -> u32 {
-    let offset = leaf_node_cell_offset(cell_num);
-    let cell = &node[offset..offset + LEAF_NODE_CELL_SIZE];
+// owns the file behind Table::with_temp and deletes it, and any wal next to it, when
+// dropped. drop it after the table
+pub struct TempGuard {
+    path: PathBuf,
+}
 
-    u32::from_le_bytes(cell[..LEAF_NODE_KEY_SIZE].try_into().unwrap())
+impl TempGuard {
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
 }
 
-fn leaf_node_value(node: &mut [u8; PAGE_SIZE], cell_num: u32) -> &mut [u8] {
-    let offset = leaf_node_cell_offset(cell_num) + LEAF_NODE_VALUE_OFFSET;
-    &mut node[offset..offset + LEAF_NODE_VALUE_SIZE]
+impl Drop for TempGuard {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        let mut wal = self.path.clone().into_os_string();
+        wal.push(".wal");
+        let _ = fs::remove_file(wal);
+    }
 }
 
-fn initialize_leaf_node(node: &mut [u8; PAGE_SIZE]) {
-    node[NODE_TYPE_OFFSET] = NodeType::Leaf as u8;
-    node[IS_ROOT_OFFSET] = 1;
-    set_leaf_node_num_cells(node, 0);
+// what Table::with_transaction's closure can do to the table: read and run statements,
+// but not flush, close or compact, any of which would let a write escape the rollback
+pub struct TransactionTable<'a> {
+    table: &'a mut Table,
+}
+
+impl TransactionTable<'_> {
+    pub fn execute(&mut self, statement: &Statement) -> Result<ExecuteResult> {
+        execute_statement(statement, self.table)
+    }
+
+    pub fn get(&mut self, id: u32) -> Result<Option<Row>> {
+        self.table.get(id)
+    }
+
+    pub fn row_count(&mut self) -> Result<u64> {
+        self.table.row_count()
+    }
+}
+
+// distinguishes temp files created by one process within the same clock tick
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub struct Table {
+    pub root_page_num: usize,
+    pub pager: Pager,
+    pub truncate_strings: bool,
+    // store Row::normalized instead of the row as given, on insert and update
+    pub normalize_strings: bool,
+    pub debug: bool,
+    row_cache: RowCache,
+    last_insert_id: Option<u32>,
+    // what the last insert statement cost, only recorded while debug is on
+    last_insert_stats: Option<InsertStats>,
+    write_barrier: bool,
+    name: Option<String>,
+    // bumped by every change to the tree's shape (inserts, merges, moved pages) so
+    // cursors can tell their position went stale
+    structure_version: u64,
+    // Cursor::advance calls over this Table's lifetime, so tests can see how far a
+    // scan actually went
+    cursor_advances: u64,
+    // idempotency token -> id it inserted, in memory only, so retries are recognised
+    // for the lifetime of this Table
+    idempotency_keys: HashMap<String, u32>,
+    auto_compact_ratio: f64,
+    mutations_since_compact_check: usize,
 }
 
-// insert a cell at the end of the leaf node: write key, serialize value, bump num_cells
-pub fn leaf_node_insert(cursor: &mut Cursor, key: u32, value: &Row) -> Result<()> {
-    let page = cursor.table.pager.get_page(cursor.page_num)?;
-    let num_cells = leaf_node_num_cells(page);
-    let cell = leaf_node_cell(page, cursor.cell_num as u32);
+impl Table {
+    // runs `f` as one unit: if it returns Ok every write it made is flushed, if it
+    // returns Err the table is put back exactly as it was and the error passed on. a
+    // panic in `f` is rolled back the same way before it carries on unwinding
+    pub fn with_transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut TransactionTable) -> Result<T>,
+    {
+        if self.pager.read_only {
+            return Err(read_only_error());
+        }
+        let root_page_num = self.root_page_num;
+        let last_insert_id = self.last_insert_id;
+        let idempotency_keys = self.idempotency_keys.clone();
+        // compaction truncates the file, which can't be rolled back
+        let auto_compact_ratio = std::mem::replace(&mut self.auto_compact_ratio, 0.0);
+
+        self.pager.begin_shadow();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            f(&mut TransactionTable { table: &mut *self })
+        }));
+        self.auto_compact_ratio = auto_compact_ratio;
+
+        let roll_back = |table: &mut Table| {
+            table.pager.discard_shadow();
+            table.root_page_num = root_page_num;
+            table.last_insert_id = last_insert_id;
+            table.idempotency_keys = idempotency_keys;
+            table.row_cache = RowCache::new(table.row_cache.capacity);
+            table.structure_version += 1;
+        };
+        match result {
+            Ok(Ok(value)) => {
+                self.pager.promote_shadow();
+                self.flush_all()?;
+                Ok(value)
+            }
+            Ok(Err(e)) => {
+                roll_back(self);
+                Err(e)
+            }
+            Err(panic) => {
+                roll_back(self);
+                std::panic::resume_unwind(panic)
+            }
+        }
+    }
+
+    // an empty table in a fresh file under the system temp dir, for tests and scratch
+    // work. fsync is off, nothing here is meant to survive
+    pub fn with_temp() -> Result<(Table, TempGuard)> {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "rsdb_{}_{}_{}.db",
+                std::process::id(),
+                nanos,
+                TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            // create_new so two callers can never end up sharing a file
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+
+            let guard = TempGuard { path };
+            let filename = guard.path.to_string_lossy().into_owned();
+            let table = db_open_with_pager(Pager::new_no_sync(&filename)?)?;
+            return Ok((table, guard));
+        }
+    }
+
+    // breadth-first walk from the root, so every reachable page is reported exactly once
+    pub fn iter_pages_with_type(&mut self) -> Result<Vec<(usize, NodeType)>> {
+        let mut pages = vec![];
+        let mut visited = [false; TABLE_MAX_PAGES];
+        let mut queue = VecDeque::from([self.root_page_num]);
+
+        while let Some(page_num) = queue.pop_front() {
+            if page_num >= TABLE_MAX_PAGES || visited[page_num] {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("page {} is out of range or reachable twice", page_num),
+                ));
+            }
+            visited[page_num] = true;
+
+            let node = self.pager.page_ref(page_num)?;
+            let node_type = node_type_of(node)?;
+            if node_type == NodeType::Internal {
+                for i in 0..=internal_node_num_keys(node) {
+                    queue.push_back(internal_node_child(node, i) as usize);
+                }
+            }
+
+            pages.push((page_num, node_type));
+        }
+
+        Ok(pages)
+    }
+
+    // every page in the file, reachable or not, in page order and open for writing.
+    // an iterator can't hand out &mut borrows of a pager it still needs for loading, so
+    // the pages are loaded into the cache before the first one is yielded; a page that
+    // fails to load ends the iteration with its error. whatever the caller does to the
+    // bytes, every page is marked dirty and cursors are invalidated.
+    pub fn pages_iter(
+        &mut self,
+    ) -> impl Iterator<Item = Result<(usize, &mut [u8; PAGE_SIZE])>> + '_ {
+        let num_pages = self.pager.num_pages;
+        let (loaded, error) = match (0..num_pages)
+            .find_map(|page_num| self.pager.get_page(page_num).err().map(|e| (page_num, e)))
+        {
+            Some((page_num, e)) => (page_num, Some(e)),
+            None => (num_pages, None),
+        };
+        self.structure_version += 1;
+        self.row_cache = RowCache::new(self.row_cache.capacity);
+
+        self.pager.pages[..loaded]
+            .iter_mut()
+            .enumerate()
+            .map(|(page_num, page)| Ok((page_num, &mut **page.as_mut().unwrap())))
+            .chain(error.map(Err))
+    }
+
+    pub fn count_pages_by_type(&mut self) -> Result<(usize, usize)> {
+        let pages = self.iter_pages_with_type()?;
+        let leaf_count = pages
+            .iter()
+            .filter(|(_, node_type)| *node_type == NodeType::Leaf)
+            .count();
+
+        Ok((leaf_count, pages.len() - leaf_count))
+    }
+
+    // walks every page reachable from the root and reports every violation found rather
+    // than stopping at the first one. an empty vec means the tree is clean.
+    pub fn integrity_check(&mut self) -> Result<Vec<IntegrityIssue>> {
+        let mut issues = vec![];
+        let mut visited = [false; TABLE_MAX_PAGES];
+        let mut queue = VecDeque::from([(self.root_page_num, 0)]);
+        let mut leaf_depths: Option<(usize, usize)> = None;
+
+        while let Some((page_num, depth)) = queue.pop_front() {
+            if page_num >= TABLE_MAX_PAGES || visited[page_num] {
+                continue;
+            }
+            visited[page_num] = true;
+
+            let node = self.pager.page_ref(page_num)?;
+            let is_root = node_is_root(node);
+            if is_root != (page_num == self.root_page_num) {
+                issues.push(IntegrityIssue::RootFlagMismatch {
+                    page: page_num,
+                    is_root,
+                });
+            }
+            match node_type_of(node) {
+                Ok(NodeType::Leaf) => {
+                    leaf_depths = Some(match leaf_depths {
+                        Some((min, max)) => (min.min(depth), max.max(depth)),
+                        None => (depth, depth),
+                    });
+
+                    let stored = leaf_node_num_cells(node);
+                    let actual = stored.min(LEAF_NODE_MAX_CELLS as u32);
+                    if stored != actual {
+                        issues.push(IntegrityIssue::InconsistentNumCells {
+                            page: page_num,
+                            stored,
+                            actual,
+                        });
+                    }
+
+                    for cell in 1..actual {
+                        let prev_key = leaf_node_key(node, cell - 1);
+                        let key = leaf_node_key(node, cell);
+                        if key <= prev_key {
+                            issues.push(IntegrityIssue::UnsortedKey {
+                                page: page_num,
+                                cell: cell as usize,
+                                key,
+                                prev_key,
+                            });
+                        }
+                    }
+                }
+                Ok(NodeType::Internal) => {
+                    for i in 0..=internal_node_num_keys(node) {
+                        queue.push_back((internal_node_child(node, i) as usize, depth + 1));
+                    }
+                }
+                Err(_) => issues.push(IntegrityIssue::InvalidNodeType {
+                    page: page_num,
+                    byte: node[NODE_TYPE_OFFSET],
+                }),
+            }
+        }
+
+        for (page, reachable) in visited.iter().enumerate().take(self.pager.num_pages) {
+            if !reachable {
+                issues.push(IntegrityIssue::OrphanPage { page });
+            }
+        }
+        if let Some((min_depth, max_depth)) = leaf_depths
+            && min_depth != max_depth
+        {
+            issues.push(IntegrityIssue::UnbalancedLeaves {
+                min_depth,
+                max_depth,
+            });
+        }
+        let mut visited = [false; TABLE_MAX_PAGES];
+        self.count_subtree(self.root_page_num, false, &mut visited, &mut issues)?;
+
+        Ok(issues)
+    }
+
+    // rows under page_num, checking every stored subtree size on the way down and
+    // reporting the ones that are off, or with `fix`, rewriting them. None for a subtree
+    // with a page that is out of range, seen twice or not a node: integrity_check reports
+    // those, and the sizes above them can't be checked
+    fn count_subtree(
+        &mut self,
+        page_num: usize,
+        fix: bool,
+        visited: &mut [bool; TABLE_MAX_PAGES],
+        mismatches: &mut Vec<IntegrityIssue>,
+    ) -> Result<Option<u32>> {
+        if page_num >= TABLE_MAX_PAGES || visited[page_num] {
+            return Ok(None);
+        }
+        visited[page_num] = true;
+
+        let node = self.pager.page_ref(page_num)?;
+        match node_type_of(node) {
+            Ok(NodeType::Leaf) => Ok(Some(
+                leaf_node_num_cells(node).min(LEAF_NODE_MAX_CELLS as u32),
+            )),
+            Ok(NodeType::Internal) => {
+                let mut total = Some(0u32);
+                for i in 0..=internal_node_num_keys(node) {
+                    let child = internal_node_child(self.pager.page_ref(page_num)?, i) as usize;
+                    let Some(actual) = self.count_subtree(child, fix, visited, mismatches)? else {
+                        total = None;
+                        continue;
+                    };
+                    let stored = internal_node_subtree_size(self.pager.page_ref(page_num)?, i);
+                    if stored != actual {
+                        if fix {
+                            set_internal_node_subtree_size(
+                                self.pager.get_page(page_num)?,
+                                i,
+                                actual,
+                            );
+                        }
+                        mismatches.push(IntegrityIssue::SubtreeSizeMismatch {
+                            page: page_num,
+                            child: i,
+                            stored,
+                            actual,
+                        });
+                    }
+                    total = total.map(|total| total.saturating_add(actual));
+                }
+                Ok(total)
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    // recounts the rows under every internal node's children and rewrites the stored
+    // sizes that disagree, returning how many were rewritten
+    pub fn repair_subtree_sizes(&mut self) -> Result<usize> {
+        let mut visited = [false; TABLE_MAX_PAGES];
+        let mut fixed = vec![];
+        self.count_subtree(self.root_page_num, true, &mut visited, &mut fixed)?;
+        if !fixed.is_empty() {
+            self.structure_version += 1;
+        }
+        Ok(fixed.len())
+    }
+
+    // adds `delta` to the stored size of every subtree between the root and `leaf`, after
+    // `delta` cells holding keys around `key` went into or out of it. the path is found
+    // by descending on `key`, falling back to walking parent links when that lands on
+    // another leaf, e.g. on a tree whose children are out of key order
+    fn adjust_subtree_sizes(&mut self, leaf: usize, key: u32, delta: i32) -> Result<()> {
+        let mut path = vec![];
+        let mut page_num = self.root_page_num;
+        for _ in 0..TABLE_MAX_PAGES {
+            let node = self.pager.page_ref(page_num)?;
+            if node_type_of(node)? == NodeType::Leaf {
+                break;
+            }
+            let child_num = internal_node_find_child_num(node, key);
+            path.push((page_num, child_num));
+            page_num = internal_node_child(node, child_num) as usize;
+        }
+
+        if page_num != leaf {
+            path = self.path_to(leaf)?;
+        }
+
+        for (parent, child_num) in path {
+            let node = self.pager.get_page(parent)?;
+            let size = internal_node_subtree_size(node, child_num).saturating_add_signed(delta);
+            set_internal_node_subtree_size(node, child_num, size);
+        }
+        Ok(())
+    }
+
+    fn check_format_version(&mut self) -> Result<()> {
+        let header = self.pager.page_ref(0)?;
+        let version = u32::from_le_bytes(
+            header[FORMAT_VERSION_OFFSET..FORMAT_VERSION_OFFSET + FORMAT_VERSION_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        if version != FORMAT_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "file format version {} is not supported, expected {}",
+                    version, FORMAT_VERSION
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // only a genuinely empty file gets a fresh root, so a page 0 that doesn't look like
+    // one means corruption. refusing to open leaves the file as it was for recovery
+    fn check_root_header(&mut self) -> Result<()> {
+        let node = self.pager.page_ref(self.root_page_num)?;
+        let valid = match node_type_of(node) {
+            Ok(NodeType::Leaf) => leaf_node_num_cells(node) as usize <= LEAF_NODE_MAX_CELLS,
+            Ok(NodeType::Internal) => true,
+            Err(_) => false,
+        };
+        if !valid || !node_is_root(node) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "corruption: page {} doesn't have a valid root header",
+                    self.root_page_num
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // (internal page, child index) for each level from the root down to `page_num`, empty
+    // if it is the root or not in the tree. walks the tree once per level, so cursors
+    // only fall back on it when the path they carry has gone stale
+    fn path_to(&mut self, page_num: usize) -> Result<Vec<(usize, u32)>> {
+        let mut path = vec![];
+        let mut child = page_num;
+        while let Some((parent, child_num)) = self.find_parent(child)? {
+            if path.len() >= TABLE_MAX_PAGES {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "tree is deeper than the page limit",
+                ));
+            }
+            path.push((parent, child_num));
+            child = parent;
+        }
+        path.reverse();
+        Ok(path)
+    }
+
+    // (parent page, child index) of the internal node pointing at `page_num`
+    fn find_parent(&mut self, page_num: usize) -> Result<Option<(usize, u32)>> {
+        for (candidate, node_type) in self.iter_pages_with_type()? {
+            if node_type != NodeType::Internal {
+                continue;
+            }
+
+            let node = self.pager.page_ref(candidate)?;
+            for i in 0..=internal_node_num_keys(node) {
+                if internal_node_child(node, i) as usize == page_num {
+                    return Ok(Some((candidate, i)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // one bounded unit of online compaction: drop the last page if nothing references
+    // it, otherwise move it into the lowest unreachable page and repoint its parent.
+    // each step leaves a consistent tree, so reads can run between steps. returns
+    // false once there is nothing left to reclaim.
+    pub fn compact_step(&mut self) -> Result<bool> {
+        let num_pages = self.pager.num_pages;
+        if num_pages <= 1 {
+            return Ok(false);
+        }
+        self.structure_version += 1;
+
+        let mut reachable = [false; TABLE_MAX_PAGES];
+        for (page_num, _) in self.iter_pages_with_type()? {
+            reachable[page_num] = true;
+        }
+
+        let last = num_pages - 1;
+        if reachable[last] {
+            let Some(hole) = (0..last).find(|&p| !reachable[p]) else {
+                return Ok(false);
+            };
+
+            let image = *self.pager.get_page(last)?;
+            *self.pager.get_page(hole)? = image;
+            match self.find_parent(last)? {
+                Some((parent, child_num)) => {
+                    let parent = self.pager.get_page(parent)?;
+                    set_internal_node_child(parent, child_num, hole as u32);
+                }
+                None => self.root_page_num = hole,
+            }
+        }
+
+        self.pager.truncate(last)?;
+        Ok(true)
+    }
+
+    // runs compact_step to completion, returning how many steps made progress. a
+    // server that can't afford the stall should call compact_step itself instead.
+    pub fn compact_in_place_online(&mut self) -> Result<usize> {
+        let mut steps = 0;
+        while self.compact_step()? {
+            steps += 1;
+        }
+        Ok(steps)
+    }
+
+    // pages in the file that the tree doesn't reach, ascending. nothing links them on
+    // disk; a page is free exactly when no live node points at it, so the list can't
+    // disagree with the tree
+    pub fn explain_free_list(&mut self) -> Result<Vec<usize>> {
+        let mut reachable = [false; TABLE_MAX_PAGES];
+        for (page_num, _) in self.iter_pages_with_type()? {
+            reachable[page_num] = true;
+        }
+        Ok((0..self.pager.num_pages)
+            .filter(|&page_num| !reachable[page_num])
+            .collect())
+    }
+
+    // share of the file's pages on the free list. there is no delete, so orphaned
+    // pages are the only waste a table can carry
+    pub fn wasted_space_ratio(&mut self) -> Result<f64> {
+        let num_pages = self.pager.num_pages;
+        if num_pages == 0 {
+            return Ok(0.0);
+        }
+        Ok(self.explain_free_list()?.len() as f64 / num_pages as f64)
+    }
+
+    // runs compact_in_place_online once the wasted share reaches auto_compact_ratio.
+    // returns whether it ran
+    fn auto_compact(&mut self) -> Result<bool> {
+        if self.auto_compact_ratio <= 0.0 || self.wasted_space_ratio()? < self.auto_compact_ratio {
+            return Ok(false);
+        }
+        self.compact_in_place_online()?;
+        Ok(true)
+    }
+
+    // the statement has already been applied by the time this runs, so a compaction
+    // that fails mustn't fail it too. the check stays due instead: it runs again on the
+    // next mutation, and db_close, which does report the error, runs it regardless
+    fn record_mutation(&mut self) {
+        self.mutations_since_compact_check += 1;
+        if self.mutations_since_compact_check >= AUTO_COMPACT_INTERVAL
+            && self.auto_compact().is_ok()
+        {
+            self.mutations_since_compact_check = 0;
+        }
+    }
+
+    // merges adjacent sibling leaves that together hold fewer than LEAF_NODE_MAX_CELLS
+    // cells, dropping the separator from their parent, until no such pair is left. a
+    // root left with a single child absorbs it. returns how many pages left the tree;
+    // they are then reclaimed from the end of the file with compact_in_place_online.
+    pub fn compact(&mut self) -> Result<usize> {
+        let mut freed = 0;
+        while let Some((parent, child_num)) = self.find_mergeable_leaves()? {
+            freed += self.merge_leaves(parent, child_num)?;
+        }
+
+        if freed > 0 {
+            self.compact_in_place_online()?;
+        }
+        Ok(freed)
+    }
+
+    // first internal node whose children `child_num` and `child_num + 1` are both
+    // leaves small enough to share a page
+    fn find_mergeable_leaves(&mut self) -> Result<Option<(usize, u32)>> {
+        for (page_num, node_type) in self.iter_pages_with_type()? {
+            if node_type != NodeType::Internal {
+                continue;
+            }
+
+            let node = *self.pager.page_ref(page_num)?;
+            for i in 0..internal_node_num_keys(&node) {
+                let left = *self
+                    .pager
+                    .page_ref(internal_node_child(&node, i) as usize)?;
+                let right = *self
+                    .pager
+                    .page_ref(internal_node_child(&node, i + 1) as usize)?;
+                if node_type_of(&left)? == NodeType::Leaf
+                    && node_type_of(&right)? == NodeType::Leaf
+                    && ((leaf_node_num_cells(&left) + leaf_node_num_cells(&right)) as usize)
+                        < LEAF_NODE_MAX_CELLS
+                {
+                    return Ok(Some((page_num, i)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // moves the smaller of the two leaves into the larger, keeping cells in key order,
+    // and returns the number of pages that left the tree
+    fn merge_leaves(&mut self, parent: usize, child_num: u32) -> Result<usize> {
+        self.structure_version += 1;
+        let parent_node = *self.pager.page_ref(parent)?;
+        let left = internal_node_child(&parent_node, child_num) as usize;
+        let right = internal_node_child(&parent_node, child_num + 1) as usize;
+        let left_cells = leaf_node_num_cells(self.pager.page_ref(left)?);
+        let right_cells = leaf_node_num_cells(self.pager.page_ref(right)?);
+
+        let survivor = if left_cells >= right_cells {
+            let right_node = *self.pager.page_ref(right)?;
+            let node = self.pager.get_page(left)?;
+            node[leaf_node_cell_offset(left_cells)
+                ..leaf_node_cell_offset(left_cells + right_cells)]
+                .copy_from_slice(
+                    &right_node[leaf_node_cell_offset(0)..leaf_node_cell_offset(right_cells)],
+                );
+            set_leaf_node_num_cells(node, left_cells + right_cells);
+            left
+        } else {
+            let left_node = *self.pager.page_ref(left)?;
+            let node = self.pager.get_page(right)?;
+            node.copy_within(
+                leaf_node_cell_offset(0)..leaf_node_cell_offset(right_cells),
+                leaf_node_cell_offset(left_cells),
+            );
+            node[leaf_node_cell_offset(0)..leaf_node_cell_offset(left_cells)].copy_from_slice(
+                &left_node[leaf_node_cell_offset(0)..leaf_node_cell_offset(left_cells)],
+            );
+            set_leaf_node_num_cells(node, left_cells + right_cells);
+            right
+        };
+
+        // drop cell `child_num`; the slot that used to point at `right` now covers both
+        let node = self.pager.get_page(parent)?;
+        let num_keys = internal_node_num_keys(node);
+        node.copy_within(
+            internal_node_cell_offset(child_num + 1)..internal_node_cell_offset(num_keys),
+            internal_node_cell_offset(child_num),
+        );
+        node[INTERNAL_NODE_NUM_KEYS_OFFSET
+            ..INTERNAL_NODE_NUM_KEYS_OFFSET + INTERNAL_NODE_NUM_KEYS_SIZE]
+            .copy_from_slice(&(num_keys - 1).to_le_bytes());
+        set_internal_node_child(node, child_num, survivor as u32);
+        set_internal_node_subtree_size(node, child_num, left_cells + right_cells);
+
+        if num_keys - 1 == 0 && parent == self.root_page_num {
+            // the root keeps its own file header; a child's image has zeros there
+            let mut image = *self.pager.page_ref(survivor)?;
+            image[IS_ROOT_OFFSET] = 1;
+            image[FORMAT_VERSION_OFFSET..]
+                .copy_from_slice(&self.pager.page_ref(parent)?[FORMAT_VERSION_OFFSET..]);
+            *self.pager.get_page(parent)? = image;
+            return Ok(2);
+        }
+
+        Ok(1)
+    }
+
+    // dirty pages in the order db_close writes them: pages outside the tree first, then
+    // the tree in post-order so a child is always on disk before any parent pointing at
+    // it, which puts the root last. bad child pointers are skipped rather than reported.
+    pub fn write_order(&mut self) -> Result<Vec<usize>> {
+        let mut in_tree = [false; TABLE_MAX_PAGES];
+        let mut post_order = vec![];
+        let mut stack = vec![(self.root_page_num, false)];
+
+        while let Some((page_num, expanded)) = stack.pop() {
+            if expanded {
+                post_order.push(page_num);
+                continue;
+            }
+            if page_num >= self.pager.num_pages || in_tree[page_num] {
+                continue;
+            }
+            in_tree[page_num] = true;
+
+            stack.push((page_num, true));
+            let node = self.pager.page_ref(page_num)?;
+            if node_type_of(node).ok() == Some(NodeType::Internal) {
+                for i in (0..=internal_node_num_keys(node)).rev() {
+                    stack.push((internal_node_child(node, i) as usize, false));
+                }
+            }
+        }
+
+        let mut order: Vec<usize> = (0..self.pager.num_pages)
+            .filter(|&page_num| !in_tree[page_num])
+            .collect();
+        order.extend(post_order);
+        order.retain(|&page_num| self.pager.is_dirty(page_num));
+
+        Ok(order)
+    }
+
+    pub fn pin_page(&mut self, page_num: usize) -> Result<PinnedPage<'_>> {
+        self.pager.load(page_num)?;
+        self.pager.pin_counts[page_num] += 1;
+        Ok(PinnedPage {
+            pager: &mut self.pager,
+            page_num,
+        })
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.pager.shrink_to_fit();
+    }
+
+    // change hook for one page, e.g. to ship its new image to a replica. see
+    // Pager::watch_page for when it runs
+    pub fn watch_page(&mut self, page_num: usize, hook: impl Fn(&[u8; PAGE_SIZE]) + 'static) {
+        self.pager.watch_page(page_num, hook);
+    }
+
+    // writes one page through to the file if it is dirty, for callers running their
+    // own durability policy. no fsync; pair it with db_close or an os-level sync.
+    pub fn flush_page(&mut self, page_num: usize) -> Result<()> {
+        if page_num >= self.pager.num_pages {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} does not exist", page_num),
+            ));
+        }
+
+        let flushed = self
+            .pager
+            .flush(page_num)
+            .and_then(|()| self.pager.checkpoint_wal());
+        self.pager.release_file();
+        flushed
+    }
+
+    // db_close's write loop without closing the file
+    pub fn flush_all(&mut self) -> Result<()> {
+        let flushed = self.write_dirty_pages();
+        self.pager.release_file();
+        flushed
+    }
+
+    fn write_dirty_pages(&mut self) -> Result<()> {
+        // any dirty page is a change, and the flush that writes it stamps the header
+        if self.pager.dirty_page_count() > 0 {
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+            let header = self.pager.get_page(0)?;
+            header[LAST_MODIFIED_OFFSET..].copy_from_slice(&nanos.to_le_bytes());
+            set_format_version(header);
+        }
+
+        // everything goes into the wal first, so the main file is only touched once the
+        // whole set can be replayed
+        let order = self.write_order()?;
+        self.pager.log_dirty_pages(&order)?;
+        let mut written = false;
+        for page_num in order {
+            let internal =
+                node_type_of(self.pager.page_ref(page_num)?).ok() == Some(NodeType::Internal);
+            if self.write_barrier && internal && written {
+                self.pager.file()?.sync_data()?;
+            }
+            self.pager.write_through(page_num)?;
+            written = true;
+        }
+        if written {
+            self.pager.checkpoint_wal()?;
+        }
+        Ok(())
+    }
+
+    // when a flush last wrote a change, as recorded in the file header. lookups alone
+    // don't count; None for a file that has never been written through this field
+    pub fn last_modified(&mut self) -> Result<Option<std::time::SystemTime>> {
+        let header = self.pager.page_ref(0)?;
+        let nanos = u64::from_le_bytes(header[LAST_MODIFIED_OFFSET..].try_into().unwrap());
+        Ok((nanos > 0).then(|| std::time::UNIX_EPOCH + Duration::from_nanos(nanos)))
+    }
+
+    // makes db_close sync before writing each internal node, so the children written
+    // ahead of it are durable by the time it can point at them
+    pub fn set_page_write_barrier(&mut self) {
+        self.write_barrier = true;
+    }
+
+    // sums num_cells over the leaf layer without deserializing anything
+    pub fn row_count(&mut self) -> Result<u64> {
+        let mut count = 0;
+        for page_num in self.leaf_pages_in_order()? {
+            count += leaf_node_num_cells(self.pager.page_ref(page_num)?) as u64;
+        }
+        Ok(count)
+    }
+
+    // deletes every row without visiting them: page 0 becomes an empty leaf root and
+    // every other page is dropped from the cache and cut off the end of the file. the new
+    // root is written and synced first, so a crash partway through never leaves a root
+    // pointing at pages the file no longer has
+    pub fn clear(&mut self) -> Result<()> {
+        if self.pager.read_only {
+            return Err(read_only_error());
+        }
+        self.structure_version += 1;
+
+        initialize_leaf_node(self.pager.get_page(ROOT_PAGE_NUM)?);
+        self.root_page_num = ROOT_PAGE_NUM;
+        // checkpoint_wal syncs the main file before dropping the wal entry
+        self.pager.flush(ROOT_PAGE_NUM)?;
+        self.pager.checkpoint_wal()?;
+        self.pager.truncate(ROOT_PAGE_NUM + 1)?;
+        self.row_cache = RowCache::new(self.row_cache.capacity);
+        Ok(())
+    }
+
+    // keeps the `max_rows` smallest ids by trimming cells off the rightmost leaves,
+    // returning how many rows went. leaves emptied this way stay in the tree until
+    // compact merges them away.
+    pub fn truncate_to(&mut self, max_rows: u64) -> Result<u64> {
+        if self.pager.read_only {
+            return Err(read_only_error());
+        }
+        let count = self.row_count()?;
+        if count <= max_rows {
+            return Ok(0);
+        }
+        self.structure_version += 1;
+
+        let mut to_delete = count - max_rows;
+        for page_num in self.leaf_pages_in_order()?.into_iter().rev() {
+            if to_delete == 0 {
+                break;
+            }
+            let num_cells = leaf_node_num_cells(self.pager.page_ref(page_num)?);
+            if num_cells == 0 {
+                continue;
+            }
+
+            let keep = num_cells.saturating_sub(to_delete.min(u32::MAX as u64) as u32);
+            let node = self.pager.get_page(page_num)?;
+            let removed: Vec<u32> = (keep..num_cells).map(|i| leaf_node_key(node, i)).collect();
+            set_leaf_node_num_cells(node, keep);
+            self.adjust_subtree_sizes(page_num, removed[0], -(removed.len() as i32))?;
+            for id in removed {
+                self.row_cache.invalidate(id);
+            }
+            to_delete -= (num_cells - keep) as u64;
+        }
+
+        Ok(count - max_rows)
+    }
+
+    // treats the table as a circular log: the `n` smallest rows are taken off the front
+    // of the leftmost leaves and re-inserted, in order, with ids continuing after the
+    // largest one. every re-inserted id lands in the rightmost leaf and leaves don't
+    // split, so this fails up front, changing nothing, unless that leaf has room.
+    pub fn rotate(&mut self, n: usize) -> Result<usize> {
+        if self.pager.read_only {
+            return Err(read_only_error());
+        }
+        let n = n.min(self.row_count()? as usize);
+        let Some(max_id) = self.last()? else {
+            return Ok(0);
+        };
+        if n == 0 {
+            return Ok(0);
+        }
+        if max_id.checked_add(n as u32).is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "rotating {} rows past id {} overflows the id space",
+                    n, max_id
+                ),
+            ));
+        }
+
+        // how many cells come off the front of each leaf
+        let mut removals = vec![];
+        let mut remaining = n;
+        let leaves = self.leaf_pages_in_order()?;
+        for &page_num in &leaves {
+            let num_cells = leaf_node_num_cells(self.pager.page_ref(page_num)?) as usize;
+            let take = remaining.min(num_cells);
+            removals.push((page_num, take, num_cells));
+            remaining -= take;
+        }
+        let &(_, taken, cells) = removals.last().unwrap();
+        if LEAF_NODE_MAX_CELLS - (cells - taken) < n {
+            return Err(Error::other(format!(
+                "leaf node full: no room to rotate {} rows into the last leaf",
+                n
+            )));
+        }
+
+        let mut oldest = Vec::with_capacity(n);
+        self.structure_version += 1;
+        for (page_num, take, num_cells) in removals {
+            if take == 0 {
+                continue;
+            }
+            let node = self.pager.get_page(page_num)?;
+            let first_key = leaf_node_key(node, 0);
+            for i in 0..take {
+                let offset = leaf_node_cell_offset(i as u32) + LEAF_NODE_VALUE_OFFSET;
+                oldest.push(deserialize_row(
+                    &node[offset..offset + LEAF_NODE_VALUE_SIZE],
+                ));
+            }
+            node.copy_within(
+                leaf_node_cell_offset(take as u32)..leaf_node_cell_offset(num_cells as u32),
+                leaf_node_cell_offset(0),
+            );
+            set_leaf_node_num_cells(node, (num_cells - take) as u32);
+            self.adjust_subtree_sizes(page_num, first_key, -(take as i32))?;
+        }
+
+        for (i, mut row) in oldest.into_iter().enumerate() {
+            self.row_cache.invalidate(row.id);
+            row.id = max_id + 1 + i as u32;
+            let (mut cursor, _) = table_find(self, row.id)?;
+            Table::insert_at_cursor(&mut cursor, &row)?;
+        }
+
+        Ok(n)
+    }
+
+    // the row with `rank` smaller ids in front of it. descends by the subtree sizes in
+    // the internal cells, so it reads one page per level and deserializes one row
+    pub fn row_at_rank(&mut self, rank: usize) -> Result<Option<Row>> {
+        let mut remaining = rank;
+        let mut page_num = self.root_page_num;
+        for _ in 0..TABLE_MAX_PAGES {
+            let node = self.pager.page_ref(page_num)?;
+            match node_type_of(node)? {
+                NodeType::Leaf => {
+                    if remaining >= leaf_node_num_cells(node) as usize {
+                        return Ok(None);
+                    }
+                    let offset = leaf_node_cell_offset(remaining as u32) + LEAF_NODE_VALUE_OFFSET;
+                    return Ok(Some(deserialize_row(
+                        &node[offset..offset + LEAF_NODE_VALUE_SIZE],
+                    )));
+                }
+                NodeType::Internal => {
+                    let num_keys = internal_node_num_keys(node);
+                    let child_num = (0..=num_keys).find(|&i| {
+                        let size = internal_node_subtree_size(node, i) as usize;
+                        if remaining < size {
+                            return true;
+                        }
+                        remaining -= size;
+                        false
+                    });
+                    let Some(child_num) = child_num else {
+                        return Ok(None);
+                    };
+                    page_num = internal_node_child(node, child_num) as usize;
+                }
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "tree is deeper than the page limit",
+        ))
+    }
+
+    // row_count with the leaf layer split across `n_threads` scoped threads. the walk
+    // that finds the leaves leaves them all cached, so the threads share that cache as
+    // a read-only snapshot instead of each going to the file.
+    pub fn parallel_count(&mut self, n_threads: usize) -> Result<u64> {
+        if n_threads == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "parallel_count needs at least one thread",
+            ));
+        }
+
+        let leaves = self.leaf_pages_in_order()?;
+        for &page_num in &leaves {
+            self.pager.page_ref(page_num)?;
+        }
+        let nodes = leaves
+            .iter()
+            .map(|&page_num| {
+                self.pager.pages[page_num].as_deref().ok_or_else(|| {
+                    Error::other(format!("leaf page {} isn't in the cache", page_num))
+                })
+            })
+            .collect::<Result<Vec<&[u8; PAGE_SIZE]>>>()?;
+        let chunk_size = nodes.len().div_ceil(n_threads).max(1);
+        let count = std::thread::scope(|scope| {
+            let workers: Vec<_> = nodes
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|&node| leaf_node_num_cells(node) as u64)
+                            .sum::<u64>()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .sum()
+        });
+
+        Ok(count)
+    }
+
+    // cheap approximation of row_count: one descent down the middle of the tree,
+    // multiplying the fanout seen at each level and the fill of the leaf it lands on.
+    // exact for a single leaf, close on an evenly filled tree.
+    pub fn estimate_rows(&mut self) -> Result<u64> {
+        let mut page_num = self.root_page_num;
+        let mut leaves = 1u64;
+
+        for _ in 0..TABLE_MAX_PAGES {
+            let node = self.pager.page_ref(page_num)?;
+            match node_type_of(node)? {
+                NodeType::Leaf => return Ok(leaves * leaf_node_num_cells(node) as u64),
+                NodeType::Internal => {
+                    let num_children = internal_node_num_keys(node) + 1;
+                    leaves *= num_children as u64;
+                    page_num = internal_node_child(node, num_children / 2) as usize;
+                }
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "tree is deeper than the page limit",
+        ))
+    }
+
+    // n distinct rows in key order, picked by rank using only the leaves' cell counts, so
+    // just the chosen rows are deserialized. the same seed gives the same sample; without
+    // one the clock is used. asking for at least row_count rows returns every row.
+    pub fn sample(&mut self, n: usize, seed: Option<u64>) -> Result<Vec<Row>> {
+        let leaves = self.leaf_pages_in_order()?;
+        let mut counts = vec![];
+        for &page_num in &leaves {
+            counts.push(leaf_node_num_cells(self.pager.page_ref(page_num)?) as u64);
+        }
+        let total: u64 = counts.iter().sum();
+
+        let mut ranks: Vec<u64> = if n as u64 >= total {
+            (0..total).collect()
+        } else {
+            let mut state = seed.unwrap_or_else(|| {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos() as u64)
+            });
+            // floyd's algorithm: n distinct ranks out of 0..total
+            let mut picked = HashSet::new();
+            for upper in total - n as u64..total {
+                let rank = splitmix64(&mut state) % (upper + 1);
+                if !picked.insert(rank) {
+                    picked.insert(upper);
+                }
+            }
+            picked.into_iter().collect()
+        };
+        ranks.sort_unstable();
+
+        let mut rows = vec![];
+        let mut leaf = 0;
+        let mut first_rank = 0;
+        for rank in ranks {
+            while rank >= first_rank + counts[leaf] {
+                first_rank += counts[leaf];
+                leaf += 1;
+            }
+            let node = self.pager.page_ref(leaves[leaf])?;
+            let offset = leaf_node_cell_offset((rank - first_rank) as u32) + LEAF_NODE_VALUE_OFFSET;
+            rows.push(deserialize_row(
+                &node[offset..offset + LEAF_NODE_VALUE_SIZE],
+            ));
+        }
+
+        Ok(rows)
+    }
+
+    // pre-order, left to right, calling `visitor(page_num, depth, node_type, keys)` once
+    // per node; the root is depth 0. a leaf's keys are its cells' keys, an internal
+    // node's are its separators. fails like leaf_pages_in_order on a malformed tree.
+    pub fn walk_depth_first<F>(&mut self, mut visitor: F) -> Result<()>
+    where
+        F: FnMut(usize, usize, NodeType, &[u32]),
+    {
+        let mut visited = [false; TABLE_MAX_PAGES];
+        let mut stack = vec![(self.root_page_num, 0)];
+
+        while let Some((page_num, depth)) = stack.pop() {
+            if page_num >= TABLE_MAX_PAGES || visited[page_num] {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("page {} is out of range or reachable twice", page_num),
+                ));
+            }
+            visited[page_num] = true;
+
+            let node = self.pager.page_ref(page_num)?;
+            let node_type = node_type_of(node)?;
+            let keys: Vec<u32> = match node_type {
+                NodeType::Leaf => (0..leaf_node_num_cells(node))
+                    .map(|i| leaf_node_key(node, i))
+                    .collect(),
+                NodeType::Internal => {
+                    let num_keys = internal_node_num_keys(node);
+                    for i in (0..=num_keys).rev() {
+                        stack.push((internal_node_child(node, i) as usize, depth + 1));
+                    }
+                    (0..num_keys).map(|i| internal_node_key(node, i)).collect()
+                }
+            };
+
+            visitor(page_num, depth, node_type, &keys);
+        }
+
+        Ok(())
+    }
+
+    // deepest level any leaf sits at, the root being 0. equal to every other leaf's
+    // depth in a balanced tree; integrity_check reports it when it isn't
+    pub fn max_leaf_depth(&mut self) -> Result<usize> {
+        let mut max_depth = 0;
+        self.walk_depth_first(|_, depth, node_type, _| {
+            if node_type == NodeType::Leaf {
+                max_depth = max_depth.max(depth);
+            }
+        })?;
+        Ok(max_depth)
+    }
+
+    // pages on the way from the root down to the leftmost leaf, the leaf included; what
+    // one point lookup reads in a balanced tree
+    fn height(&mut self) -> Result<usize> {
+        let mut page_num = self.root_page_num;
+        for height in 1..=TABLE_MAX_PAGES {
+            let node = self.pager.page_ref(page_num)?;
+            match node_type_of(node)? {
+                NodeType::Leaf => return Ok(height),
+                NodeType::Internal => page_num = internal_node_child(node, 0) as usize,
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "tree is deeper than the page limit",
+        ))
+    }
+
+    // depth-first, left to right, so leaves come back in key order
+    fn leaf_pages_in_order(&mut self) -> Result<Vec<usize>> {
+        let mut leaves = vec![];
+        let mut visited = [false; TABLE_MAX_PAGES];
+        let mut stack = vec![self.root_page_num];
+
+        while let Some(page_num) = stack.pop() {
+            if page_num >= TABLE_MAX_PAGES || visited[page_num] {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("page {} is out of range or reachable twice", page_num),
+                ));
+            }
+            visited[page_num] = true;
+
+            let node = self.pager.page_ref(page_num)?;
+            match node_type_of(node)? {
+                NodeType::Leaf => leaves.push(page_num),
+                NodeType::Internal => {
+                    for i in (0..=internal_node_num_keys(node)).rev() {
+                        stack.push(internal_node_child(node, i) as usize);
+                    }
+                }
+            }
+        }
+
+        Ok(leaves)
+    }
+
+    // there are no next_leaf pointers on disk: scans visit leaves in the order internal
+    // nodes list their children, so that order is the leaf chain. puts every internal
+    // node's children back in key order, sorting by each subtree's smallest key and
+    // rewriting the separators to match, then recounting the subtree sizes under the
+    // moved children. returns how many internal nodes changed
+    pub fn repair_leaf_chain(&mut self) -> Result<usize> {
+        let mut repaired = 0;
+        self.repair_child_order(self.root_page_num, 0, &mut repaired)?;
+        if repaired > 0 {
+            self.repair_subtree_sizes()?;
+            self.structure_version += 1;
+        }
+        Ok(repaired)
+    }
+
+    // the (smallest, largest) key under page_num once its subtree is in order, None if
+    // the subtree holds no rows
+    fn repair_child_order(
+        &mut self,
+        page_num: usize,
+        depth: usize,
+        repaired: &mut usize,
+    ) -> Result<Option<(u32, u32)>> {
+        if depth >= TABLE_MAX_PAGES {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "tree is deeper than the page limit",
+            ));
+        }
+
+        let node = self.pager.page_ref(page_num)?;
+        if node_type_of(node)? == NodeType::Leaf {
+            let num_cells = leaf_node_num_cells(node);
+            return Ok((num_cells > 0)
+                .then(|| (leaf_node_key(node, 0), leaf_node_key(node, num_cells - 1))));
+        }
+
+        let children: Vec<u32> = (0..=internal_node_num_keys(node))
+            .map(|i| internal_node_child(node, i))
+            .collect();
+        let mut subtrees = vec![];
+        for &child in &children {
+            subtrees.push((
+                self.repair_child_order(child as usize, depth + 1, repaired)?,
+                child,
+            ));
+        }
+        // stable, and empty subtrees sort first, so a tree already in order is untouched
+        subtrees.sort_by_key(|(range, _)| range.map(|(min, _)| min));
+
+        let node = self.pager.page_ref(page_num)?;
+        let mut separators = vec![];
+        let mut max_so_far = 0;
+        for (range, _) in &subtrees {
+            if let Some((_, max)) = range {
+                max_so_far = *max;
+            }
+            separators.push(max_so_far);
+        }
+        let in_order = subtrees
+            .iter()
+            .map(|&(_, child)| child)
+            .eq(children.iter().copied())
+            && (0..internal_node_num_keys(node))
+                .all(|i| internal_node_key(node, i) == separators[i as usize]);
+
+        if !in_order {
+            let node = self.pager.get_page(page_num)?;
+            for (i, &(_, child)) in subtrees.iter().enumerate() {
+                set_internal_node_child(node, i as u32, child);
+                if (i as u32) < internal_node_num_keys(node) {
+                    let offset = internal_node_cell_offset(i as u32) + INTERNAL_NODE_CHILD_SIZE;
+                    node[offset..offset + INTERNAL_NODE_KEY_SIZE]
+                        .copy_from_slice(&separators[i].to_le_bytes());
+                }
+            }
+            *repaired += 1;
+        }
+
+        let mut ranges = subtrees.iter().filter_map(|(range, _)| *range);
+        Ok(ranges.next().map(|(min, max)| {
+            ranges.fold((min, max), |(min, max), (lo, hi)| {
+                (min.min(lo), max.max(hi))
+            })
+        }))
+    }
+
+    // the (key, row) stored in one leaf cell, read straight off the page; meant for
+    // tests checking exact cell layout
+    pub fn debug_cell(&mut self, page_num: usize, cell_num: usize) -> Result<(u32, Row)> {
+        if page_num >= self.pager.num_pages {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} does not exist", page_num),
+            ));
+        }
+        let node = self.pager.page_ref(page_num)?;
+        if node_type_of(node)? != NodeType::Leaf {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} is not a leaf", page_num),
+            ));
+        }
+        if cell_num >= leaf_node_num_cells(node) as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} has no cell {}", page_num, cell_num),
+            ));
+        }
+
+        let offset = leaf_node_cell_offset(cell_num as u32) + LEAF_NODE_VALUE_OFFSET;
+        Ok((
+            leaf_node_key(node, cell_num as u32),
+            deserialize_row(&node[offset..offset + LEAF_NODE_VALUE_SIZE]),
+        ))
+    }
+
+    // one header line plus a line per cell. there is no on-disk sibling chain, so
+    // prev/next are the neighbouring leaves in key order
+    pub fn explain_leaf(&mut self, page_num: usize) -> Result<String> {
+        if page_num >= self.pager.num_pages {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} does not exist", page_num),
+            ));
+        }
+        if node_type_of(self.pager.page_ref(page_num)?)? != NodeType::Leaf {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} is not a leaf", page_num),
+            ));
+        }
+
+        let leaves = self.leaf_pages_in_order()?;
+        let position = leaves.iter().position(|&leaf| leaf == page_num);
+        let sibling = |page: Option<&usize>| page.map_or("none".to_string(), |p| p.to_string());
+        let prev = sibling(
+            position
+                .and_then(|i| i.checked_sub(1))
+                .and_then(|i| leaves.get(i)),
+        );
+        let next = sibling(position.and_then(|i| leaves.get(i + 1)));
+
+        let node = self.pager.page_ref(page_num)?;
+        let num_cells = leaf_node_num_cells(node);
+        let mut out = format!(
+            "page {} | leaf | cells: {}/{} | prev: {} | next: {}\n",
+            page_num, num_cells, LEAF_NODE_MAX_CELLS, prev, next
+        );
+        for i in 0..num_cells {
+            let offset = leaf_node_cell_offset(i) + LEAF_NODE_VALUE_OFFSET;
+            let row = deserialize_row(&node[offset..offset + LEAF_NODE_VALUE_SIZE]);
+            out.push_str(&format!(
+                "  cell {}: key={} username={} email={}\n",
+                i,
+                leaf_node_key(node, i),
+                row.username,
+                row.email
+            ));
+        }
+
+        Ok(out)
+    }
+
+    // point lookup by id, served from the row cache when enabled
+    pub fn get(&mut self, id: u32) -> Result<Option<Row>> {
+        if let Some(row) = self.row_cache.get(id) {
+            return Ok(Some(row));
+        }
+
+        let (mut cursor, exact_match) = table_find(self, id)?;
+        if !exact_match {
+            return Ok(None);
+        }
+
+        let row = deserialize_row(cursor.value_ref()?);
+        self.row_cache.put(row.clone());
+        Ok(Some(row))
+    }
+
+    // like `get` but never deserializes the row
+    pub fn contains(&mut self, id: u32) -> Result<bool> {
+        let (_, exact_match) = table_find(self, id)?;
+        Ok(exact_match)
+    }
+
+    // batch point lookup, results in input order. ids are visited sorted so that runs of
+    // ids falling in the same leaf share one descent instead of one each
+    pub fn get_many(&mut self, ids: &[u32]) -> Result<Vec<Option<Row>>> {
+        let mut order: Vec<usize> = (0..ids.len()).collect();
+        order.sort_by_key(|&i| ids[i]);
+
+        let mut results = vec![None; ids.len()];
+        // the last leaf descended into and its largest key
+        let mut leaf: Option<(usize, u32)> = None;
+        for i in order {
+            let id = ids[i];
+            let page_num = match leaf {
+                Some((page_num, max_key)) if id <= max_key => page_num,
+                _ => {
+                    let (cursor, _) = table_find(self, id)?;
+                    let page_num = cursor.page_num;
+                    let node = self.pager.page_ref(page_num)?;
+                    let num_cells = leaf_node_num_cells(node);
+                    if num_cells > 0 {
+                        leaf = Some((page_num, leaf_node_key(node, num_cells - 1)));
+                    }
+                    page_num
+                }
+            };
+
+            let node = self.pager.page_ref(page_num)?;
+            if let Some(cell_num) =
+                (0..leaf_node_num_cells(node)).find(|&c| leaf_node_key(node, c) == id)
+            {
+                let offset = leaf_node_cell_offset(cell_num) + LEAF_NODE_VALUE_OFFSET;
+                results[i] = Some(deserialize_row(
+                    &node[offset..offset + LEAF_NODE_VALUE_SIZE],
+                ));
+            }
+        }
+
+        Ok(results)
+    }
+
+    // every row, largest id first: a cursor from table_end steps back one leaf at a
+    // time, reading each back to front
+    pub fn scan_descending(&mut self) -> Result<Vec<Row>> {
+        let mut rows = vec![];
+        let mut cursor = Cursor::table_end(self)?;
+        loop {
+            let node = cursor.table.pager.page_ref(cursor.page_num)?;
+            for i in (0..leaf_node_num_cells(node)).rev() {
+                let offset = leaf_node_cell_offset(i) + LEAF_NODE_VALUE_OFFSET;
+                rows.push(deserialize_row(
+                    &node[offset..offset + LEAF_NODE_VALUE_SIZE],
+                ));
+            }
+            match cursor.neighbour_leaf(Edge::Left)? {
+                Some(page_num) => cursor.page_num = page_num,
+                None => break,
+            }
+        }
+        Ok(rows)
+    }
+
+    // keyset pagination: up to `limit` rows with id >= start_id, starting from the
+    // leaf table_find lands on rather than from the beginning of the table
+    pub fn scan_forward_from(&mut self, start_id: u32, limit: usize) -> Result<Vec<Row>> {
+        let (mut cursor, _) = table_find(self, start_id)?;
+        // table_find only looked at one leaf; let the cursor decide if anything follows it
+        cursor.end_of_table = false;
+
+        let mut rows = vec![];
+        while rows.len() < limit {
+            match cursor.next_row()? {
+                Some(row) => rows.push(row),
+                None => break,
+            }
+        }
+        Ok(rows)
+    }
+
+    // whether any row matches, stopping at the first one that does. cheaper than
+    // counting matches when the answer is yes, e.g. checking an email is free before
+    // an insert
+    pub fn exists_where(&mut self, predicate: impl Fn(&Row) -> bool) -> Result<bool> {
+        let mut cursor = Cursor::table_start(self)?;
+        while let Some(row) = cursor.next_row()? {
+            if predicate(&row) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    // every row with id >= `id`, in key order: resume a scan from the last key seen
+    // rather than an offset, which insertions before that key would shift
+    pub fn iter_from(&mut self, id: u32) -> Result<impl Iterator<Item = Row> + use<>> {
+        Ok(self.scan_forward_from(id, usize::MAX)?.into_iter())
+    }
+
+    // linear scan until an index exists; callers shouldn't need to change once it does
+    pub fn find_by_email(&mut self, email: &str) -> Result<Option<Row>> {
+        let mut cursor = Cursor::table_start(self)?;
+        while let Some(row) = cursor.next_row()? {
+            if row.email == email {
+                return Ok(Some(row));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn find_all_by_email(&mut self, email: &str) -> Result<Vec<Row>> {
+        let mut rows = vec![];
+        let mut cursor = Cursor::table_start(self)?;
+        while let Some(row) = cursor.next_row()? {
+            if row.email == email {
+                rows.push(row);
+            }
+        }
+
+        Ok(rows)
+    }
+
+    // reads a dump of concatenated ROW_SIZE records, inserting each valid one at its
+    // place in key order, in whatever order the file has them. returns (imported,
+    // skipped); invalid records, a trailing partial record and ids already in the table
+    // or earlier in the file count as skipped. if the rows won't all fit in the leaves
+    // they belong to, fails before writing any of them.
+    pub fn import_fixed(&mut self, path: &str) -> Result<(usize, usize)> {
+        let data = fs::read(path)?;
+        let mut skipped = 0;
+
+        let mut rows = vec![];
+        let mut seen = HashSet::new();
+        // cells each leaf gains from this import
+        let mut added: HashMap<usize, usize> = HashMap::new();
+        for chunk in data.chunks(ROW_SIZE) {
+            if !is_valid_serialized_row(chunk) {
+                skipped += 1;
+                continue;
+            }
+            let row = deserialize_row(chunk);
+            let (cursor, exact_match) = table_find(self, row.id)?;
+            if exact_match || !seen.insert(row.id) {
+                skipped += 1;
+                continue;
+            }
+            *added.entry(cursor.page_num).or_default() += 1;
+            rows.push(row);
+        }
+
+        for (&page_num, &extra) in &added {
+            let num_cells = leaf_node_num_cells(self.pager.page_ref(page_num)?) as usize;
+            if num_cells + extra > LEAF_NODE_MAX_CELLS {
+                return Err(Error::other(format!(
+                    "leaf node full: page {} has room for {} more rows, the import needs {}",
+                    page_num,
+                    LEAF_NODE_MAX_CELLS - num_cells.min(LEAF_NODE_MAX_CELLS),
+                    extra
+                )));
+            }
+        }
+
+        for row in &rows {
+            let (mut cursor, _) = table_find(self, row.id)?;
+            Table::insert_at_cursor(&mut cursor, row)?;
+        }
+
+        Ok((rows.len(), skipped))
+    }
+
+    // inserts a batch in key order, so consecutive rows land next to each other in the
+    // same leaf. stops at the first duplicate or full leaf; returns the number inserted.
+    pub fn insert_many(&mut self, rows: &[Row]) -> Result<usize> {
+        let mut sorted: Vec<&Row> = rows.iter().collect();
+        sorted.sort_by_key(|row| row.id);
+
+        for (inserted, row) in sorted.into_iter().enumerate() {
+            let (mut cursor, exact_match) = table_find(self, row.id)?;
+            if exact_match {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("duplicate key {} after {} rows", row.id, inserted),
+                ));
+            }
+            Table::insert_at_cursor(&mut cursor, row)?;
+        }
+
+        Ok(rows.len())
+    }
+
+    // sorts the batch by id and inserts through one cursor that only moves forward,
+    // descending from the root again only when an id runs past the current leaf.
+    // stops at the first duplicate or full leaf, keeping whatever was inserted.
+    pub fn insert_sorted_batch(&mut self, mut rows: Vec<Row>) -> Result<()> {
+        rows.sort_by_key(|row| row.id);
+
+        let mut cursor: Option<Cursor> = None;
+        for row in &rows {
+            let mut current = match cursor.take() {
+                Some(mut current) => {
+                    let node = current.table.pager.page_ref(current.page_num)?;
+                    let num_cells = leaf_node_num_cells(node);
+                    if num_cells > 0 && row.id <= leaf_node_key(node, num_cells - 1) {
+                        // still inside this leaf: the slot is at or after the last one
+                        while leaf_node_key(node, current.cell_num as u32) < row.id {
+                            current.cell_num += 1;
+                        }
+                        current
+                    } else {
+                        table_find(current.table, row.id)?.0
+                    }
+                }
+                None => table_find(self, row.id)?.0,
+            };
+
+            let node = current.table.pager.page_ref(current.page_num)?;
+            if (current.cell_num as u32) < leaf_node_num_cells(node)
+                && leaf_node_key(node, current.cell_num as u32) == row.id
+            {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("duplicate key {}", row.id),
+                ));
+            }
+            Table::insert_at_cursor(&mut current, row)?;
+            cursor = Some(current);
+        }
+
+        Ok(())
+    }
+
+    // synthetic `useri useri@example.com` rows, numbered on from the current max key
+    pub fn seed(&mut self, n: usize) -> Result<usize> {
+        let start = self.last()?.map_or(1, |key| key + 1);
+        let rows: Vec<Row> = (start..start + n as u32)
+            .map(|i| Row {
+                id: i,
+                username: format!("user{}", i),
+                email: format!("user{}@example.com", i),
+            })
+            .collect();
+
+        self.insert_many(&rows)
+    }
+
+    // merge-join over both tables' keys, which key_iter yields in sorted order
+    pub fn diff(&mut self, other: &mut Table) -> Result<Vec<RowDiff>> {
+        let ours: Vec<u32> = self.key_iter()?.collect::<Result<_>>()?;
+        let theirs: Vec<u32> = other.key_iter()?.collect::<Result<_>>()?;
+
+        let mut diffs = vec![];
+        let (mut i, mut j) = (0, 0);
+        while i < ours.len() || j < theirs.len() {
+            let ord = match (ours.get(i), theirs.get(j)) {
+                (Some(a), Some(b)) => a.cmp(b),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, _) => std::cmp::Ordering::Greater,
+            };
+
+            match ord {
+                std::cmp::Ordering::Less => {
+                    diffs.push(RowDiff::OnlyInSelf(self.get(ours[i])?.unwrap()));
+                    i += 1;
+                }
+                std::cmp::Ordering::Greater => {
+                    diffs.push(RowDiff::OnlyInOther(other.get(theirs[j])?.unwrap()));
+                    j += 1;
+                }
+                std::cmp::Ordering::Equal => {
+                    let a = self.get(ours[i])?.unwrap();
+                    let b = other.get(theirs[j])?.unwrap();
+                    if a != b {
+                        diffs.push(RowDiff::Changed { ours: a, theirs: b });
+                    }
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+
+        Ok(diffs)
+    }
+
+    // copies every row of `other` into this table in key order, settling duplicate ids
+    // by `on_conflict`. returns how many rows were inserted or replaced; an Error
+    // conflict or full leaf stops the merge, keeping whatever was already merged.
+    pub fn merge_from(&mut self, other: &mut Table, on_conflict: MergeConflict) -> Result<usize> {
+        if self.pager.read_only {
+            return Err(read_only_error());
+        }
+        let mut merged = 0;
+        let mut rows = Cursor::table_start(other)?;
+        while let Some(row) = rows.next_row()? {
+            let id = row.id;
+            let (mut cursor, exact_match) = table_find(self, id)?;
+            if !exact_match {
+                Table::insert_at_cursor(&mut cursor, &row)?;
+                merged += 1;
+                continue;
+            }
+
+            match on_conflict {
+                MergeConflict::Skip => {}
+                MergeConflict::Replace => {
+                    serialize_row(&row, cursor.value()?)?;
+                    cursor.table.row_cache.invalidate(id);
+                    merged += 1;
+                }
+                MergeConflict::Error => {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("duplicate key {} after {} rows", id, merged),
+                    ));
+                }
+            }
+        }
+
+        Ok(merged)
+    }
+
+    // keys in order, read one cell at a time through a cursor as the iterator is
+    // driven; only the key field is read, no row is deserialized
+    pub fn key_iter(&mut self) -> Result<impl Iterator<Item = Result<u32>> + '_> {
+        let mut cursor = Cursor::table_start(self)?;
+        Ok(std::iter::from_fn(move || cursor.next_key().transpose()))
+    }
+
+    // smallest key, from the first non-empty leaf
+    pub fn first(&mut self) -> Result<Option<u32>> {
+        for page_num in self.leaf_pages_in_order()? {
+            let node = self.pager.page_ref(page_num)?;
+            if leaf_node_num_cells(node) > 0 {
+                return Ok(Some(leaf_node_key(node, 0)));
+            }
+        }
+        Ok(None)
+    }
+
+    // largest key, from the last non-empty leaf
+    pub fn last(&mut self) -> Result<Option<u32>> {
+        for page_num in self.leaf_pages_in_order()?.into_iter().rev() {
+            let node = self.pager.page_ref(page_num)?;
+            let num_cells = leaf_node_num_cells(node);
+            if num_cells > 0 {
+                return Ok(Some(leaf_node_key(node, num_cells - 1)));
+            }
+        }
+        Ok(None)
+    }
+
+    // key counts over `buckets` equal-width ranges spanning [first, last]; handy for
+    // spotting skew. an empty table gives all zeros.
+    pub fn key_histogram(&mut self, buckets: usize) -> Result<Vec<u64>> {
+        let mut counts = vec![0; buckets];
+        let (Some(min), Some(max)) = (self.first()?, self.last()?) else {
+            return Ok(counts);
+        };
+        if buckets == 0 {
+            return Ok(counts);
+        }
+
+        let span = (max - min) as u64 + 1;
+        for key in self.key_iter()? {
+            let key = key?;
+            counts[((key - min) as u64 * buckets as u64 / span) as usize] += 1;
+        }
+        Ok(counts)
+    }
+
+    // appends a page image to `<file>.wal` without touching the main file or the cache.
+    // a flush logs all of its dirty pages this way before writing any of them, and
+    // restore_from_wal applies the entries in order on the next open if the main file
+    // never caught up
+    pub fn write_ahead_append(&mut self, page_num: usize, data: &[u8; PAGE_SIZE]) -> Result<()> {
+        if self.pager.read_only {
+            return Err(read_only_error());
+        }
+        if page_num >= TABLE_MAX_PAGES {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} is out of bounds", page_num),
+            ));
+        }
+        self.pager.append_wal(&[(page_num, data)])
+    }
+
+    // replays complete `(page_num, page_bytes)` entries from `<file>.wal` into the pager,
+    // writes them through to the main file, then removes the wal. a clean shutdown never
+    // leaves one behind, so any wal found here is from a crashed writer. a torn trailing
+    // entry from a crash mid-append is ignored.
+    pub fn restore_from_wal(&mut self) -> Result<u64> {
+        let wal_path = self.pager.wal_path();
+        let mut wal = match File::open(&wal_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        // the file is stale until the wal is replayed, and replaying needs write access
+        if self.pager.read_only {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "database has a wal to replay; open it read-write first",
+            ));
+        }
+
+        let mut contents = vec![];
+        wal.read_to_end(&mut contents)?;
+
+        let mut replayed = 0;
+        for entry in contents.chunks_exact(WAL_ENTRY_SIZE) {
+            let page_num =
+                u32::from_le_bytes(entry[..WAL_PAGE_NUM_SIZE].try_into().unwrap()) as usize;
+            if page_num >= TABLE_MAX_PAGES {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("wal entry references page {} past the page limit", page_num),
+                ));
+            }
+
+            let page = self.pager.get_page(page_num)?;
+            page.copy_from_slice(&entry[WAL_PAGE_NUM_SIZE..]);
+            // already in the wal being replayed, so no need to log it again
+            self.pager.write_through(page_num)?;
+            replayed += 1;
+        }
+
+        if replayed > 0 {
+            self.pager.file()?.sync_all()?;
+        }
+        drop(wal);
+        fs::remove_file(&wal_path)?;
+
+        Ok(replayed)
+    }
+
+    // inserts at a pre-positioned cursor (e.g. from table_find) and leaves it just past
+    // the new cell, so a sorted batch can share one cursor instead of repositioning each time
+    pub fn insert_at_cursor(cursor: &mut Cursor, row: &Row) -> Result<()> {
+        if cursor.table.pager.read_only {
+            return Err(read_only_error());
+        }
+        let num_cells = leaf_node_num_cells(cursor.table.pager.page_ref(cursor.page_num)?);
+        if num_cells as usize >= LEAF_NODE_MAX_CELLS {
+            return Err(Error::other("leaf node full"));
+        }
+
+        leaf_node_insert(cursor, row.id, row)?;
+        cursor.cell_num += 1;
+        // this cursor stays valid, any other one is now stale
+        cursor.table.structure_version += 1;
+        cursor.version = cursor.table.structure_version;
+        cursor.table.row_cache.invalidate(row.id);
+        cursor.table.last_insert_id = Some(row.id);
+
+        Ok(())
+    }
+
+    pub fn row_size_bytes(&self) -> usize {
+        ROW_SIZE
+    }
+
+    // what the file will occupy once every cached page is flushed
+    pub fn estimated_file_size(&mut self) -> u64 {
+        (self.pager.num_pages * PAGE_SIZE) as u64
+    }
+
+    pub fn actual_file_size(&self) -> Result<u64> {
+        Ok(fs::metadata(&self.pager.filename)?.len())
+    }
+
+    // insert meant to be retried: a token already seen is a no-op success, whatever id
+    // the retry carries. a failed insert doesn't record its token.
+    pub fn insert_idempotent(&mut self, token: &str, row: &Row) -> Result<ExecuteResult> {
+        if self.idempotency_keys.contains_key(token) {
+            return Ok(ExecuteResult::Success);
+        }
+
+        let (mut cursor, exact_match) = table_find(self, row.id)?;
+        if exact_match {
+            return Ok(ExecuteResult::DuplicateKey);
+        }
+        Table::insert_at_cursor(&mut cursor, row)?;
+        self.idempotency_keys.insert(token.to_string(), row.id);
+
+        Ok(ExecuteResult::Success)
+    }
+
+    // id inserted under `token`, if it has been seen
+    pub fn idempotency_key_id(&self, token: &str) -> Option<u32> {
+        self.idempotency_keys.get(token).copied()
+    }
+
+    // label shown by .btree and .stats, ahead of supporting more than one table
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_string());
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    // up to `n` rows from a saved position, or from the first row if there is none, and
+    // the token to carry on from; None once the table is exhausted. this is what lets
+    // the repl's .page resume in a later session
+    pub fn page_from_token(
+        &mut self,
+        token: Option<CursorToken>,
+        n: usize,
+    ) -> Result<(Vec<Row>, Option<CursorToken>)> {
+        let mut cursor = match token {
+            Some(token) => Cursor::from_token(self, token)?,
+            None => Cursor::table_start(self)?,
+        };
+        let mut rows = vec![];
+        while rows.len() < n
+            && let Some(row) = cursor.next_row()?
+        {
+            rows.push(row);
+        }
+
+        let next = (!cursor.end_of_table).then(|| cursor.to_token());
+        Ok((rows, next))
+    }
+
+    // what the last insert statement cost, recorded only while debug is on
+    pub fn last_insert_stats(&self) -> Option<InsertStats> {
+        self.last_insert_stats
+    }
+
+    // id of the most recent successful insert in this session
+    pub fn last_insert_id(&self) -> Option<u32> {
+        self.last_insert_id
+    }
+}
+
+// a cursor position that outlives the cursor: page_num then cell_num, both u32 le.
+// like the cursor it came from, it only means the same row while the tree is unchanged
+pub type CursorToken = [u8; 8];
+
+pub struct Cursor<'a> {
+    pub table: &'a mut Table,
+    pub page_num: usize,
+    pub cell_num: usize,
+    pub end_of_table: bool,
+    // table.structure_version when page_num/cell_num were last known good
+    version: u64,
+    // (internal page, child index) for each level from the root down to page_num, so
+    // moving to a neighbouring leaf climbs only as far as the nearest common ancestor.
+    // rebuilt from the tree whenever it doesn't end at page_num
+    path: Vec<(usize, u32)>,
+}
+
+impl<'a> Cursor<'a> {
+    // first cell of the leftmost non-empty leaf
+    pub fn table_start(table: &'a mut Table) -> Result<Self> {
+        let mut path = vec![];
+        let page_num = descend_to_edge(table, table.root_page_num, Edge::Left, &mut path)?;
+        let mut cursor = Cursor {
+            version: table.structure_version,
+            table,
+            page_num,
+            cell_num: 0,
+            end_of_table: false,
+            path,
+        };
+        cursor.skip_exhausted_leaves()?;
+
+        Ok(cursor)
+    }
+
+    // back to where table_start would put it, keeping the borrow. the position is
+    // fresh, so this also revives a cursor the tree changed underneath
+    pub fn rewind(&mut self) -> Result<()> {
+        self.path.clear();
+        self.page_num = descend_to_edge(
+            self.table,
+            self.table.root_page_num,
+            Edge::Left,
+            &mut self.path,
+        )?;
+        self.cell_num = 0;
+        self.end_of_table = false;
+        self.version = self.table.structure_version;
+        self.skip_exhausted_leaves()
+    }
+
+    pub fn to_token(&self) -> CursorToken {
+        let mut token = [0; 8];
+        token[..4].copy_from_slice(&(self.page_num as u32).to_le_bytes());
+        token[4..].copy_from_slice(&(self.cell_num as u32).to_le_bytes());
+        token
+    }
+
+    // resumes at a position from to_token, possibly saved by an earlier session. the
+    // page has to be a leaf of this tree and the cell within it (or one past its end)
+    pub fn from_token(table: &'a mut Table, token: CursorToken) -> Result<Self> {
+        let page_num = u32::from_le_bytes(token[..4].try_into().unwrap()) as usize;
+        let cell_num = u32::from_le_bytes(token[4..].try_into().unwrap()) as usize;
+        let in_tree = table.leaf_pages_in_order()?.contains(&page_num);
+        if !in_tree || cell_num > leaf_node_num_cells(table.pager.page_ref(page_num)?) as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "cursor token (page {}, cell {}) doesn't point into this table",
+                    page_num, cell_num
+                ),
+            ));
+        }
+
+        let mut cursor = Cursor {
+            version: table.structure_version,
+            table,
+            page_num,
+            cell_num,
+            end_of_table: false,
+            path: vec![],
+        };
+        cursor.skip_exhausted_leaves()?;
+
+        Ok(cursor)
+    }
+
+    // one past the last cell of the rightmost leaf
+    pub fn table_end(table: &'a mut Table) -> Result<Self> {
+        let mut path = vec![];
+        let page_num = descend_to_edge(table, table.root_page_num, Edge::Right, &mut path)?;
+        let num_cells = leaf_node_num_cells(table.pager.page_ref(page_num)?);
+
+        Ok(Cursor {
+            version: table.structure_version,
+            table,
+            page_num,
+            cell_num: num_cells as usize,
+            end_of_table: true,
+            path,
+        })
+    }
+
+    // the row bytes under the cursor, for writing: the page is marked dirty
+    pub fn value(&mut self) -> Result<&mut [u8]> {
+        self.check_on_cell()?;
+        let page = self.table.pager.get_page(self.page_num)?;
+        Ok(leaf_node_value(page, self.cell_num as u32))
+    }
+
+    // the row bytes under the cursor, for reading: the page's dirty flag is left alone
+    pub fn value_ref(&mut self) -> Result<&[u8]> {
+        self.check_on_cell()?;
+        let page = self.table.pager.page_ref(self.page_num)?;
+        let offset = leaf_node_cell_offset(self.cell_num as u32) + LEAF_NODE_VALUE_OFFSET;
+        Ok(&page[offset..offset + LEAF_NODE_VALUE_SIZE])
+    }
+
+    fn check_on_cell(&mut self) -> Result<()> {
+        self.check_valid()?;
+        self.skip_exhausted_leaves()?;
+        if self.end_of_table {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "cursor is past the end of the table",
+            ));
+        }
+        Ok(())
+    }
+
+    // the row under the cursor, moving past it; None once the table is exhausted
+    pub fn next_row(&mut self) -> Result<Option<Row>> {
+        self.check_valid()?;
+        self.skip_exhausted_leaves()?;
+        if self.end_of_table {
+            return Ok(None);
+        }
+
+        let row = deserialize_row(self.value_ref()?);
+        self.advance()?;
+        Ok(Some(row))
+    }
+
+    // like next_row, but reads only the key of the cell under the cursor
+    pub fn next_key(&mut self) -> Result<Option<u32>> {
+        self.check_valid()?;
+        self.skip_exhausted_leaves()?;
+        if self.end_of_table {
+            return Ok(None);
+        }
+
+        let key = leaf_node_key(
+            self.table.pager.page_ref(self.page_num)?,
+            self.cell_num as u32,
+        );
+        self.advance()?;
+        Ok(Some(key))
+    }
+
+    pub fn advance(&mut self) -> Result<()> {
+        self.check_valid()?;
+        self.table.cursor_advances += 1;
+        self.cell_num += 1;
+        self.skip_exhausted_leaves()
+    }
+
+    // moves forward `n` cells, a leaf at a time rather than a cell at a time. running
+    // past the last cell leaves the cursor at end_of_table
+    pub fn skip(&mut self, n: usize) -> Result<()> {
+        self.check_valid()?;
+        let mut remaining = n;
+        while remaining > 0 && !self.end_of_table {
+            let num_cells = leaf_node_num_cells(self.table.pager.page_ref(self.page_num)?) as usize;
+            let step = remaining.min(num_cells.saturating_sub(self.cell_num));
+            self.cell_num += step;
+            remaining -= step;
+            self.skip_exhausted_leaves()?;
+        }
+        Ok(())
+    }
+
+    // the tree changed shape since this cursor was positioned, e.g. an insert through
+    // another cursor shifted the cells it points into
+    fn check_valid(&self) -> Result<()> {
+        if self.version != self.table.structure_version {
+            return Err(Error::other(
+                "cursor invalidated: the tree changed underneath it",
+            ));
+        }
+        Ok(())
+    }
+
+    // the leaf under the cursor may have run out of cells because the cursor passed
+    // its last one or because cells were removed underneath it; either way move on to
+    // the first cell of the next non-empty leaf in key order, or hit end_of_table
+    fn skip_exhausted_leaves(&mut self) -> Result<()> {
+        while self.cell_num
+            >= leaf_node_num_cells(self.table.pager.page_ref(self.page_num)?) as usize
+        {
+            match self.neighbour_leaf(Edge::Right)? {
+                Some(page_num) => {
+                    self.page_num = page_num;
+                    self.cell_num = 0;
+                }
+                None => {
+                    self.end_of_table = true;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // the leaf after (Edge::Right) or before (Edge::Left) page_num in key order, moving
+    // the path to it: up to the first ancestor with a child further that way, then down
+    // that child's near edge. None at the end of the leaf layer
+    fn neighbour_leaf(&mut self, towards: Edge) -> Result<Option<usize>> {
+        let on_path = match self.path.last() {
+            Some(&(parent, child_num)) => {
+                internal_node_child(self.table.pager.page_ref(parent)?, child_num) as usize
+                    == self.page_num
+            }
+            None => self.page_num == self.table.root_page_num,
+        };
+        if !on_path {
+            self.path = self.table.path_to(self.page_num)?;
+        }
+
+        while let Some((parent, child_num)) = self.path.pop() {
+            let num_keys = internal_node_num_keys(self.table.pager.page_ref(parent)?);
+            let sibling = match towards {
+                Edge::Right if child_num < num_keys => child_num + 1,
+                Edge::Left if child_num > 0 => child_num - 1,
+                _ => continue,
+            };
+            self.path.push((parent, sibling));
+            let child = internal_node_child(self.table.pager.page_ref(parent)?, sibling) as usize;
+            let near_edge = match towards {
+                Edge::Right => Edge::Left,
+                Edge::Left => Edge::Right,
+            };
+            return descend_to_edge(self.table, child, near_edge, &mut self.path).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    // counts every cell in the table regardless of the cursor's position
+    pub fn total_cells(&mut self) -> Result<u64> {
+        self.table.row_count()
+    }
+}
+
+// descends to the leaf that would hold `key` and positions a cursor on the first cell
+// with a key >= `key` (the insertion point), returning whether the key is already present
+pub fn table_find(table: &mut Table, key: u32) -> Result<(Cursor<'_>, bool)> {
+    let mut page_num = table.root_page_num;
+    let mut path = vec![];
+
+    for _ in 0..TABLE_MAX_PAGES {
+        let node = table.pager.page_ref(page_num)?;
+        match node_type_of(node)? {
+            NodeType::Internal => {
+                let child_num = internal_node_find_child_num(node, key);
+                path.push((page_num, child_num));
+                page_num = internal_node_child(node, child_num) as usize;
+            }
+            NodeType::Leaf => {
+                let num_cells = leaf_node_num_cells(node) as usize;
+                let (cell_num, exact_match) = leaf_node_bsearch(node, key);
+
+                let cursor = Cursor {
+                    version: table.structure_version,
+                    table,
+                    page_num,
+                    cell_num,
+                    end_of_table: cell_num >= num_cells,
+                    path,
+                };
+                return Ok((cursor, exact_match));
+            }
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        "tree is deeper than the page limit",
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edge {
+    Left,
+    Right,
+}
+
+// follows the first (Edge::Left) or last (Edge::Right) child from page_num down to a
+// leaf, pushing each internal node passed onto `path`, and returns the leaf
+fn descend_to_edge(
+    table: &mut Table,
+    mut page_num: usize,
+    edge: Edge,
+    path: &mut Vec<(usize, u32)>,
+) -> Result<usize> {
+    for _ in 0..TABLE_MAX_PAGES {
+        let node = table.pager.page_ref(page_num)?;
+        if node_type_of(node)? == NodeType::Leaf {
+            return Ok(page_num);
+        }
+        let child_num = match edge {
+            Edge::Left => 0,
+            Edge::Right => internal_node_num_keys(node),
+        };
+        path.push((page_num, child_num));
+        page_num = internal_node_child(node, child_num) as usize;
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        "tree is deeper than the page limit",
+    ))
+}
+
+pub fn db_open(filename: &str) -> Result<Table> {
+    db_open_with_pager(Pager::new(filename)?)
+}
+
+pub fn db_open_named(filename: &str, name: &str) -> Result<Table> {
+    let mut table = db_open(filename)?;
+    table.set_name(name);
+    Ok(table)
+}
+
+pub fn db_open_with_pager(pager: Pager) -> Result<Table> {
+    db_open_with_options(pager, DbOptions::default())
+}
+
+pub fn db_open_with_options(mut pager: Pager, options: DbOptions) -> Result<Table> {
+    if pager.num_pages == 0 {
+        let page = pager.get_page(0)?;
+        initialize_leaf_node(page);
+        set_format_version(page);
+    }
+
+    let mut table = Table {
+        root_page_num: ROOT_PAGE_NUM,
+        pager,
+        truncate_strings: false,
+        normalize_strings: false,
+        debug: false,
+        row_cache: RowCache::new(options.row_cache_size),
+        last_insert_id: None,
+        last_insert_stats: None,
+        write_barrier: false,
+        name: None,
+        structure_version: 0,
+        cursor_advances: 0,
+        idempotency_keys: HashMap::new(),
+        auto_compact_ratio: options.auto_compact_ratio,
+        mutations_since_compact_check: 0,
+    };
+    table.restore_from_wal()?;
+    table.check_root_header()?;
+    table.check_format_version()?;
+
+    table.pager.file_access = options.file_access;
+    table.pager.release_file();
+
+    Ok(table)
+}
+
+pub fn db_close(table: &mut Table) -> Result<()> {
+    // nothing can have changed, and the shared handle is not ours to sync
+    if table.pager.read_only {
+        table.pager.release_file();
+        return Ok(());
+    }
+    table.auto_compact()?;
+    table.flush_all()?;
+
+    if table.pager.fsync_on_close {
+        table.pager.file()?.sync_all()?;
+    }
+    table.pager.release_file();
+
+    Ok(())
+}
+
+// refuses to truncate: a row built outside the parser can still carry oversized strings.
+// inlined so callers in other crates (benches, embedders) see the fixed ROW_SIZE offsets
+#[inline]
+pub fn serialize_row(row: &Row, destination: &mut [u8]) -> Result<()> {
+    if row.username.len() > field_capacity(USERNAME_SIZE)
+        || row.email.len() > field_capacity(EMAIL_SIZE)
+    {
+        return Err(Error::new(ErrorKind::InvalidInput, "string is too long"));
+    }
+
+    serialize_row_truncated(row, destination);
+    Ok(())
+}
+
+// opt-in variant that silently cuts oversized strings to their column size
+pub fn serialize_row_truncated(row: &Row, destination: &mut [u8]) {
+    // a short slice means the layout constants have drifted apart
+    debug_assert!(
+        destination.len() >= ROW_SIZE,
+        "destination is {} bytes, a row needs {}",
+        destination.len(),
+        ROW_SIZE
+    );
+    destination[ID_OFFSET..ID_OFFSET + ID_SIZE].copy_from_slice(&row.id.to_le_bytes());
+    write_field(destination, USERNAME_OFFSET, USERNAME_SIZE, &row.username);
+    write_field(destination, EMAIL_OFFSET, EMAIL_SIZE, &row.email);
+}
+
+// the most bytes a field of `size` holds: past u8::MAX its length byte can't count them
+fn field_capacity(size: usize) -> usize {
+    size.min(u8::MAX as usize)
+}
+
+fn write_field(destination: &mut [u8], offset: usize, size: usize, value: &str) {
+    let data = &value.as_bytes()[..value.len().min(field_capacity(size))];
+    destination[offset] = data.len() as u8;
+    let field = &mut destination[offset + FIELD_LEN_SIZE..offset + FIELD_LEN_SIZE + size];
+    field[..data.len()].copy_from_slice(data);
+    field[data.len()..].fill(0);
+}
+
+// the stored bytes of a text field, or None if its length byte is out of range
+fn read_field(source: &[u8], offset: usize, size: usize) -> Option<&[u8]> {
+    let len = source[offset] as usize;
+    (len <= size).then(|| &source[offset + FIELD_LEN_SIZE..offset + FIELD_LEN_SIZE + len])
+}
+
+// runs once per row in every scan; inlining lets the fixed field offsets fold into
+// the caller's slice arithmetic
+#[inline]
+pub fn deserialize_row(source: &[u8]) -> Row {
+    let id = u32::from_le_bytes([source[0], source[1], source[2], source[3]]);
+    // a corrupt length reads as the whole field rather than failing the scan
+    let text = |offset, size| {
+        let field = read_field(source, offset, size)
+            .unwrap_or(&source[offset + FIELD_LEN_SIZE..offset + FIELD_LEN_SIZE + size]);
+        String::from_utf8_lossy(field).to_string()
+    };
+    let username = text(USERNAME_OFFSET, USERNAME_SIZE);
+    let email = text(EMAIL_OFFSET, EMAIL_SIZE);
+
+    Row {
+        id,
+        username,
+        email,
+    }
+}
+
+// one step of splitmix64: small and seedable, which is all sample needs
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// deserialize_row is lossy, so check the raw text fields before trusting an external chunk
+fn is_valid_serialized_row(source: &[u8]) -> bool {
+    let field_is_text = |offset, size| {
+        read_field(source, offset, size).is_some_and(|field| std::str::from_utf8(field).is_ok())
+    };
+
+    source.len() == ROW_SIZE
+        && field_is_text(USERNAME_OFFSET, USERNAME_SIZE)
+        && field_is_text(EMAIL_OFFSET, EMAIL_SIZE)
+}
+
+// --- leaf node accessors ---
+
+fn leaf_node_num_cells(node: &[u8; PAGE_SIZE]) -> u32 {
+    u32::from_le_bytes(
+        node[LEAF_NODE_NUM_CELLS_OFFSET..LEAF_NODE_NUM_CELLS_OFFSET + LEAF_NODE_NUM_CELLS_SIZE]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+fn set_leaf_node_num_cells(node: &mut [u8; PAGE_SIZE], num_cells: u32) {
+    node[LEAF_NODE_NUM_CELLS_OFFSET..LEAF_NODE_NUM_CELLS_OFFSET + LEAF_NODE_NUM_CELLS_SIZE]
+        .copy_from_slice(&num_cells.to_le_bytes());
+}
+
+// pure arithmetic on constants, called for every cell touched
+#[inline]
+fn leaf_node_cell_offset(cell_num: u32) -> usize {
+    LEAF_NODE_HEADER_SIZE + (cell_num as usize * LEAF_NODE_CELL_SIZE)
+}
+
+fn leaf_node_cell(node: &mut [u8; PAGE_SIZE], cell_num: u32) -> &mut [u8] {
+    let offset = leaf_node_cell_offset(cell_num);
+    &mut node[offset..offset + LEAF_NODE_CELL_SIZE]
+}
+
+// the inner loop of table_find and key_iter
+#[inline]
+fn leaf_node_key(node: &[u8; PAGE_SIZE], cell_num: u32) -> u32 {
+    let offset = leaf_node_cell_offset(cell_num);
+    let cell = &node[offset..offset + LEAF_NODE_CELL_SIZE];
+
+    u32::from_le_bytes(cell[..LEAF_NODE_KEY_SIZE].try_into().unwrap())
+}
+
+// (insertion point, exact match): the first cell whose key is >= `key`, or num_cells
+// when every key is smaller. relies on the cells being sorted by key
+fn leaf_node_bsearch(node: &[u8; PAGE_SIZE], key: u32) -> (usize, bool) {
+    let mut lo = 0;
+    let mut hi = leaf_node_num_cells(node);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if leaf_node_key(node, mid) < key {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+
+    let found = lo < leaf_node_num_cells(node) && leaf_node_key(node, lo) == key;
+    (lo as usize, found)
+}
+
+fn leaf_node_value(node: &mut [u8; PAGE_SIZE], cell_num: u32) -> &mut [u8] {
+    let offset = leaf_node_cell_offset(cell_num) + LEAF_NODE_VALUE_OFFSET;
+    let value = &mut node[offset..offset + LEAF_NODE_VALUE_SIZE];
+    debug_assert_eq!(
+        value.len(),
+        ROW_SIZE,
+        "leaf value slots must hold exactly one row"
+    );
+    value
+}
+
+// --- internal node accessors ---
+
+fn internal_node_num_keys(node: &[u8; PAGE_SIZE]) -> u32 {
+    u32::from_le_bytes(
+        node[INTERNAL_NODE_NUM_KEYS_OFFSET
+            ..INTERNAL_NODE_NUM_KEYS_OFFSET + INTERNAL_NODE_NUM_KEYS_SIZE]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+fn internal_node_right_child(node: &[u8; PAGE_SIZE]) -> u32 {
+    u32::from_le_bytes(
+        node[INTERNAL_NODE_RIGHT_CHILD_OFFSET
+            ..INTERNAL_NODE_RIGHT_CHILD_OFFSET + INTERNAL_NODE_RIGHT_CHILD_SIZE]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+fn internal_node_cell_offset(cell_num: u32) -> usize {
+    INTERNAL_NODE_HEADER_SIZE + (cell_num as usize * INTERNAL_NODE_CELL_SIZE)
+}
+
+// child `num_keys` is the right child, everything before it lives in the cells
+fn internal_node_child(node: &[u8; PAGE_SIZE], child_num: u32) -> u32 {
+    let num_keys = internal_node_num_keys(node);
+    if child_num == num_keys {
+        return internal_node_right_child(node);
+    }
+
+    let offset = internal_node_cell_offset(child_num);
+    u32::from_le_bytes(
+        node[offset..offset + INTERNAL_NODE_CHILD_SIZE]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+fn set_internal_node_child(node: &mut [u8; PAGE_SIZE], child_num: u32, page_num: u32) {
+    let offset = if child_num == internal_node_num_keys(node) {
+        INTERNAL_NODE_RIGHT_CHILD_OFFSET
+    } else {
+        internal_node_cell_offset(child_num)
+    };
+    node[offset..offset + INTERNAL_NODE_CHILD_SIZE].copy_from_slice(&page_num.to_le_bytes());
+}
+
+fn internal_node_subtree_size_offset(node: &[u8; PAGE_SIZE], child_num: u32) -> usize {
+    if child_num == internal_node_num_keys(node) {
+        INTERNAL_NODE_RIGHT_SUBTREE_SIZE_OFFSET
+    } else {
+        internal_node_cell_offset(child_num) + INTERNAL_NODE_SUBTREE_SIZE_OFFSET
+    }
+}
+
+// rows in the subtree under child `child_num`
+fn internal_node_subtree_size(node: &[u8; PAGE_SIZE], child_num: u32) -> u32 {
+    let offset = internal_node_subtree_size_offset(node, child_num);
+    u32::from_le_bytes(
+        node[offset..offset + INTERNAL_NODE_SUBTREE_SIZE_SIZE]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+fn set_internal_node_subtree_size(node: &mut [u8; PAGE_SIZE], child_num: u32, size: u32) {
+    let offset = internal_node_subtree_size_offset(node, child_num);
+    node[offset..offset + INTERNAL_NODE_SUBTREE_SIZE_SIZE].copy_from_slice(&size.to_le_bytes());
+}
+
+fn internal_node_key(node: &[u8; PAGE_SIZE], key_num: u32) -> u32 {
+    let offset = internal_node_cell_offset(key_num) + INTERNAL_NODE_CHILD_SIZE;
+    u32::from_le_bytes(
+        node[offset..offset + INTERNAL_NODE_KEY_SIZE]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+// index of the child that would hold `key`, num_keys for the right child. each key is
+// the max key of the child to its left
+fn internal_node_find_child_num(node: &[u8; PAGE_SIZE], key: u32) -> u32 {
+    let num_keys = internal_node_num_keys(node);
+    (0..num_keys)
+        .find(|&i| key <= internal_node_key(node, i))
+        .unwrap_or(num_keys)
+}
+
+fn node_type_of(node: &[u8; PAGE_SIZE]) -> Result<NodeType> {
+    match node[NODE_TYPE_OFFSET] {
+        0 => Ok(NodeType::Leaf),
+        1 => Ok(NodeType::Internal),
+        byte => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid node type byte {}", byte),
+        )),
+    }
+}
+
+fn set_format_version(header: &mut [u8; PAGE_SIZE]) {
+    header[FORMAT_VERSION_OFFSET..FORMAT_VERSION_OFFSET + FORMAT_VERSION_SIZE]
+        .copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+}
+
+fn node_is_root(node: &[u8; PAGE_SIZE]) -> bool {
+    node[IS_ROOT_OFFSET] != 0
+}
+
+fn initialize_leaf_node(node: &mut [u8; PAGE_SIZE]) {
+    node[NODE_TYPE_OFFSET] = NodeType::Leaf as u8;
+    node[IS_ROOT_OFFSET] = 1;
+    set_leaf_node_num_cells(node, 0);
+}
+
+// an empty, non-root internal node: no keys, no parent and a right child of page 0,
+// which callers overwrite before anything reads the node
+pub fn initialize_internal_node(node: &mut [u8; PAGE_SIZE]) {
+    node[NODE_TYPE_OFFSET] = NodeType::Internal as u8;
+    node[IS_ROOT_OFFSET] = 0;
+    node[PARENT_POINTER_OFFSET..PARENT_POINTER_OFFSET + PARENT_POINTER_SIZE].fill(0);
+    node[INTERNAL_NODE_NUM_KEYS_OFFSET
+        ..INTERNAL_NODE_NUM_KEYS_OFFSET + INTERNAL_NODE_NUM_KEYS_SIZE]
+        .fill(0);
+    node[INTERNAL_NODE_RIGHT_CHILD_OFFSET
+        ..INTERNAL_NODE_RIGHT_CHILD_OFFSET + INTERNAL_NODE_RIGHT_CHILD_SIZE]
+        .fill(0);
+    node[INTERNAL_NODE_RIGHT_SUBTREE_SIZE_OFFSET
+        ..INTERNAL_NODE_RIGHT_SUBTREE_SIZE_OFFSET + INTERNAL_NODE_SUBTREE_SIZE_SIZE]
+        .fill(0);
+}
+
+// insert a cell at the cursor: shift later cells right by one, write key and serialized
+// value, bump num_cells. the value is serialized first so a rejected row leaves the leaf as is.
+pub fn leaf_node_insert(cursor: &mut Cursor, key: u32, value: &Row) -> Result<()> {
+    let normalized;
+    let value = if cursor.table.normalize_strings {
+        normalized = value.normalized();
+        &normalized
+    } else {
+        value
+    };
+    let mut serialized = [0u8; ROW_SIZE];
+    if cursor.table.truncate_strings {
+        serialize_row_truncated(value, &mut serialized);
+    } else {
+        serialize_row(value, &mut serialized)?;
+    }
+
+    let page = cursor.table.pager.get_page(cursor.page_num)?;
+    let num_cells = leaf_node_num_cells(page);
+    let cell_num = cursor.cell_num as u32;
+
+    if cell_num < num_cells {
+        page.copy_within(
+            leaf_node_cell_offset(cell_num)..leaf_node_cell_offset(num_cells),
+            leaf_node_cell_offset(cell_num + 1),
+        );
+    }
+
+    let cell = leaf_node_cell(page, cell_num);
+    cell[..LEAF_NODE_KEY_SIZE].copy_from_slice(&key.to_le_bytes());
+    cell[LEAF_NODE_VALUE_OFFSET..].copy_from_slice(&serialized);
+
+    set_leaf_node_num_cells(page, num_cells + 1);
+    cursor.table.adjust_subtree_sizes(cursor.page_num, key, 1)?;
+
+    Ok(())
+}
+
+// --- debug meta commands ---
+//
+// repl-only output, compiled out along with main.rs when the `cli` feature is off
+
+#[cfg(feature = "cli")]
+pub fn print_constants() {
+    println!("ROW_SIZE: {}", ROW_SIZE);
+    println!("LEAF_NODE_HEADER_SIZE: {}", LEAF_NODE_HEADER_SIZE);
+    println!("LEAF_NODE_CELL_SIZE: {}", LEAF_NODE_CELL_SIZE);
+    println!("PAGE_SIZE: {}", PAGE_SIZE);
+    println!("LEAF_NODE_SPACE_FOR_CELLS: {}", LEAF_NODE_SPACE_FOR_CELLS);
+    println!("LEAF_NODE_MAX_CELLS: {}", LEAF_NODE_MAX_CELLS);
+}
+
+#[cfg(feature = "cli")]
+fn print_table_header(table: &Table) {
+    if let Some(name) = table.name() {
+        println!("=== table: {} ===", name);
+    }
+}
+
+#[cfg(feature = "cli")]
+pub fn print_btree(table: &mut Table) -> Result<()> {
+    print_table_header(table);
+    table.walk_depth_first(|_, depth, node_type, keys| {
+        let indent = "  ".repeat(depth);
+        match node_type {
+            NodeType::Leaf => {
+                println!("{}*---*", indent);
+                for (i, key) in keys.iter().enumerate() {
+                    println!("{}  - {}: {}", indent, i, key);
+                }
+                println!("{}*---*", indent);
+            }
+            NodeType::Internal => {
+                println!("{}- internal (size {})", indent, keys.len());
+                for key in keys {
+                    println!("{}  - key {}", indent, key);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(feature = "cli")]
+pub fn print_stats(table: &mut Table) -> Result<()> {
+    let (leaf_pages, internal_pages) = table.count_pages_by_type()?;
+    print_table_header(table);
+    println!("leaf_pages: {}", leaf_pages);
+    println!("internal_pages: {}", internal_pages);
+    Ok(())
+}
+
+// `(id, username, email)` per row; aligned pads every column but the last to its widest
+// value in this result set, ids right-aligned
+#[cfg(feature = "cli")]
+pub fn format_rows(rows: &[Row], align: bool) -> Vec<String> {
+    if !align {
+        return rows
+            .iter()
+            .map(|row| format!("({}, {}, {})", row.id, row.username, row.email))
+            .collect();
+    }
+
+    let id_width = rows
+        .iter()
+        .map(|row| row.id.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let username_width = rows.iter().map(|row| row.username.len()).max().unwrap_or(0);
+    rows.iter()
+        .map(|row| {
+            // the comma stays attached to the username, padding goes after it
+            let username = format!("{},", row.username);
+            format!(
+                "({:>id_width$}, {:<username_width$} {})",
+                row.id,
+                username,
+                row.email,
+                username_width = username_width + 1,
+            )
+        })
+        .collect()
+}
+
+// one insert statement per row that replays it; values are quoted so a `--` inside
+// them isn't taken for a comment
+#[cfg(feature = "cli")]
+pub fn format_rows_as_inserts(rows: &[Row]) -> Vec<String> {
+    rows.iter()
+        .map(|row| format!("insert {} '{}' '{}'", row.id, row.username, row.email))
+        .collect()
+}
+
+#[cfg(feature = "cli")]
+pub fn print_size(table: &mut Table) -> Result<()> {
+    println!("row_size_bytes: {}", table.row_size_bytes());
+    println!("estimated_file_size: {}", table.estimated_file_size());
+    println!("actual_file_size: {}", table.actual_file_size()?);
+    Ok(())
+}
+
+#[cfg(feature = "cli")]
+pub fn print_free_list(table: &mut Table) -> Result<()> {
+    let free = table.explain_free_list()?;
+    if free.is_empty() {
+        println!("free pages: none");
+    } else {
+        let pages: Vec<String> = free.iter().map(|page_num| page_num.to_string()).collect();
+        println!("free pages: {}", pages.join(", "));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "cli")]
+pub fn print_integrity_check(table: &mut Table) -> Result<()> {
+    let issues = table.integrity_check()?;
+    if issues.is_empty() {
+        println!("ok.");
+    }
+    for issue in issues {
+        println!("{:?}", issue);
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum StatementType {
+    Insert,
+    Update,
+    Select,
+    Sample { n: usize, seed: Option<u64> },
+    SelectPrefix(String),
+    SelectDescending,
+    SelectOffset(usize),
+    // sorted, without duplicates
+    SelectIn(Vec<u32>),
+    SelectGreaterThan(u32),
+    SelectLessThan(u32),
+    LastInsertId,
+    // runs the inner statement and reports how it went instead of its rows
+    ExplainAnalyze(Box<Statement>),
+}
+
+impl StatementType {
+    // how execute_statement gets at the rows, for explain analyze
+    pub fn plan(&self) -> String {
+        match self {
+            StatementType::Insert => "descend to the leaf for id, insert a cell".to_string(),
+            StatementType::Update => "descend to the leaf for id, rewrite its cell".to_string(),
+            StatementType::Select => "full scan in key order".to_string(),
+            StatementType::Sample { n, .. } => format!("sample {} rows by point lookups", n),
+            StatementType::SelectPrefix(prefix) => {
+                format!("full scan, filter on prefix '{}'", prefix)
+            }
+            StatementType::SelectDescending => "full scan in reverse key order".to_string(),
+            StatementType::SelectOffset(offset) => {
+                format!("full scan, skipping the first {} cells", offset)
+            }
+            StatementType::SelectIn(ids) => format!("{} point lookups on id", ids.len()),
+            StatementType::SelectGreaterThan(bound) => {
+                format!("seek to id {}, scan to the end", bound.saturating_add(1))
+            }
+            StatementType::SelectLessThan(bound) => {
+                format!("scan from the start, stop at id {}", bound)
+            }
+            StatementType::LastInsertId => "no table access".to_string(),
+            StatementType::ExplainAnalyze(inner) => inner.statement_type.plan(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Statement {
+    pub statement_type: StatementType,
+    pub row_to_insert: Option<Row>,
+    pub on_conflict: Option<ConflictAction>,
+}
+
+impl Statement {
+    // rough page reads for running this statement against `table`, for choosing between
+    // plans: a point lookup costs the tree's height, a full scan its leaf count, and a
+    // range the leaves it spans. with no stored statistics, keys are assumed to be
+    // spread evenly between the first and the last
+    pub fn cost_estimate(&self, table: &mut Table) -> Result<u64> {
+        let height = table.height()? as u64;
+        let leaves = table.count_pages_by_type()?.0 as u64;
+        let (first, last) = (table.first()?, table.last()?);
+        // leaves holding keys in lo..=hi
+        let spanned = |lo: u32, hi: u32| match (first, last) {
+            (Some(first), Some(last)) if lo.max(first) <= hi.min(last) => {
+                let covered = (hi.min(last) - lo.max(first)) as u64 + 1;
+                let span = (last - first) as u64 + 1;
+                (leaves * covered).div_ceil(span)
+            }
+            _ => 0,
+        };
+
+        Ok(match &self.statement_type {
+            StatementType::Insert | StatementType::Update => height,
+            StatementType::Select
+            | StatementType::SelectPrefix(_)
+            | StatementType::SelectDescending
+            | StatementType::SelectOffset(_) => leaves,
+            StatementType::Sample { n, .. } => leaves + *n as u64 * height,
+            StatementType::SelectIn(ids) => ids.len() as u64 * height,
+            // a range costs its descent to the leaf it starts on, the leftmost one for a
+            // less-than, plus the leaves it spans
+            StatementType::SelectGreaterThan(bound) => {
+                let range = match bound.checked_add(1) {
+                    Some(lo) => spanned(lo, u32::MAX),
+                    None => 0,
+                };
+                height - 1 + range.max(1)
+            }
+            StatementType::SelectLessThan(bound) => {
+                let range = match bound.checked_sub(1) {
+                    Some(hi) => spanned(0, hi),
+                    None => 0,
+                };
+                height - 1 + range.max(1)
+            }
+            StatementType::LastInsertId => 0,
+            StatementType::ExplainAnalyze(inner) => inner.cost_estimate(table)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConflictAction {
+    UpdateEmail(String),
+}
+
+pub enum PrepareResult {
+    Success(Statement),
+    UnrecognizedStatement,
+    SyntaxError,
+    StringTooLong,
+    NegativeId,
+    UnexpectedToken(String),
+}
+
+// the non-success outcomes of prepare_statement, as an error type for `str::parse`
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrepareError {
+    UnrecognizedStatement,
+    SyntaxError,
+    StringTooLong,
+    NegativeId,
+    UnexpectedToken(String),
+}
+
+impl std::fmt::Display for PrepareError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrepareError::UnrecognizedStatement => write!(f, "unrecognized statement"),
+            PrepareError::SyntaxError => write!(f, "syntax error"),
+            PrepareError::StringTooLong => write!(f, "string is too long"),
+            PrepareError::NegativeId => write!(f, "id must be positive"),
+            PrepareError::UnexpectedToken(token) => write!(f, "unexpected token '{}'", token),
+        }
+    }
+}
+
+impl std::error::Error for PrepareError {}
+
+impl From<&str> for PrepareResult {
+    fn from(input: &str) -> Self {
+        prepare_statement(input)
+    }
+}
+
+impl std::str::FromStr for Statement {
+    type Err = PrepareError;
+
+    fn from_str(input: &str) -> std::result::Result<Self, Self::Err> {
+        match prepare_statement(input) {
+            PrepareResult::Success(statement) => Ok(statement),
+            PrepareResult::UnrecognizedStatement => Err(PrepareError::UnrecognizedStatement),
+            PrepareResult::SyntaxError => Err(PrepareError::SyntaxError),
+            PrepareResult::StringTooLong => Err(PrepareError::StringTooLong),
+            PrepareResult::NegativeId => Err(PrepareError::NegativeId),
+            PrepareResult::UnexpectedToken(token) => Err(PrepareError::UnexpectedToken(token)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ParseMode {
+    // anything past the grammar is ignored, e.g. `select garbage` runs a full scan
+    #[default]
+    Lenient,
+    // the first token the grammar doesn't account for is a syntax error
+    Strict,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Integer,
+    Text(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnMeta {
+    pub name: &'static str,
+    pub column_type: ColumnType,
+    // stored when an insert leaves the column out
+    pub default: Option<&'static str>,
+}
+
+// the fixed (id, username, email) schema, in storage order
+pub fn row_columns() -> Vec<ColumnMeta> {
+    vec![
+        ColumnMeta {
+            name: "id",
+            column_type: ColumnType::Integer,
+            default: None,
+        },
+        ColumnMeta {
+            name: "username",
+            column_type: ColumnType::Text(COLUMN_USERNAME_SIZE),
+            default: None,
+        },
+        ColumnMeta {
+            name: "email",
+            column_type: ColumnType::Text(COLUMN_EMAIL_SIZE),
+            default: Some(DEFAULT_EMAIL),
+        },
+    ]
+}
+
+pub const DEFAULT_EMAIL: &str = "unknown@example.com";
+
+pub fn column_default(name: &str) -> Option<&'static str> {
+    row_columns()
+        .into_iter()
+        .find(|column| column.name == name)
+        .and_then(|column| column.default)
+}
+
+#[derive(Debug)]
+pub struct ResultSet {
+    pub columns: Vec<ColumnMeta>,
+    pub rows: Vec<Row>,
+}
+
+// what explain analyze measured while running a statement
+#[derive(Debug)]
+pub struct QueryStats {
+    pub plan: String,
+    pub rows: usize,
+    pub io: PageIo,
+    pub elapsed: Duration,
+}
+
+// write cost of one insert statement, for spotting the inserts that overflow a leaf.
+// leaves don't split, so such an insert is refused: needed_split is set and nothing
+// is dirtied
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InsertStats {
+    pub needed_split: bool,
+    // pages that weren't dirty before the insert and are now, i.e. what it added to
+    // the next flush
+    pub pages_dirtied: usize,
+}
+
+#[derive(Debug)]
+pub enum ExecuteResult {
+    Success,
+    Rows(ResultSet),
+    DuplicateKey,
+    // the insert's leaf has no room, and leaves don't split yet
+    LeafFull,
+    NotFound,
+    LastInsertId(Option<u32>),
+    Analyze(QueryStats),
+}
+
+// how the repl prints select results
+#[cfg(feature = "cli")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputMode {
+    // `(id, username, email)` tuples
+    #[default]
+    Tuples,
+    // re-runnable insert statements
+    Insert,
+}
+
+#[cfg(feature = "cli")]
+pub enum MetaCommandResult {
+    Exit,
+    PrintConstants,
+    PrintBtree,
+    PrintStats,
+    CheckIntegrity,
+    PrintSize,
+    PrintFreeList,
+    ImportFixed(String),
+    Seed(usize),
+    Dump(usize),
+    // the next n rows after the position an earlier `.page` saved
+    Page(usize),
+    PageReset,
+    DebugOn,
+    DebugOff,
+    TimerOn,
+    TimerOff,
+    AlignOn,
+    AlignOff,
+    SetMode(OutputMode),
+    UnrecognizedCommand,
+}
+
+#[cfg(feature = "cli")]
+pub fn do_meta_command(input: &str) -> MetaCommandResult {
+    if let Some(path) = input.strip_prefix(".import-fixed ") {
+        return MetaCommandResult::ImportFixed(path.trim().to_string());
+    }
+    if let Some(page_num) = input.strip_prefix(".dump ") {
+        return match page_num.trim().parse() {
+            Ok(page_num) => MetaCommandResult::Dump(page_num),
+            Err(_) => MetaCommandResult::UnrecognizedCommand,
+        };
+    }
+    if let Some(n) = input.strip_prefix(".page ") {
+        return match n.trim() {
+            "reset" => MetaCommandResult::PageReset,
+            n => match n.parse() {
+                Ok(n) => MetaCommandResult::Page(n),
+                Err(_) => MetaCommandResult::UnrecognizedCommand,
+            },
+        };
+    }
+    if let Some(n) = input.strip_prefix(".seed ") {
+        return match n.trim().parse() {
+            Ok(n) => MetaCommandResult::Seed(n),
+            Err(_) => MetaCommandResult::UnrecognizedCommand,
+        };
+    }
+
+    match input {
+        ".exit" => MetaCommandResult::Exit,
+        ".constants" => MetaCommandResult::PrintConstants,
+        ".btree" => MetaCommandResult::PrintBtree,
+        ".stats" => MetaCommandResult::PrintStats,
+        ".check" => MetaCommandResult::CheckIntegrity,
+        ".size" => MetaCommandResult::PrintSize,
+        ".pragma freelist" => MetaCommandResult::PrintFreeList,
+        ".debug on" => MetaCommandResult::DebugOn,
+        ".debug off" => MetaCommandResult::DebugOff,
+        ".timer on" => MetaCommandResult::TimerOn,
+        ".timer off" => MetaCommandResult::TimerOff,
+        ".align on" => MetaCommandResult::AlignOn,
+        ".align off" => MetaCommandResult::AlignOff,
+        ".mode tuples" => MetaCommandResult::SetMode(OutputMode::Tuples),
+        ".mode insert" => MetaCommandResult::SetMode(OutputMode::Insert),
+        _ => MetaCommandResult::UnrecognizedCommand,
+    }
+}
+
+pub fn prepare_statement(input: &str) -> PrepareResult {
+    prepare_statement_with_mode(input, ParseMode::Lenient)
+}
+
+pub fn prepare_statement_with_mode(input: &str, mode: ParseMode) -> PrepareResult {
+    let input = strip_comment(input);
+
+    if mode == ParseMode::Strict
+        && let Some(token) = unexpected_token(input)
+    {
+        return PrepareResult::UnexpectedToken(token.to_string());
+    }
+
+    if let Some(inner) = input.strip_prefix("explain analyze ") {
+        match prepare_statement_with_mode(inner, mode) {
+            PrepareResult::Success(statement) => PrepareResult::Success(Statement {
+                statement_type: StatementType::ExplainAnalyze(Box::new(statement)),
+                row_to_insert: None,
+                on_conflict: None,
+            }),
+            error => error,
+        }
+    } else if input.split_whitespace().eq(["select", "last_insert_id()"]) {
+        PrepareResult::Success(Statement {
+            statement_type: StatementType::LastInsertId,
+            row_to_insert: None,
+            on_conflict: None,
+        })
+    } else if input
+        .split_whitespace()
+        .eq(["select", "order", "by", "id", "desc"])
+    {
+        PrepareResult::Success(Statement {
+            statement_type: StatementType::SelectDescending,
+            row_to_insert: None,
+            on_conflict: None,
+        })
+    } else if input.starts_with("select") && input.split_whitespace().nth(1) == Some("offset") {
+        parse_select_offset(input)
+    } else if input.starts_with("select") && input.split_whitespace().nth(1) == Some("sample") {
+        parse_sample(input)
+    } else if input.starts_with("select") && input.split_whitespace().nth(1) == Some("where") {
+        parse_select_where(input)
+    } else if input.starts_with("select") {
+        PrepareResult::Success(Statement {
+            statement_type: StatementType::Select,
+            row_to_insert: None,
+            on_conflict: None,
+        })
+    } else if input.starts_with("insert") || input.starts_with("update") {
+        parse_insert_or_update(input)
+    } else {
+        PrepareResult::UnrecognizedStatement
+    }
+}
+
+// the statement parsers read tokens through this rather than indexing a Vec, so every
+// lookup is an Option and a short or malformed statement falls through to an error
+#[derive(Clone)]
+struct Tokens<'a>(std::str::SplitWhitespace<'a>);
+
+impl<'a> Tokens<'a> {
+    fn new(input: &'a str) -> Self {
+        Tokens(input.split_whitespace())
+    }
+
+    // consumes `keywords` in order, stopping at the first token that differs
+    fn eat(&mut self, keywords: &[&str]) -> bool {
+        keywords.iter().all(|keyword| self.next() == Some(keyword))
+    }
+
+    fn is_done(&self) -> bool {
+        self.clone().next().is_none()
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.0.next()
+    }
+}
+
+// select sample <n> [seed <seed>]
+fn parse_sample(input: &str) -> PrepareResult {
+    let mut tokens = Tokens::new(input);
+    if !tokens.eat(&["select", "sample"]) {
+        return PrepareResult::UnrecognizedStatement;
+    }
+    let Some(n) = tokens.next() else {
+        return PrepareResult::UnrecognizedStatement;
+    };
+    let seed = match (tokens.next(), tokens.next(), tokens.is_done()) {
+        (None, _, _) => None,
+        (Some("seed"), Some(seed), true) => match seed.parse::<u64>() {
+            Ok(seed) => Some(seed),
+            Err(_) => return PrepareResult::SyntaxError,
+        },
+        _ => return PrepareResult::UnrecognizedStatement,
+    };
+    let n = match n.parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => return PrepareResult::SyntaxError,
+    };
+
+    PrepareResult::Success(Statement {
+        statement_type: StatementType::Sample { n, seed },
+        row_to_insert: None,
+        on_conflict: None,
+    })
+}
+
+// the `(1, 3, 5)` after `id in`, with its whitespace already dropped. duplicates are
+// removed and the ids sorted so execution can probe them in key order
+fn parse_id_list(list: String) -> PrepareResult {
+    let Some(list) = list
+        .strip_prefix('(')
+        .and_then(|list| list.strip_suffix(')'))
+    else {
+        return PrepareResult::SyntaxError;
+    };
+    let Ok(mut ids) = list
+        .split(',')
+        .map(str::parse::<u32>)
+        .collect::<std::result::Result<Vec<_>, _>>()
+    else {
+        return PrepareResult::SyntaxError;
+    };
+    ids.sort_unstable();
+    ids.dedup();
+
+    PrepareResult::Success(Statement {
+        statement_type: StatementType::SelectIn(ids),
+        row_to_insert: None,
+        on_conflict: None,
+    })
+}
+
+// select offset <n>
+fn parse_select_offset(input: &str) -> PrepareResult {
+    let mut tokens = Tokens::new(input);
+    if !tokens.eat(&["select", "offset"]) {
+        return PrepareResult::SyntaxError;
+    }
+    let (Some(offset), true) = (tokens.next(), tokens.is_done()) else {
+        return PrepareResult::SyntaxError;
+    };
+    let Ok(offset) = offset.parse::<usize>() else {
+        return PrepareResult::SyntaxError;
+    };
+
+    PrepareResult::Success(Statement {
+        statement_type: StatementType::SelectOffset(offset),
+        row_to_insert: None,
+        on_conflict: None,
+    })
+}
+
+// select where prefix = <s>, matched against username or email
+// select where id in (<id>, <id>, ...)
+// select where id > <n>, select where id < <n>
+fn parse_select_where(input: &str) -> PrepareResult {
+    let mut tokens = Tokens::new(input);
+    if tokens.clone().eat(&["select", "where", "id", "in"]) {
+        return parse_id_list(tokens.skip(4).collect::<Vec<_>>().concat());
+    }
+    if tokens.clone().eat(&["select", "where", "id"]) {
+        let mut tokens = tokens.skip(3);
+        let (Some(op), Some(Ok(bound)), true) = (
+            tokens.next(),
+            tokens.next().map(str::parse::<u32>),
+            tokens.next().is_none(),
+        ) else {
+            return PrepareResult::SyntaxError;
+        };
+        let statement_type = match op {
+            ">" => StatementType::SelectGreaterThan(bound),
+            "<" => StatementType::SelectLessThan(bound),
+            _ => return PrepareResult::SyntaxError,
+        };
+        return PrepareResult::Success(Statement {
+            statement_type,
+            row_to_insert: None,
+            on_conflict: None,
+        });
+    }
+    if !tokens.eat(&["select", "where", "prefix", "="]) {
+        return PrepareResult::SyntaxError;
+    }
+    let (Some(prefix), true) = (tokens.next(), tokens.is_done()) else {
+        return PrepareResult::SyntaxError;
+    };
+
+    PrepareResult::Success(Statement {
+        statement_type: StatementType::SelectPrefix(prefix.trim_matches('\'').to_string()),
+        row_to_insert: None,
+        on_conflict: None,
+    })
+}
+
+// insert <id> <username> [<email>] [on conflict do update set email = <new_email>]
+// update <id> <username> <email>
+fn parse_insert_or_update(input: &str) -> PrepareResult {
+    let mut tokens = Tokens::new(input);
+    let keyword = tokens.next();
+    // id, username and email; parse_row_fields reads them
+    let row_fields = tokens.by_ref().take(3).count();
+
+    let (statement_type, on_conflict) = match (keyword, row_fields) {
+        (Some("insert"), 2 | 3) if tokens.is_done() => (StatementType::Insert, None),
+        (Some("update"), 3) if tokens.is_done() => (StatementType::Update, None),
+        (Some("insert"), 3)
+            if tokens.eat(&["on", "conflict", "do", "update", "set", "email", "="]) =>
+        {
+            let (Some(new_email), true) = (tokens.next(), tokens.is_done()) else {
+                return PrepareResult::UnrecognizedStatement;
+            };
+            let new_email = new_email.trim_matches('\'');
+            if new_email.len() > COLUMN_EMAIL_SIZE {
+                return PrepareResult::StringTooLong;
+            }
+            (
+                StatementType::Insert,
+                Some(ConflictAction::UpdateEmail(new_email.to_string())),
+            )
+        }
+        _ => return PrepareResult::UnrecognizedStatement,
+    };
+
+    let (id, username, email) = match parse_row_fields(input) {
+        Ok(fields) => fields,
+        Err(result) => return result,
+    };
+
+    PrepareResult::Success(Statement {
+        statement_type,
+        row_to_insert: Some(Row {
+            id,
+            username: username.to_string(),
+            email: email.to_string(),
+        }),
+        on_conflict,
+    })
+}
+
+// the `<keyword> <id> <username> <email>` prefix shared by insert and update; anything
+// after the fourth token is left for the caller. a missing email takes the column default.
+fn parse_row_fields(input: &str) -> std::result::Result<(u32, &str, &str), PrepareResult> {
+    let mut tokens = Tokens::new(input).skip(1);
+    let (Some(id), Some(username)) = (tokens.next(), tokens.next()) else {
+        return Err(PrepareResult::SyntaxError);
+    };
+
+    let id = id.parse::<u32>().map_err(|_| PrepareResult::SyntaxError)?;
+    let username = username.trim_matches('\'');
+    let email = match tokens.next() {
+        Some(email) => email.trim_matches('\''),
+        None => column_default("email").ok_or(PrepareResult::SyntaxError)?,
+    };
+    if username.len() > COLUMN_USERNAME_SIZE || email.len() > COLUMN_EMAIL_SIZE {
+        return Err(PrepareResult::StringTooLong);
+    }
+
+    Ok((id, username, email))
+}
+
+// drops a trailing `-- comment`, ignoring `--` inside single-quoted values
+pub fn strip_comment(input: &str) -> &str {
+    let mut in_quote = false;
+    let mut prev = None;
+
+    for (i, c) in input.char_indices() {
+        match c {
+            '\'' => in_quote = !in_quote,
+            '-' if !in_quote && prev == Some('-') => return input[..i - 1].trim_end(),
+            _ => {}
+        }
+        prev = Some(c);
+    }
+
+    input
+}
+
+// the first token past what the statement's grammar consumes, if any
+fn unexpected_token(input: &str) -> Option<&str> {
+    let parts = Tokens::new(input).collect::<Vec<_>>();
+    let expected_len = match parts.first() {
+        Some(&"select") if parts.get(1) == Some(&"last_insert_id()") => 2,
+        Some(&"select") if parts.get(1) == Some(&"sample") && parts.get(3) == Some(&"seed") => 5,
+        Some(&"select") if parts.get(1) == Some(&"sample") => 3,
+        // the id list runs to the end of the statement
+        Some(&"select") if parts.get(1) == Some(&"where") && parts.get(3) == Some(&"in") => {
+            return None;
+        }
+        Some(&"select") if parts.get(1) == Some(&"where") => 5,
+        Some(&"select") if parts.get(1) == Some(&"order") => 5,
+        Some(&"select") if parts.get(1) == Some(&"offset") => 3,
+        Some(&"select") => 1,
+        Some(&"insert") if parts.get(4) == Some(&"on") => 12,
+        Some(&"insert") | Some(&"update") => 4,
+        Some(keyword)
+            if keyword.starts_with("select")
+                || keyword.starts_with("insert")
+                || keyword.starts_with("update") =>
+        {
+            return Some(keyword);
+        }
+        _ => return None,
+    };
+
+    parts.get(expected_len).copied()
+}
+
+pub fn execute_statement(statement: &Statement, table: &mut Table) -> Result<ExecuteResult> {
+    if table.pager.read_only
+        && matches!(
+            statement.statement_type,
+            StatementType::Insert | StatementType::Update
+        )
+    {
+        return Err(read_only_error());
+    }
+
+    match statement.statement_type {
+        StatementType::Insert => {
+            let row = statement.row_to_insert.as_ref().unwrap();
+            let dirty_before = table.pager.dirty_page_count();
+            let (mut cursor, exact_match) = table_find(table, row.id)?;
+
+            if exact_match {
+                return match &statement.on_conflict {
+                    Some(ConflictAction::UpdateEmail(email)) => {
+                        let mut existing = deserialize_row(cursor.value_ref()?);
+                        existing.email = email.clone();
+                        serialize_row(&existing, cursor.value()?)?;
+                        cursor.table.row_cache.invalidate(row.id);
+                        Ok(ExecuteResult::Success)
+                    }
+                    None => Ok(ExecuteResult::DuplicateKey),
+                };
+            }
+
+            let num_cells = leaf_node_num_cells(cursor.table.pager.page_ref(cursor.page_num)?);
+            let needed_split = num_cells as usize >= LEAF_NODE_MAX_CELLS;
+            let cell_num = cursor.cell_num;
+            if !needed_split {
+                Table::insert_at_cursor(&mut cursor, row)?;
+            }
+
+            if cursor.table.debug {
+                let stats = InsertStats {
+                    needed_split,
+                    pages_dirtied: cursor.table.pager.dirty_page_count() - dirty_before,
+                };
+                if !needed_split {
+                    println!("page_num: {}", cursor.page_num);
+                    println!("cell_num: {}", cell_num);
+                    println!("num_pages: {}", cursor.table.pager.num_pages);
+                }
+                println!("split: {}", if needed_split { "needed" } else { "no" });
+                println!("pages_dirtied: {}", stats.pages_dirtied);
+                cursor.table.last_insert_stats = Some(stats);
+            }
+            if needed_split {
+                return Ok(ExecuteResult::LeafFull);
+            }
+        }
+        StatementType::Update => {
+            let row = statement.row_to_insert.as_ref().unwrap();
+            let (mut cursor, exact_match) = table_find(table, row.id)?;
+            if !exact_match {
+                return Ok(ExecuteResult::NotFound);
+            }
+
+            if cursor.table.normalize_strings {
+                serialize_row(&row.normalized(), cursor.value()?)?;
+            } else {
+                serialize_row(row, cursor.value()?)?;
+            }
+            cursor.table.row_cache.invalidate(row.id);
+        }
+        StatementType::Select => {
+            let mut rows = vec![];
+            let mut pages_scanned = 0;
+            let mut last_page = None;
+            let mut cursor = Cursor::table_start(table)?;
+            loop {
+                // advance already moved past exhausted leaves, so this is the row's page
+                let page_num = cursor.page_num;
+                let Some(row) = cursor.next_row()? else {
+                    break;
+                };
+                if last_page != Some(page_num) {
+                    pages_scanned += 1;
+                    last_page = Some(page_num);
+                }
+                rows.push(row);
+            }
+
+            if cursor.table.debug {
+                println!("pages_scanned: {}", pages_scanned);
+            }
+
+            return Ok(ExecuteResult::Rows(ResultSet {
+                columns: row_columns(),
+                rows,
+            }));
+        }
+        StatementType::Sample { n, seed } => {
+            return Ok(ExecuteResult::Rows(ResultSet {
+                columns: row_columns(),
+                rows: table.sample(n, seed)?,
+            }));
+        }
+        StatementType::SelectPrefix(ref prefix) => {
+            let mut rows = vec![];
+            let mut cursor = Cursor::table_start(table)?;
+            while let Some(row) = cursor.next_row()? {
+                if row.matches_prefix(prefix) {
+                    rows.push(row);
+                }
+            }
+
+            return Ok(ExecuteResult::Rows(ResultSet {
+                columns: row_columns(),
+                rows,
+            }));
+        }
+        StatementType::SelectIn(ref ids) => {
+            return Ok(ExecuteResult::Rows(ResultSet {
+                columns: row_columns(),
+                rows: table.get_many(ids)?.into_iter().flatten().collect(),
+            }));
+        }
+        StatementType::SelectGreaterThan(bound) => {
+            // seek straight past `bound` instead of scanning up to it
+            let rows = match bound.checked_add(1) {
+                Some(start) => table.scan_forward_from(start, usize::MAX)?,
+                None => vec![],
+            };
+
+            return Ok(ExecuteResult::Rows(ResultSet {
+                columns: row_columns(),
+                rows,
+            }));
+        }
+        StatementType::SelectLessThan(bound) => {
+            let mut rows = vec![];
+            let mut cursor = Cursor::table_start(table)?;
+            while let Some(row) = cursor.next_row()? {
+                if row.id >= bound {
+                    break;
+                }
+                rows.push(row);
+            }
+
+            return Ok(ExecuteResult::Rows(ResultSet {
+                columns: row_columns(),
+                rows,
+            }));
+        }
+        StatementType::SelectOffset(offset) => {
+            let mut rows = vec![];
+            let mut cursor = Cursor::table_start(table)?;
+            cursor.skip(offset)?;
+            while let Some(row) = cursor.next_row()? {
+                rows.push(row);
+            }
+
+            return Ok(ExecuteResult::Rows(ResultSet {
+                columns: row_columns(),
+                rows,
+            }));
+        }
+        StatementType::SelectDescending => {
+            return Ok(ExecuteResult::Rows(ResultSet {
+                columns: row_columns(),
+                rows: table.scan_descending()?,
+            }));
+        }
+        StatementType::LastInsertId => {
+            return Ok(ExecuteResult::LastInsertId(table.last_insert_id()));
+        }
+        StatementType::ExplainAnalyze(ref inner) => {
+            let before = table.pager.page_io();
+            let start = Instant::now();
+            // a statement that didn't run to completion reports why, not its cost
+            let rows = match execute_statement(inner, table)? {
+                ExecuteResult::Rows(result) => result.rows.len(),
+                ExecuteResult::Success => 0,
+                other => return Ok(other),
+            };
+
+            return Ok(ExecuteResult::Analyze(QueryStats {
+                plan: inner.statement_type.plan(),
+                rows,
+                io: table.pager.page_io().since(&before),
+                elapsed: start.elapsed(),
+            }));
+        }
+    }
+    table.record_mutation();
+    Ok(ExecuteResult::Success)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn temp_db_path(name: &str) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir()
+            .join(format!("rsdb_{}_{}.db", name, timestamp))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn open_temp_db(name: &str) -> (Table, String) {
+        let path = temp_db_path(name);
+        let table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        (table, path)
+    }
+
+    fn test_row(id: u32) -> Row {
+        Row {
+            id,
+            username: format!("user{}", id),
+            email: format!("person{}@example.com", id),
+        }
+    }
+
+    // inserts each row at its sorted position, like the insert statement does
+    fn insert_rows(table: &mut Table, rows: &[Row]) {
+        for row in rows {
+            let (mut cursor, _) = table_find(table, row.id).unwrap();
+            Table::insert_at_cursor(&mut cursor, row).unwrap();
+        }
+    }
+
+    // hand-builds an internal root (page 0) over two leaves (pages 1 and 2)
+    fn build_two_leaf_tree(table: &mut Table, left: &[u32], right: &[u32]) {
+        build_wide_tree(table, &[left.to_vec(), right.to_vec()]);
+    }
+
+    // an internal root on page 0 over leaves on pages 1..=leaves.len(), in order, each
+    // separated by the largest key of the leaf before it
+    fn build_wide_tree(table: &mut Table, leaves: &[Vec<u32>]) {
+        for (i, ids) in leaves.iter().enumerate() {
+            let page_num = i + 1;
+            let leaf = table.pager.get_page(page_num).unwrap();
+            initialize_leaf_node(leaf);
+            leaf[IS_ROOT_OFFSET] = 0;
+
+            let mut cursor = Cursor {
+                version: table.structure_version,
+                table: &mut *table,
+                page_num,
+                cell_num: 0,
+                end_of_table: true,
+                path: vec![],
+            };
+            for &id in ids {
+                Table::insert_at_cursor(&mut cursor, &test_row(id)).unwrap();
+            }
+        }
+
+        let num_keys = leaves.len() as u32 - 1;
+        let root = table.pager.get_page(0).unwrap();
+        initialize_internal_node(root);
+        root[IS_ROOT_OFFSET] = 1;
+        root[INTERNAL_NODE_NUM_KEYS_OFFSET..INTERNAL_NODE_NUM_KEYS_OFFSET + 4]
+            .copy_from_slice(&num_keys.to_le_bytes());
+        for (i, ids) in leaves[..num_keys as usize].iter().enumerate() {
+            let separator = ids.iter().copied().max().unwrap_or(0);
+            let cell = internal_node_cell_offset(i as u32);
+            root[cell..cell + 4].copy_from_slice(&(i as u32 + 1).to_le_bytes());
+            root[cell + 4..cell + 8].copy_from_slice(&separator.to_le_bytes());
+        }
+        root[INTERNAL_NODE_RIGHT_CHILD_OFFSET..INTERNAL_NODE_RIGHT_CHILD_OFFSET + 4]
+            .copy_from_slice(&(leaves.len() as u32).to_le_bytes());
+        table.repair_subtree_sizes().unwrap();
+    }
+
+    fn select_all(table: &mut Table) -> Vec<Row> {
+        let mut rows = vec![];
+        let mut cursor = Cursor::table_start(table).unwrap();
+        while let Some(row) = cursor.next_row().unwrap() {
+            rows.push(row);
+        }
+        rows
+    }
+
+    #[test]
+    fn test_row_serialization() {
+        let row = Row {
+            id: 1,
+            username: "john".to_string(),
+            email: "john@test.com".to_string(),
+        };
+
+        let mut buffer = [0u8; ROW_SIZE];
+        serialize_row(&row, &mut buffer).unwrap();
+        let deser_row = deserialize_row(&buffer);
+
+        assert_eq!(row, deser_row);
+    }
+
+    #[test]
+    fn test_serialize_row_rejects_oversized_strings() {
+        let row = Row {
+            id: 1,
+            username: "a".repeat(COLUMN_USERNAME_SIZE + 1),
+            email: "john@test.com".to_string(),
+        };
+
+        let mut buffer = [0u8; ROW_SIZE];
+        let err = serialize_row(&row, &mut buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(buffer, [0u8; ROW_SIZE]);
+
+        serialize_row_truncated(&row, &mut buffer);
+        let deser_row = deserialize_row(&buffer);
+        assert_eq!(deser_row.username, "a".repeat(COLUMN_USERNAME_SIZE));
+    }
+
+    #[test]
+    fn test_insert_at_cursor_matches_statement_path() {
+        let (mut batched, batched_path) = open_temp_db("batched");
+        let (mut naive, naive_path) = open_temp_db("naive");
+
+        {
+            let mut cursor = Cursor::table_end(&mut batched).unwrap();
+            for id in 1..=10 {
+                Table::insert_at_cursor(&mut cursor, &test_row(id)).unwrap();
+            }
+        }
+
+        for id in 1..=10 {
+            let statement = Statement {
+                statement_type: StatementType::Insert,
+                row_to_insert: Some(test_row(id)),
+                on_conflict: None,
+            };
+            execute_statement(&statement, &mut naive).unwrap();
+        }
+
+        let batched_rows = select_all(&mut batched);
+        assert_eq!(batched_rows.len(), 10);
+        assert_eq!(batched_rows, select_all(&mut naive));
+
+        let _ = std::fs::remove_file(batched_path);
+        let _ = std::fs::remove_file(naive_path);
+    }
+
+    #[test]
+    fn test_restore_from_wal_after_crash() {
+        let (mut source, source_path) = open_temp_db("wal_source");
+        {
+            let mut cursor = Cursor::table_end(&mut source).unwrap();
+            for id in 1..=3 {
+                Table::insert_at_cursor(&mut cursor, &test_row(id)).unwrap();
+            }
+        }
+        let page_image = *source.pager.get_page(0).unwrap();
+
+        // one complete entry followed by a torn one, as if the writer died mid-append
+        let path = temp_db_path("wal_crash");
+        let wal_path = format!("{}.wal", path);
+        let mut wal = vec![];
+        wal.extend_from_slice(&0u32.to_le_bytes());
+        wal.extend_from_slice(&page_image);
+        wal.extend_from_slice(&1u32.to_le_bytes());
+        wal.extend_from_slice(&page_image[..100]);
+        std::fs::write(&wal_path, &wal).unwrap();
+
+        let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        assert_eq!(select_all(&mut table).len(), 3);
+        assert!(!std::path::Path::new(&wal_path).exists());
+
+        std::fs::write(&wal_path, &wal[..WAL_ENTRY_SIZE]).unwrap();
+        assert_eq!(table.restore_from_wal().unwrap(), 1);
+        assert_eq!(table.restore_from_wal().unwrap(), 0);
+
+        let _ = std::fs::remove_file(source_path);
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(wal_path);
+    }
+
+    #[test]
+    fn test_row_hash_dedup() {
+        use std::collections::HashSet;
+
+        let rows = vec![
+            test_row(1),
+            test_row(2),
+            test_row(1),
+            test_row(3),
+            test_row(2),
+        ];
+        let unique: HashSet<Row> = rows.into_iter().collect();
+
+        assert_eq!(unique.len(), 3);
+        assert!(unique.contains(&test_row(1)));
+    }
+
+    #[test]
+    fn test_select_result_set_metadata() {
+        let (mut table, path) = open_temp_db("result_set");
+        {
+            let mut cursor = Cursor::table_end(&mut table).unwrap();
+            Table::insert_at_cursor(&mut cursor, &test_row(1)).unwrap();
+        }
+
+        let statement = Statement {
+            statement_type: StatementType::Select,
+            row_to_insert: None,
+            on_conflict: None,
+        };
+        let result = match execute_statement(&statement, &mut table).unwrap() {
+            ExecuteResult::Rows(result) => result,
+            other => panic!("expected rows, got {:?}", other),
+        };
+
+        let names: Vec<_> = result.columns.iter().map(|c| c.name).collect();
+        assert_eq!(names, ["id", "username", "email"]);
+        assert_eq!(result.columns[0].column_type, ColumnType::Integer);
+        assert_eq!(
+            result.columns[2].column_type,
+            ColumnType::Text(COLUMN_EMAIL_SIZE)
+        );
+        assert_eq!(result.rows, vec![test_row(1)]);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_every_select_form_has_row_metadata() {
+        let (mut table, path) = open_temp_db("result_set_forms");
+        insert_rows(&mut table, &(1..=5).map(test_row).collect::<Vec<_>>());
+
+        let columns = |columns: &[ColumnMeta]| -> Vec<_> {
+            columns.iter().map(|c| (c.name, c.column_type)).collect()
+        };
+        let expected = columns(&row_columns());
+        for (input, ids) in [
+            ("select where id > 3", vec![4, 5]),
+            ("select where id < 3", vec![1, 2]),
+            ("select where id in (2, 4)", vec![2, 4]),
+            ("select where prefix = user5", vec![5]),
+            ("select offset 3", vec![4, 5]),
+            ("select order by id desc", vec![5, 4, 3, 2, 1]),
+            ("select where id > 9", vec![]),
+        ] {
+            let statement = input.parse::<Statement>().unwrap();
+            let result = match execute_statement(&statement, &mut table).unwrap() {
+                ExecuteResult::Rows(result) => result,
+                other => panic!("expected rows for {:?}, got {:?}", input, other),
+            };
+            // the schema is fixed, so even an empty result set describes every column
+            assert_eq!(columns(&result.columns), expected, "{}", input);
+            let got: Vec<u32> = result.rows.iter().map(|row| row.id).collect();
+            assert_eq!(got, ids, "{}", input);
+        }
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_iter_pages_with_type() {
+        let (mut table, path) = open_temp_db("page_types");
+        assert_eq!(
+            table.iter_pages_with_type().unwrap(),
+            vec![(0, NodeType::Leaf)]
+        );
+
+        build_two_leaf_tree(&mut table, &[1], &[2]);
+
+        assert_eq!(
+            table.iter_pages_with_type().unwrap(),
+            vec![
+                (0, NodeType::Internal),
+                (1, NodeType::Leaf),
+                (2, NodeType::Leaf)
+            ]
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_key_iter() {
+        let (mut table, path) = open_temp_db("key_iter");
+        {
+            let mut cursor = Cursor::table_end(&mut table).unwrap();
+            for id in 1..=5 {
+                Table::insert_at_cursor(&mut cursor, &test_row(id)).unwrap();
+            }
+        }
+        let scanned: Vec<u32> = select_all(&mut table).iter().map(|r| r.id).collect();
+        assert_eq!(
+            table
+                .key_iter()
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            scanned
+        );
+
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5, 6]);
+        let keys: Vec<u32> = table.key_iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(keys, (1..=6).collect::<Vec<_>>());
+
+        // keys are read as the iterator is driven, not up front
+        table.cursor_advances = 0;
+        let first_two: Vec<u32> = table
+            .key_iter()
+            .unwrap()
+            .take(2)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(first_two, [1, 2]);
+        assert_eq!(table.cursor_advances, 2);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_find_by_email() {
+        let (mut table, path) = open_temp_db("find_by_email");
+        let shared = Row {
+            id: 3,
+            username: "other".to_string(),
+            email: "person1@example.com".to_string(),
+        };
+        {
+            let mut cursor = Cursor::table_end(&mut table).unwrap();
+            for row in [test_row(1), test_row(2), shared.clone()] {
+                Table::insert_at_cursor(&mut cursor, &row).unwrap();
+            }
+        }
+
+        assert_eq!(
+            table.find_by_email("person2@example.com").unwrap(),
+            Some(test_row(2))
+        );
+        assert_eq!(table.find_by_email("nobody@example.com").unwrap(), None);
+        assert_eq!(
+            table.find_all_by_email("person1@example.com").unwrap(),
+            vec![test_row(1), shared]
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_reopen_replays_orphaned_wal() {
+        let path = temp_db_path("orphaned_wal");
+        let wal_path = format!("{}.wal", path);
+
+        let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        {
+            let mut cursor = Cursor::table_end(&mut table).unwrap();
+            Table::insert_at_cursor(&mut cursor, &test_row(1)).unwrap();
+        }
+        db_close(&mut table).unwrap();
+
+        // the writer logs a second insert to the wal, then dies before checkpointing
+        {
+            let mut cursor = Cursor::table_end(&mut table).unwrap();
+            Table::insert_at_cursor(&mut cursor, &test_row(2)).unwrap();
+        }
+        let mut wal = 0u32.to_le_bytes().to_vec();
+        wal.extend_from_slice(table.pager.get_page(0).unwrap());
+        std::fs::write(&wal_path, &wal).unwrap();
+
+        let err = Pager::new_no_sync(&path).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+        drop(table);
+
+        let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        assert!(!std::path::Path::new(&wal_path).exists());
+        assert_eq!(select_all(&mut table), vec![test_row(1), test_row(2)]);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_count_pages_by_type() {
+        let (mut table, path) = open_temp_db("count_pages");
+        assert_eq!(table.count_pages_by_type().unwrap(), (1, 0));
+
+        build_two_leaf_tree(&mut table, &[1, 2], &[3, 4]);
+        assert_eq!(table.count_pages_by_type().unwrap(), (2, 1));
+
+        // two full leaves and a partial one: at least rows / LEAF_NODE_MAX_CELLS leaves,
+        // rounded up, and one level of internal nodes above them
+        let max = LEAF_NODE_MAX_CELLS as u32;
+        let leaves = vec![
+            (1..=max).collect(),
+            (max + 1..=2 * max).collect(),
+            (2 * max + 1..=2 * max + 4).collect(),
+        ];
+        build_wide_tree(&mut table, &leaves);
+        let rows = table.row_count().unwrap() as usize;
+        assert_eq!(rows, 2 * LEAF_NODE_MAX_CELLS + 4);
+        let (leaf_pages, internal_pages) = table.count_pages_by_type().unwrap();
+        assert_eq!((leaf_pages, internal_pages), (3, 1));
+        assert_eq!(leaf_pages, rows.div_ceil(LEAF_NODE_MAX_CELLS));
+        assert!(rows < leaf_pages * LEAF_NODE_MAX_CELLS);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_row_cache() {
+        let path = temp_db_path("row_cache");
+        let options = DbOptions {
+            row_cache_size: 2,
+            ..Default::default()
+        };
+        let mut table = db_open_with_options(Pager::new_no_sync(&path).unwrap(), options).unwrap();
+        {
+            let mut cursor = Cursor::table_end(&mut table).unwrap();
+            for id in 1..=3 {
+                Table::insert_at_cursor(&mut cursor, &test_row(id)).unwrap();
+            }
+        }
+
+        assert_eq!(table.get(2).unwrap(), Some(test_row(2)));
+        let page_accesses = table.pager.cache_hits + table.pager.cache_misses;
+        assert_eq!(table.get(2).unwrap(), Some(test_row(2)));
+        assert_eq!(
+            table.pager.cache_hits + table.pager.cache_misses,
+            page_accesses
+        );
+
+        // writing the id drops the cached copy so the next lookup goes back to the page
+        {
+            let mut cursor = Cursor::table_end(&mut table).unwrap();
+            Table::insert_at_cursor(&mut cursor, &test_row(2)).unwrap();
+        }
+        let page_accesses = table.pager.cache_hits + table.pager.cache_misses;
+        table.get(2).unwrap();
+        assert!(table.pager.cache_hits + table.pager.cache_misses > page_accesses);
+
+        // capacity 2: caching 1 and 3 evicts 2
+        table.get(1).unwrap();
+        table.get(3).unwrap();
+        assert!(table.row_cache.get(2).is_none());
+        assert_eq!(table.get(4).unwrap(), None);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_contains() {
+        let (mut table, path) = open_temp_db("contains");
+        insert_rows(&mut table, &[test_row(3), test_row(1), test_row(2)]);
+
+        assert!(table.contains(1).unwrap());
+        assert!(table.contains(3).unwrap());
+        assert!(!table.contains(4).unwrap());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_per_transaction_reopen_restats_the_file() {
+        let path = temp_db_path("per_transaction_restat");
+        let options = DbOptions {
+            file_access: FileAccess::PerTransaction,
+            ..Default::default()
+        };
+        let mut table = db_open_with_options(Pager::new_no_sync(&path).unwrap(), options).unwrap();
+        insert_rows(&mut table, &[test_row(1)]);
+        db_close(&mut table).unwrap();
+        assert_eq!(table.pager.num_pages, 1);
+
+        // another writer appends a page while the handle is closed
+        let mut appended = [0u8; PAGE_SIZE];
+        initialize_leaf_node(&mut appended);
+        appended[IS_ROOT_OFFSET] = 0;
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&appended)
+            .unwrap();
+        assert_eq!(table.pager.page_ref(1).unwrap(), &appended);
+        assert_eq!(table.pager.num_pages, 2);
+        assert_eq!(table.pager.file_length, 2 * PAGE_SIZE as u64);
+
+        // and one that loses pages already on disk is refused rather than read as zeros
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(PAGE_SIZE as u64)
+            .unwrap();
+        table.pager.evict(0).unwrap();
+        assert_eq!(
+            table.pager.page_ref(0).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_file_access_modes() {
+        let mut results = vec![];
+
+        for file_access in [FileAccess::Persistent, FileAccess::PerTransaction] {
+            let path = temp_db_path("file_access");
+            let options = DbOptions {
+                file_access,
+                ..Default::default()
+            };
+
+            let mut table =
+                db_open_with_options(Pager::new_no_sync(&path).unwrap(), options.clone()).unwrap();
+            {
+                let mut cursor = Cursor::table_end(&mut table).unwrap();
+                for id in 1..=3 {
+                    Table::insert_at_cursor(&mut cursor, &test_row(id)).unwrap();
+                }
+            }
+            db_close(&mut table).unwrap();
+
+            // only the per-transaction table has let go of the file (and its lock), though
+            // this process still can't open it a second time
+            let other_handle = OpenOptions::new().read(true).open(&path).unwrap();
+            assert_eq!(
+                other_handle.try_lock().is_ok(),
+                file_access == FileAccess::PerTransaction
+            );
+            drop(other_handle);
+            assert_eq!(
+                Pager::new_no_sync(&path).err().unwrap().kind(),
+                ErrorKind::ResourceBusy
+            );
+            drop(table);
+
+            let mut table =
+                db_open_with_options(Pager::new_no_sync(&path).unwrap(), options).unwrap();
+            results.push(select_all(&mut table));
+
+            let _ = std::fs::remove_file(path);
+        }
+
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0].len(), 3);
+    }
+
+    #[test]
+    fn test_diff() {
+        let (mut a, a_path) = open_temp_db("diff_a");
+        let (mut b, b_path) = open_temp_db("diff_b");
+        let changed = Row {
+            email: "changed@example.com".to_string(),
+            ..test_row(2)
+        };
+        insert_rows(&mut a, &[test_row(3), test_row(1), test_row(2)]);
+        insert_rows(&mut b, &[changed.clone(), test_row(3), test_row(4)]);
+
+        assert_eq!(
+            a.diff(&mut b).unwrap(),
+            vec![
+                RowDiff::OnlyInSelf(test_row(1)),
+                RowDiff::Changed {
+                    ours: test_row(2),
+                    theirs: changed,
+                },
+                RowDiff::OnlyInOther(test_row(4)),
+            ]
+        );
+
+        let _ = std::fs::remove_file(a_path);
+        let _ = std::fs::remove_file(b_path);
+    }
+
+    #[test]
+    fn test_merge_from() {
+        let theirs_changed = Row {
+            email: "theirs@example.com".to_string(),
+            ..test_row(2)
+        };
+        for (policy, expected) in [
+            (
+                MergeConflict::Skip,
+                Ok((2, [1, 2, 3, 4].map(test_row).to_vec())),
+            ),
+            (
+                MergeConflict::Replace,
+                Ok((
+                    3,
+                    vec![
+                        test_row(1),
+                        theirs_changed.clone(),
+                        test_row(3),
+                        test_row(4),
+                    ],
+                )),
+            ),
+            // 2 conflicts before 3 or 4 is reached
+            (MergeConflict::Error, Err(vec![test_row(1), test_row(2)])),
+        ] {
+            let (mut ours, ours_path) = open_temp_db("merge_from_ours");
+            let (mut theirs, theirs_path) = open_temp_db("merge_from_theirs");
+            insert_rows(&mut ours, &[test_row(1), test_row(2)]);
+            insert_rows(
+                &mut theirs,
+                &[test_row(4), theirs_changed.clone(), test_row(3)],
+            );
+
+            match (ours.merge_from(&mut theirs, policy), expected) {
+                (Ok(merged), Ok((expected_merged, rows))) => {
+                    assert_eq!(merged, expected_merged, "{:?}", policy);
+                    assert_eq!(select_all(&mut ours), rows, "{:?}", policy);
+                }
+                (Err(e), Err(rows)) => {
+                    assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+                    assert_eq!(select_all(&mut ours), rows);
+                }
+                (result, _) => panic!("unexpected {:?} for {:?}", result, policy),
+            }
+            // the source is only read
+            assert_eq!(
+                theirs
+                    .key_iter()
+                    .unwrap()
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap(),
+                vec![2, 3, 4]
+            );
+
+            let _ = std::fs::remove_file(ours_path);
+            let _ = std::fs::remove_file(theirs_path);
+        }
+    }
+
+    #[test]
+    fn test_single_root_flag() {
+        let (mut table, path) = open_temp_db("single_root_flag");
+        let root_flags = |table: &mut Table| -> Vec<usize> {
+            let pages = table.iter_pages_with_type().unwrap();
+            pages
+                .into_iter()
+                .map(|(page_num, _)| page_num)
+                .filter(|&page_num| node_is_root(table.pager.page_ref(page_num).unwrap()))
+                .collect()
+        };
+        assert_eq!(root_flags(&mut table), vec![0]);
+
+        // grow to two levels, then merge back down so the root absorbs its only child
+        build_two_leaf_tree(&mut table, &[1, 2], &[3, 4]);
+        assert_eq!(root_flags(&mut table), vec![table.root_page_num]);
+        table.compact().unwrap();
+        assert_eq!(root_flags(&mut table), vec![table.root_page_num]);
+        assert_eq!(table.integrity_check().unwrap(), vec![]);
+
+        // a wider tree, and the merge that leaves its root in place
+        build_wide_tree(&mut table, &[vec![1], vec![2, 3], (4..=13).collect()]);
+        assert_eq!(root_flags(&mut table), vec![0]);
+        assert_eq!(table.compact().unwrap(), 1);
+        assert_eq!(table.count_pages_by_type().unwrap(), (2, 1));
+        assert_eq!(root_flags(&mut table), vec![0]);
+        assert_eq!(table.integrity_check().unwrap(), vec![]);
+
+        // a leaf left flagged after its old root grew a parent
+        build_two_leaf_tree(&mut table, &[1, 2], &[3, 4]);
+        table.pager.get_page(1).unwrap()[IS_ROOT_OFFSET] = 1;
+        assert_eq!(
+            table.integrity_check().unwrap(),
+            vec![IntegrityIssue::RootFlagMismatch {
+                page: 1,
+                is_root: true,
+            }]
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_max_leaf_depth() {
+        let (mut table, path) = open_temp_db("max_leaf_depth");
+        assert_eq!(table.max_leaf_depth().unwrap(), 0);
+
+        build_two_leaf_tree(&mut table, &[1, 2], &[5, 6]);
+        assert_eq!(table.max_leaf_depth().unwrap(), 1);
+        assert_eq!(table.integrity_check().unwrap(), vec![]);
+
+        // hang an internal node with two leaves of its own off the right side
+        {
+            let node = table.pager.get_page(3).unwrap();
+            initialize_internal_node(node);
+            node[INTERNAL_NODE_NUM_KEYS_OFFSET..INTERNAL_NODE_NUM_KEYS_OFFSET + 4]
+                .copy_from_slice(&1u32.to_le_bytes());
+            let cell = internal_node_cell_offset(0);
+            node[cell..cell + 4].copy_from_slice(&2u32.to_le_bytes());
+            node[cell + 4..cell + 8].copy_from_slice(&6u32.to_le_bytes());
+            node[INTERNAL_NODE_RIGHT_CHILD_OFFSET..INTERNAL_NODE_RIGHT_CHILD_OFFSET + 4]
+                .copy_from_slice(&4u32.to_le_bytes());
+            let leaf = table.pager.get_page(4).unwrap();
+            initialize_leaf_node(leaf);
+            leaf[IS_ROOT_OFFSET] = 0;
+            set_internal_node_child(table.pager.get_page(0).unwrap(), 1, 3);
+        }
+        table.repair_subtree_sizes().unwrap();
+        assert_eq!(table.max_leaf_depth().unwrap(), 2);
+        assert_eq!(
+            table.integrity_check().unwrap(),
+            vec![IntegrityIssue::UnbalancedLeaves {
+                min_depth: 1,
+                max_depth: 2,
+            }]
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_integrity_check() {
+        let (mut table, path) = open_temp_db("integrity");
+        insert_rows(&mut table, &[test_row(2), test_row(1), test_row(3)]);
+        assert_eq!(table.integrity_check().unwrap(), vec![]);
+
+        build_two_leaf_tree(&mut table, &[1, 2], &[3, 4]);
+        assert_eq!(table.integrity_check().unwrap(), vec![]);
+
+        // append out of order on the left leaf, corrupt the right one, leak page 3
+        {
+            let mut cursor = Cursor {
+                version: table.structure_version,
+                table: &mut table,
+                page_num: 1,
+                cell_num: 2,
+                end_of_table: true,
+                path: vec![],
+            };
+            Table::insert_at_cursor(&mut cursor, &test_row(0)).unwrap();
+        }
+        table.pager.get_page(2).unwrap()[NODE_TYPE_OFFSET] = 7;
+        initialize_leaf_node(table.pager.get_page(3).unwrap());
+
+        assert_eq!(
+            table.integrity_check().unwrap(),
+            vec![
+                IntegrityIssue::UnsortedKey {
+                    page: 1,
+                    cell: 2,
+                    key: 0,
+                    prev_key: 2,
+                },
+                IntegrityIssue::InvalidNodeType { page: 2, byte: 7 },
+                IntegrityIssue::OrphanPage { page: 3 },
+            ]
+        );
+
+        set_leaf_node_num_cells(table.pager.get_page(1).unwrap(), 500);
+        assert!(
+            table
+                .integrity_check()
+                .unwrap()
+                .contains(&IntegrityIssue::InconsistentNumCells {
+                    page: 1,
+                    stored: 500,
+                    actual: LEAF_NODE_MAX_CELLS as u32,
+                })
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_trailing_tokens() {
+        let strict = |input| prepare_statement_with_mode(input, ParseMode::Strict);
+
+        assert!(matches!(
+            strict("select limitt 5"),
+            PrepareResult::UnexpectedToken(token) if token == "limitt"
+        ));
+        assert!(matches!(
+            strict("selectt"),
+            PrepareResult::UnexpectedToken(token) if token == "selectt"
+        ));
+        assert!(matches!(
+            strict("insert 1 user1 person1@example.com extra"),
+            PrepareResult::UnexpectedToken(token) if token == "extra"
+        ));
+
+        assert!(matches!(strict("select"), PrepareResult::Success(_)));
+        assert!(matches!(
+            strict("insert 1 user1 person1@example.com"),
+            PrepareResult::Success(_)
+        ));
+        assert!(matches!(
+            strict("insert 1 user1 a@b.com on conflict do update set email = c@d.com"),
+            PrepareResult::Success(_)
+        ));
+
+        // lenient mode keeps ignoring the tail
+        assert!(matches!(
+            prepare_statement("select limitt 5"),
+            PrepareResult::Success(_)
+        ));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let (mut table, path) = open_temp_db("rotate");
+        assert_eq!(table.rotate(3).unwrap(), 0);
+
+        // a full leaf has room once its oldest rows come off
+        table.seed(LEAF_NODE_MAX_CELLS).unwrap();
+        assert_eq!(table.rotate(4).unwrap(), 4);
+        let rows = select_all(&mut table);
+        assert_eq!(
+            rows.iter().map(|row| row.id).collect::<Vec<_>>(),
+            (5..=17).collect::<Vec<_>>()
+        );
+        assert_eq!(rows[9].username, "user1");
+        assert_eq!(rows[12].username, "user4");
+        assert_eq!(table.get(1).unwrap(), None);
+        let _ = std::fs::remove_file(path);
+
+        // across leaves: the left one empties, the right one takes every new id
+        let (mut table, path) = open_temp_db("rotate_leaves");
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5]);
+        assert_eq!(table.rotate(4).unwrap(), 4);
+        assert_eq!(
+            table
+                .key_iter()
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![5, 6, 7, 8, 9]
+        );
+        assert_eq!(table.get(9).unwrap().unwrap().username, "user4");
+        assert_eq!(table.integrity_check().unwrap(), vec![]);
+        let _ = std::fs::remove_file(path);
+
+        let (mut table, path) = open_temp_db("rotate_full");
+        let right: Vec<u32> = (2..=LEAF_NODE_MAX_CELLS as u32 + 1).collect();
+        build_two_leaf_tree(&mut table, &[1], &right);
+        assert!(table.rotate(1).is_err());
+        assert_eq!(table.row_count().unwrap(), LEAF_NODE_MAX_CELLS as u64 + 1);
+        assert_eq!(table.first().unwrap(), Some(1));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_parallel_count() {
+        let (mut table, path) = open_temp_db("parallel_count");
+        assert_eq!(table.parallel_count(4).unwrap(), 0);
+
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5]);
+        for n_threads in [1, 2, 3, 8] {
+            assert_eq!(table.parallel_count(n_threads).unwrap(), 5);
+        }
+        assert_eq!(
+            table.parallel_count(0).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_truncate_to() {
+        let path = temp_db_path("truncate_to");
+        let options = DbOptions {
+            row_cache_size: 8,
+            ..DbOptions::default()
+        };
+        let mut table = db_open_with_options(Pager::new_no_sync(&path).unwrap(), options).unwrap();
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5]);
+        assert_eq!(table.get(5).unwrap(), Some(test_row(5)));
+
+        assert_eq!(table.truncate_to(5).unwrap(), 0);
+        assert_eq!(table.truncate_to(9).unwrap(), 0);
+
+        // empties the right leaf and reaches one cell into the left
+        assert_eq!(table.truncate_to(2).unwrap(), 3);
+        assert_eq!(select_all(&mut table), vec![test_row(1), test_row(2)]);
+        assert_eq!(table.get(5).unwrap(), None);
+        assert_eq!(table.last().unwrap(), Some(2));
+
+        assert_eq!(table.truncate_to(0).unwrap(), 2);
+        assert_eq!(table.row_count().unwrap(), 0);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_row_at_rank_across_leaves() {
+        let (mut table, path) = open_temp_db("row_at_rank");
+        build_two_leaf_tree(&mut table, &[2, 4], &[6, 8, 10]);
+
+        let ranked: Vec<Option<u32>> = (0..6)
+            .map(|rank| table.row_at_rank(rank).unwrap().map(|row| row.id))
+            .collect();
+        assert_eq!(
+            ranked,
+            vec![Some(2), Some(4), Some(6), Some(8), Some(10), None]
+        );
+        assert_eq!(table.row_at_rank(3).unwrap(), Some(test_row(8)));
+
+        // inserts and deletes keep the root's counts in step
+        let (mut cursor, _) = table_find(&mut table, 3).unwrap();
+        Table::insert_at_cursor(&mut cursor, &test_row(3)).unwrap();
+        assert_eq!(table.row_at_rank(2).unwrap(), Some(test_row(4)));
+        assert_eq!(table.truncate_to(4).unwrap(), 2);
+        assert_eq!(table.row_at_rank(3).unwrap(), Some(test_row(6)));
+        assert_eq!(table.row_at_rank(4).unwrap(), None);
+        assert!(table.integrity_check().unwrap().is_empty());
+
+        // the lookup goes by the stored counts alone, and integrity_check notices them drifting
+        set_internal_node_subtree_size(table.pager.get_page(0).unwrap(), 0, 1);
+        assert_eq!(table.row_at_rank(1).unwrap(), Some(test_row(6)));
+        assert_eq!(
+            table.integrity_check().unwrap(),
+            vec![IntegrityIssue::SubtreeSizeMismatch {
+                page: 0,
+                child: 0,
+                stored: 1,
+                actual: 3,
+            }]
+        );
+        assert_eq!(table.repair_subtree_sizes().unwrap(), 1);
+        assert_eq!(table.row_at_rank(1).unwrap(), Some(test_row(3)));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_select_where_id_in() {
+        let (mut table, path) = open_temp_db("select_where_id_in");
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5]);
+
+        let select = |table: &mut Table, input: &str| -> Vec<u32> {
+            let statement = match prepare_statement(input) {
+                PrepareResult::Success(statement) => statement,
+                _ => panic!("expected a parsed select: {}", input),
+            };
+            match execute_statement(&statement, table).unwrap() {
+                ExecuteResult::Rows(result) => result.rows.iter().map(|row| row.id).collect(),
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+        assert_eq!(
+            select(&mut table, "select where id in (1, 3, 5)"),
+            vec![1, 3, 5]
+        );
+        assert_eq!(select(&mut table, "select where id in (9,2,7)"), vec![2]);
+        assert_eq!(
+            select(&mut table, "select where id in ( 4 , 4, 1,4 )"),
+            vec![1, 4]
+        );
+        assert_eq!(
+            select(&mut table, "select where id in (8)"),
+            Vec::<u32>::new()
+        );
+
+        for input in [
+            "select where id in ()",
+            "select where id in (1, x)",
+            "select where id in 1, 2",
+            "select where id in (1,, 2)",
+        ] {
+            assert!(
+                matches!(prepare_statement(input), PrepareResult::SyntaxError),
+                "{}",
+                input
+            );
+        }
+        assert!(matches!(
+            prepare_statement_with_mode("select where id in (1, 2)", ParseMode::Strict),
+            PrepareResult::Success(_)
+        ));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_explain_analyze() {
+        let (mut table, path) = open_temp_db("explain_analyze");
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5]);
+
+        let mut analyze =
+            |input: &str| match execute_statement(&input.parse::<Statement>().unwrap(), &mut table)
+                .unwrap()
+            {
+                ExecuteResult::Analyze(stats) => stats,
+                other => panic!("unexpected {:?}", other),
+            };
+
+        let stats = analyze("explain analyze select");
+        assert_eq!(stats.plan, "full scan in key order");
+        assert_eq!(stats.rows, 5);
+        assert!(stats.io.reads > 0);
+        let stats = analyze("explain analyze select where id > 3");
+        assert_eq!(stats.plan, "seek to id 4, scan to the end");
+        assert_eq!(stats.rows, 2);
+        let stats = analyze("explain analyze select where id in (2, 9)");
+        assert_eq!(
+            (stats.plan.as_str(), stats.rows),
+            ("2 point lookups on id", 1)
+        );
+
+        // the statement really runs
+        analyze("explain analyze insert 6 user6 person6@example.com");
+        assert_eq!(table.get(6).unwrap(), Some(test_row(6)));
+        // and its failure is passed on unchanged
+        let statement = "explain analyze insert 6 user6 person6@example.com"
+            .parse::<Statement>()
+            .unwrap();
+        assert!(matches!(
+            execute_statement(&statement, &mut table).unwrap(),
+            ExecuteResult::DuplicateKey
+        ));
+        assert!(matches!(
+            prepare_statement("explain analyze bogus"),
+            PrepareResult::UnrecognizedStatement
+        ));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_select_where_id_range() {
+        let (mut table, path) = open_temp_db("select_where_id_range");
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5]);
+
+        let select = |table: &mut Table, input: &str| -> Vec<u32> {
+            let statement = match prepare_statement(input) {
+                PrepareResult::Success(statement) => statement,
+                _ => panic!("expected a parsed select: {}", input),
+            };
+            match execute_statement(&statement, table).unwrap() {
+                ExecuteResult::Rows(result) => result.rows.iter().map(|row| row.id).collect(),
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+        assert_eq!(select(&mut table, "select where id > 3"), vec![4, 5]);
+        assert_eq!(
+            select(&mut table, "select where id > 0"),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(select(&mut table, "select where id > 5"), Vec::<u32>::new());
+        assert_eq!(
+            select(&mut table, &format!("select where id > {}", u32::MAX)),
+            Vec::<u32>::new()
+        );
+        assert_eq!(select(&mut table, "select where id < 3"), vec![1, 2]);
+        assert_eq!(
+            select(&mut table, "select where id < 100"),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(select(&mut table, "select where id < 1"), Vec::<u32>::new());
+
+        for input in [
+            "select where id >",
+            "select where id >= 3",
+            "select where id > x",
+            "select where id < 3 4",
+        ] {
+            assert!(
+                matches!(prepare_statement(input), PrepareResult::SyntaxError),
+                "{}",
+                input
+            );
+        }
+        assert!(matches!(
+            prepare_statement_with_mode("select where id < 3 limit", ParseMode::Strict),
+            PrepareResult::UnexpectedToken(token) if token == "limit"
+        ));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_cursor_token_resumes_across_sessions() {
+        let (mut table, path) = open_temp_db("cursor_token");
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5]);
+
+        let mut cursor = Cursor::table_start(&mut table).unwrap();
+        cursor.skip(2).unwrap();
+        let mid_leaf = cursor.to_token();
+        cursor.skip(1).unwrap();
+        let next_leaf = cursor.to_token();
+        db_close(&mut table).unwrap();
+        drop(table);
+
+        let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        let mut resume = |token| {
+            let mut cursor = Cursor::from_token(&mut table, token).unwrap();
+            let mut ids = vec![];
+            while let Some(row) = cursor.next_row().unwrap() {
+                ids.push(row.id);
+            }
+            ids
+        };
+        assert_eq!(resume(mid_leaf), vec![3, 4, 5]);
+        assert_eq!(resume(next_leaf), vec![4, 5]);
+        // one past the end of a leaf carries on into the next
+        assert_eq!(resume([1, 0, 0, 0, 3, 0, 0, 0]), vec![4, 5]);
+
+        for token in [[0, 0, 0, 0, 0, 0, 0, 0], [1, 0, 0, 0, 4, 0, 0, 0], [9; 8]] {
+            let err = Cursor::from_token(&mut table, token).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_cursor_rewind() {
+        let (mut table, path) = open_temp_db("cursor_rewind");
+        build_two_leaf_tree(&mut table, &[1, 2], &[3]);
+
+        let mut cursor = Cursor::table_start(&mut table).unwrap();
+        let mut passes = vec![];
+        for _ in 0..2 {
+            let mut ids = vec![];
+            while let Some(row) = cursor.next_row().unwrap() {
+                ids.push(row.id);
+            }
+            passes.push(ids);
+            cursor.rewind().unwrap();
+        }
+        assert_eq!(passes, vec![vec![1, 2, 3], vec![1, 2, 3]]);
+        assert_eq!((cursor.page_num, cursor.cell_num), (1, 0));
+
+        // a stale cursor is usable again once rewound
+        cursor.table.structure_version += 1;
+        assert!(cursor.advance().is_err());
+        cursor.rewind().unwrap();
+        assert_eq!(cursor.next_row().unwrap(), Some(test_row(1)));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_cursor_skip() {
+        let (mut table, path) = open_temp_db("cursor_skip");
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5]);
+
+        let mut cursor = Cursor::table_start(&mut table).unwrap();
+        cursor.skip(0).unwrap();
+        assert_eq!((cursor.page_num, cursor.cell_num), (1, 0));
+        cursor.skip(1).unwrap();
+        assert_eq!((cursor.page_num, cursor.cell_num), (1, 1));
+        // two cells run off the left leaf and land on the right leaf's first
+        cursor.skip(2).unwrap();
+        assert_eq!((cursor.page_num, cursor.cell_num), (2, 0));
+        assert_eq!(cursor.next_row().unwrap(), Some(test_row(4)));
+        cursor.skip(10).unwrap();
+        assert!(cursor.end_of_table);
+
+        let statement = match prepare_statement("select offset 2") {
+            PrepareResult::Success(statement) => statement,
+            _ => panic!("expected a parsed select"),
+        };
+        match execute_statement(&statement, &mut table).unwrap() {
+            ExecuteResult::Rows(result) => {
+                assert_eq!(result.rows, [3, 4, 5].map(test_row).to_vec())
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            prepare_statement("select offset -1"),
+            PrepareResult::SyntaxError
+        ));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_scan_descending() {
+        let (mut table, path) = open_temp_db("scan_descending");
+        assert!(table.scan_descending().unwrap().is_empty());
+
+        build_two_leaf_tree(&mut table, &[1, 2, 4], &[6, 9]);
+        let expected: Vec<Row> = [9, 6, 4, 2, 1].map(test_row).to_vec();
+        assert_eq!(table.scan_descending().unwrap(), expected);
+
+        let statement = match prepare_statement("select order by id desc") {
+            PrepareResult::Success(statement) => statement,
+            _ => panic!("expected a parsed select"),
+        };
+        match execute_statement(&statement, &mut table).unwrap() {
+            ExecuteResult::Rows(result) => assert_eq!(result.rows, expected),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_read_only_pager() {
+        let (mut table, path) = open_temp_db("read_only");
+        insert_rows(&mut table, &[test_row(1)]);
+        db_close(&mut table).unwrap();
+        drop(table);
+
+        let mut table = db_open_with_pager(Pager::new_read_only(&path).unwrap()).unwrap();
+        assert!(table.pager.is_read_only());
+        assert_eq!(select_all(&mut table), vec![test_row(1)]);
+
+        let statement = "insert 2 bob bob@x.com".parse::<Statement>().unwrap();
+        let err = execute_statement(&statement, &mut table).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(
+            table.seed(3).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            table.truncate_to(0).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        let (mut other, other_path) = open_temp_db("read_only_merge_source");
+        let mut replacement = test_row(1);
+        replacement.email = "replaced@x.com".to_string();
+        insert_rows(&mut other, &[replacement]);
+        assert_eq!(
+            table
+                .merge_from(&mut other, MergeConflict::Replace)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(select_all(&mut table), vec![test_row(1)]);
+        let _ = std::fs::remove_file(other_path);
+        db_close(&mut table).unwrap();
+        drop(table);
+
+        let err = Pager::new_read_only(&format!("{}.missing", path))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), PAGE_SIZE as u64);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_read_only_compact() {
+        let (mut table, path) = open_temp_db("read_only_compact");
+        build_two_leaf_tree(&mut table, &[1, 2], &[3, 4, 5]);
+        db_close(&mut table).unwrap();
+        drop(table);
+
+        // the two leaves could merge, but nothing here may write
+        let mut table = db_open_with_pager(Pager::new_read_only(&path).unwrap()).unwrap();
+        assert_eq!(
+            table.compact().unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        // every page is still in the tree, so there is nothing to move either
+        assert!(!table.compact_step().unwrap());
+        assert_eq!(table.pager.dirty_page_count(), 0);
+        assert_eq!(table.count_pages_by_type().unwrap(), (2, 1));
+        assert_eq!(
+            select_all(&mut table),
+            (1..=5).map(test_row).collect::<Vec<_>>()
+        );
+        // a tree that needs no repair reads fine
+        assert_eq!(table.repair_subtree_sizes().unwrap(), 0);
+        assert_eq!(table.repair_leaf_chain().unwrap(), 0);
+        db_close(&mut table).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            3 * PAGE_SIZE as u64
+        );
+        drop(table);
+
+        // nor a repair, when there is one to make
+        let mut table = db_open(&path).unwrap();
+        set_internal_node_subtree_size(table.pager.get_page(0).unwrap(), 0, 7);
+        db_close(&mut table).unwrap();
+        drop(table);
+        let mut table = db_open_with_pager(Pager::new_read_only(&path).unwrap()).unwrap();
+        assert_eq!(
+            table.repair_subtree_sizes().unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            internal_node_subtree_size(table.pager.page_ref(0).unwrap(), 0),
+            7
+        );
+        drop(table);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_open_rejects_other_format_versions() {
+        let (mut table, path) = open_temp_db("format_version");
+        insert_rows(&mut table, &[test_row(1)]);
+        db_close(&mut table).unwrap();
+        drop(table);
+        let mut file = std::fs::read(&path).unwrap();
+        assert_eq!(
+            file[FORMAT_VERSION_OFFSET..FORMAT_VERSION_OFFSET + FORMAT_VERSION_SIZE],
+            FORMAT_VERSION.to_le_bytes()
+        );
+
+        // a file from before the marker: a valid root, but the version bytes are zero
+        file[FORMAT_VERSION_OFFSET..FORMAT_VERSION_OFFSET + FORMAT_VERSION_SIZE].fill(0);
+        std::fs::write(&path, &file).unwrap();
+        let err = db_open_with_pager(Pager::new_no_sync(&path).unwrap())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("format version 0"), "{}", err);
+        assert_eq!(std::fs::read(&path).unwrap(), file);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_open_rejects_invalid_root() {
+        let path = temp_db_path("invalid_root");
+        std::fs::write(&path, [0u8; PAGE_SIZE]).unwrap();
+
+        let err = db_open_with_pager(Pager::new_no_sync(&path).unwrap())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("valid root header"), "{}", err);
+        // nothing was written back over it
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0u8; PAGE_SIZE]);
+
+        let mut page = [0u8; PAGE_SIZE];
+        page[NODE_TYPE_OFFSET] = 9;
+        page[IS_ROOT_OFFSET] = 1;
+        std::fs::write(&path, page).unwrap();
+        assert!(db_open_with_pager(Pager::new_no_sync(&path).unwrap()).is_err());
+
+        // an empty file is still initialized
+        std::fs::write(&path, []).unwrap();
+        let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        db_close(&mut table).unwrap();
+        drop(table);
+        assert!(db_open_with_pager(Pager::new_no_sync(&path).unwrap()).is_ok());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_same_file_opened_twice() {
+        let (mut table, path) = open_temp_db("opened_twice");
+        insert_rows(&mut table, &[test_row(1)]);
+        db_close(&mut table).unwrap();
+
+        let err = db_open(&path).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+        // a different spelling of the same path is caught too
+        let dir = std::path::Path::new(&path).parent().unwrap();
+        let file_name = std::path::Path::new(&path).file_name().unwrap();
+        let respelled = dir.join(".").join(file_name);
+        let err = db_open(respelled.to_str().unwrap()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+
+        // a pager that drops its handle between uses is still registered
+        let options = DbOptions {
+            file_access: FileAccess::PerTransaction,
+            ..DbOptions::default()
+        };
+        drop(table);
+        let table = db_open_with_options(Pager::new_no_sync(&path).unwrap(), options).unwrap();
+        assert_eq!(
+            db_open(&path).err().unwrap().kind(),
+            ErrorKind::ResourceBusy
+        );
+
+        drop(table);
+        let mut reopened = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        assert!(reopened.contains(1).unwrap());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_estimate_rows() {
+        let (mut table, path) = open_temp_db("estimate_rows");
+        insert_rows(&mut table, &[test_row(1), test_row(2)]);
+        assert_eq!(table.estimate_rows().unwrap(), 2);
+
+        let left: Vec<u32> = (1..=13).collect();
+        let right: Vec<u32> = (14..=25).collect();
+        build_two_leaf_tree(&mut table, &left, &right);
+
+        let exact = table.row_count().unwrap();
+        let estimate = table.estimate_rows().unwrap();
+        assert_eq!(exact, 25);
+        assert!(
+            estimate.abs_diff(exact) * 10 <= exact,
+            "estimate {} vs {}",
+            estimate,
+            exact
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_debug_cell() {
+        let (mut table, path) = open_temp_db("debug_cell");
+        insert_rows(&mut table, &[test_row(3), test_row(1), test_row(2)]);
+
+        for (cell_num, id) in [1, 2, 3].into_iter().enumerate() {
+            assert_eq!(table.debug_cell(0, cell_num).unwrap(), (id, test_row(id)));
+        }
+        assert_eq!(
+            table.debug_cell(0, 3).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            table.debug_cell(4, 0).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        build_two_leaf_tree(&mut table, &[1], &[2]);
+        assert_eq!(table.debug_cell(2, 0).unwrap(), (2, test_row(2)));
+        assert!(table.debug_cell(0, 0).is_err());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_insert_default_email() {
+        let (mut table, path) = open_temp_db("insert_default_email");
+        assert_eq!(column_default("email"), Some(DEFAULT_EMAIL));
+        assert_eq!(column_default("username"), None);
+
+        let statement = match prepare_statement("insert 1 john") {
+            PrepareResult::Success(statement) => statement,
+            _ => panic!("expected a parsed insert"),
+        };
+        execute_statement(&statement, &mut table).unwrap();
+        assert_eq!(
+            table.get(1).unwrap(),
+            Some(Row {
+                id: 1,
+                username: "john".to_string(),
+                email: "unknown@example.com".to_string(),
+            })
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_flush_page_and_flush_all() {
+        let (mut table, path) = open_temp_db("flush_page");
+        insert_rows(&mut table, &[test_row(1)]);
+        table.pager.get_page(1).unwrap();
+        assert!(table.pager.is_dirty(0));
+
+        table.flush_page(0).unwrap();
+        assert!(!table.pager.is_dirty(0));
+        assert!(table.pager.is_dirty(1));
+        let on_disk = std::fs::read(&path).unwrap();
+        assert_eq!(on_disk.len(), PAGE_SIZE);
+        let value = leaf_node_cell_offset(0) + LEAF_NODE_VALUE_OFFSET;
+        assert_eq!(
+            deserialize_row(&on_disk[value..value + ROW_SIZE]),
+            test_row(1)
+        );
+
+        table.flush_all().unwrap();
+        assert!(!table.pager.is_dirty(1));
+        assert_eq!(table.actual_file_size().unwrap(), 2 * PAGE_SIZE as u64);
+
+        assert_eq!(
+            table.flush_page(5).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_cursor_invalidated_by_structural_change() {
+        let (mut table, path) = open_temp_db("cursor_invalidated");
+        build_two_leaf_tree(&mut table, &[1, 3], &[5]);
+
+        let mut cursor = Cursor::table_start(&mut table).unwrap();
+        assert_eq!(cursor.next_row().unwrap(), Some(test_row(1)));
+
+        // an insert that doesn't go through this cursor shifts the cell it points at
+        cursor.table.insert_many(&[test_row(2)]).unwrap();
+        for err in [
+            cursor.value().map(|_| ()).unwrap_err(),
+            cursor.advance().unwrap_err(),
+            cursor.next_row().map(|_| ()).unwrap_err(),
+        ] {
+            assert!(err.to_string().contains("cursor invalidated"));
+        }
+
+        // inserting through the cursor itself keeps it usable
+        let (mut cursor, _) = table_find(&mut table, 4).unwrap();
+        Table::insert_at_cursor(&mut cursor, &test_row(4)).unwrap();
+        assert_eq!(cursor.next_row().unwrap(), Some(test_row(5)));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_scan_forward_from() {
+        let (mut table, path) = open_temp_db("scan_forward_from");
+        build_two_leaf_tree(&mut table, &[1, 3, 5], &[7, 9]);
+
+        let ids = |rows: Vec<Row>| rows.into_iter().map(|row| row.id).collect::<Vec<_>>();
+        assert_eq!(ids(table.scan_forward_from(4, 3).unwrap()), vec![5, 7, 9]);
+        assert_eq!(ids(table.scan_forward_from(6, 10).unwrap()), vec![7, 9]);
+        assert_eq!(ids(table.scan_forward_from(0, 2).unwrap()), vec![1, 3]);
+        assert!(table.scan_forward_from(10, 5).unwrap().is_empty());
+        assert!(table.scan_forward_from(1, 0).unwrap().is_empty());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_insert_sorted_batch() {
+        let (mut table, path) = open_temp_db("insert_sorted_batch");
+        build_two_leaf_tree(&mut table, &[2, 6], &[10, 20]);
+
+        let batch = [15, 1, 4, 12, 3, 30, 5].map(test_row).to_vec();
+        table.insert_sorted_batch(batch).unwrap();
+        assert_eq!(
+            table
+                .key_iter()
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![1, 2, 3, 4, 5, 6, 10, 12, 15, 20, 30]
+        );
+        assert!(table.integrity_check().unwrap().is_empty());
+        assert_eq!(table.get(12).unwrap(), Some(test_row(12)));
+
+        let err = table
+            .insert_sorted_batch(vec![test_row(7), test_row(10)])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(table.contains(7).unwrap());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn test_format_rows_as_inserts() {
+        let row = Row {
+            id: 7,
+            username: "jane".to_string(),
+            email: "jane--work@x.com".to_string(),
+        };
+        let lines = format_rows_as_inserts(std::slice::from_ref(&row));
+        assert_eq!(lines, vec!["insert 7 'jane' 'jane--work@x.com'"]);
+
+        match prepare_statement(&lines[0]) {
+            PrepareResult::Success(statement) => assert_eq!(statement.row_to_insert, Some(row)),
+            _ => panic!("expected the emitted insert to parse"),
+        }
+    }
+
+    #[test]
+    fn test_key_histogram() {
+        let (mut table, path) = open_temp_db("key_histogram");
+        assert_eq!(table.key_histogram(3).unwrap(), vec![0, 0, 0]);
+        assert_eq!(table.first().unwrap(), None);
+
+        // range 1..=20 in four buckets of five keys each
+        build_two_leaf_tree(&mut table, &[1, 2, 3, 4, 6], &[7, 10, 11, 19, 20]);
+        assert_eq!(
+            (table.first().unwrap(), table.last().unwrap()),
+            (Some(1), Some(20))
+        );
+        assert_eq!(table.key_histogram(4).unwrap(), vec![4, 3, 1, 2]);
+        assert_eq!(table.key_histogram(1).unwrap(), vec![10]);
+        assert!(table.key_histogram(0).unwrap().is_empty());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_compact_merges_leaves() {
+        let (mut table, path) = open_temp_db("compact_merge");
+        build_two_leaf_tree(&mut table, &[1, 2], &[3, 4, 5]);
+        db_close(&mut table).unwrap();
+        let stamped = table.last_modified().unwrap();
+        assert!(stamped.is_some());
+
+        assert_eq!(table.compact().unwrap(), 2);
+        assert_eq!(table.count_pages_by_type().unwrap(), (1, 0));
+        // the root took over a child's image but kept the file header
+        assert_eq!(table.last_modified().unwrap(), stamped);
+        let header = table.pager.page_ref(0).unwrap();
+        assert_eq!(
+            header[FORMAT_VERSION_OFFSET..FORMAT_VERSION_OFFSET + FORMAT_VERSION_SIZE],
+            FORMAT_VERSION.to_le_bytes()
+        );
+        assert_eq!(table.pager.num_pages, 1);
+        assert!(table.integrity_check().unwrap().is_empty());
+        assert_eq!(
+            select_all(&mut table),
+            (1..=5).map(test_row).collect::<Vec<_>>()
+        );
+
+        let _ = std::fs::remove_file(path);
+
+        // 7 + 6 cells would fill the page, so they stay apart
+        let (mut table, path) = open_temp_db("compact_no_merge");
+        let left: Vec<u32> = (1..=7).collect();
+        let right: Vec<u32> = (8..=13).collect();
+        build_two_leaf_tree(&mut table, &left, &right);
+        assert_eq!(table.compact().unwrap(), 0);
+        assert_eq!(table.count_pages_by_type().unwrap(), (2, 1));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_compact_keeps_order_when_right_is_larger() {
+        let (mut table, path) = open_temp_db("compact_right_larger");
+        build_two_leaf_tree(&mut table, &[1], &[2, 3, 4]);
+        // a third leaf, so the root survives the merge
+        {
+            let root = table.pager.get_page(0).unwrap();
+            root[INTERNAL_NODE_NUM_KEYS_OFFSET..INTERNAL_NODE_NUM_KEYS_OFFSET + 4]
+                .copy_from_slice(&2u32.to_le_bytes());
+            let cell = internal_node_cell_offset(1);
+            root[cell..cell + 4].copy_from_slice(&2u32.to_le_bytes());
+            root[cell + 4..cell + 8].copy_from_slice(&4u32.to_le_bytes());
+            root[INTERNAL_NODE_RIGHT_CHILD_OFFSET..INTERNAL_NODE_RIGHT_CHILD_OFFSET + 4]
+                .copy_from_slice(&3u32.to_le_bytes());
+            let leaf = table.pager.get_page(3).unwrap();
+            initialize_leaf_node(leaf);
+            leaf[IS_ROOT_OFFSET] = 0;
+            let mut cursor = Cursor {
+                version: table.structure_version,
+                table: &mut table,
+                page_num: 3,
+                cell_num: 0,
+                end_of_table: true,
+                path: vec![],
+            };
+            for id in 5..=14 {
+                Table::insert_at_cursor(&mut cursor, &test_row(id)).unwrap();
+            }
+        }
+        table.repair_subtree_sizes().unwrap();
+
+        assert_eq!(table.compact().unwrap(), 1);
+        assert_eq!(table.count_pages_by_type().unwrap(), (2, 1));
+        assert!(table.integrity_check().unwrap().is_empty());
+        assert_eq!(
+            table
+                .key_iter()
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            (1..=14).collect::<Vec<_>>()
+        );
+        for id in 1..=14 {
+            assert!(table.contains(id).unwrap(), "missing {}", id);
+        }
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_cursor_next_row() {
+        let (mut table, path) = open_temp_db("cursor_next_row");
+        build_two_leaf_tree(&mut table, &[1], &[2, 3]);
+
+        let mut cursor = Cursor::table_start(&mut table).unwrap();
+        for id in 1..=3 {
+            assert_eq!(cursor.next_row().unwrap(), Some(test_row(id)));
+        }
+        assert_eq!(cursor.next_row().unwrap(), None);
+        assert!(cursor.end_of_table);
+        assert_eq!(cursor.next_row().unwrap(), None);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_externally_truncated_file() {
+        let (mut table, path) = open_temp_db("externally_truncated");
+        for page_num in 0..3 {
+            table.pager.get_page(page_num).unwrap();
+        }
+        db_close(&mut table).unwrap();
+        drop(table);
+
+        let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(PAGE_SIZE as u64)
+            .unwrap();
+
+        let err = table.pager.page_ref(2).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("page 2"));
+        assert!(err.to_string().contains("shorter than expected"));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_row_text_matching() {
+        let row = Row {
+            id: 1,
+            username: "alice".to_string(),
+            email: "wonder@land.org".to_string(),
+        };
+
+        assert!(row.matches_prefix("ali"));
+        assert!(row.matches_prefix("wond"));
+        assert!(!row.matches_prefix("land"));
+        assert!(row.matches_suffix(".org"));
+        assert!(row.matches_suffix("ice"));
+        assert!(!row.matches_suffix("ali"));
+        assert!(row.matches_contains("@land"));
+        assert!(row.matches_contains("lic"));
+        assert!(!row.matches_contains("bob"));
+    }
+
+    #[test]
+    fn test_select_where_prefix() {
+        let (mut table, path) = open_temp_db("select_where_prefix");
+        let rows = [
+            Row {
+                id: 1,
+                username: "alice".to_string(),
+                email: "a@x.com".to_string(),
+            },
+            Row {
+                id: 2,
+                username: "bob".to_string(),
+                email: "alice.b@x.com".to_string(),
+            },
+            test_row(3),
+        ];
+        insert_rows(&mut table, &rows);
+
+        let statement = match prepare_statement("select where prefix = 'alice'") {
+            PrepareResult::Success(statement) => statement,
+            _ => panic!("expected a parsed select"),
+        };
+        match execute_statement(&statement, &mut table).unwrap() {
+            ExecuteResult::Rows(result) => assert_eq!(result.rows, rows[..2]),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            prepare_statement("select where suffix = x"),
+            PrepareResult::SyntaxError
+        ));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_insert_idempotent() {
+        let (mut table, path) = open_temp_db("insert_idempotent");
+
+        assert!(matches!(
+            table.insert_idempotent("req-1", &test_row(1)).unwrap(),
+            ExecuteResult::Success
+        ));
+        // the retry picked a different id but carries the same token
+        assert!(matches!(
+            table.insert_idempotent("req-1", &test_row(2)).unwrap(),
+            ExecuteResult::Success
+        ));
+        assert_eq!(table.row_count().unwrap(), 1);
+        assert_eq!(table.idempotency_key_id("req-1"), Some(1));
+
+        assert!(matches!(
+            table.insert_idempotent("req-2", &test_row(1)).unwrap(),
+            ExecuteResult::DuplicateKey
+        ));
+        assert_eq!(table.idempotency_key_id("req-2"), None);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_page_io_point_lookup_vs_scan() {
+        let (mut table, path) = open_temp_db("page_io");
+        build_wide_tree(
+            &mut table,
+            &[
+                vec![1, 2, 3],
+                vec![4, 5, 6],
+                vec![7, 8, 9],
+                vec![10, 11, 12],
+            ],
+        );
+        assert_eq!(table.row_count().unwrap(), 12);
+
+        let mut io_for = |input: &str| {
+            let statement = input.parse::<Statement>().unwrap();
+            let before = table.pager.page_io();
+            execute_statement(&statement, &mut table).unwrap();
+            table.pager.page_io().since(&before)
+        };
+        let lookup = io_for("select where id in (8)");
+        let scan = io_for("select");
+        assert!(
+            lookup.reads * 4 <= scan.reads,
+            "lookup {:?} vs scan {:?}",
+            lookup,
+            scan
+        );
+        assert_eq!((lookup.writes, scan.writes), (0, 0));
+
+        db_close(&mut table).unwrap();
+        assert!(table.pager.page_io().writes >= 5);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_allocated_pages() {
+        let (mut table, path) = open_temp_db("allocated_pages");
+        assert_eq!(table.pager.allocated_pages(), vec![0]);
+
+        table.pager.page_ref(3).unwrap();
+        table.pager.get_page(1).unwrap();
+        assert_eq!(table.pager.allocated_pages(), vec![0, 1, 3]);
+
+        table.pager.truncate(2).unwrap();
+        assert_eq!(table.pager.allocated_pages(), vec![0, 1]);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_pin_page() {
+        let (mut table, path) = open_temp_db("pin_page");
+        build_two_leaf_tree(&mut table, &[1, 2], &[3]);
+
+        {
+            let mut pinned = table.pin_page(1).unwrap();
+            assert_eq!(leaf_node_key(&pinned, 1), 2);
+            assert!(pinned.pager().is_pinned(1));
+            assert!(!pinned.pager().is_pinned(2));
+
+            // other pages stay reachable while the pin is held
+            let right = pinned.pager().page_ref(2).unwrap();
+            assert_eq!(leaf_node_key(right, 0), 3);
+        }
+        assert!(!table.pager.is_pinned(1));
+
+        let err = table.pin_page(TABLE_MAX_PAGES).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_page_slots_grow_on_demand() {
+        let (mut table, path) = open_temp_db("page_slots");
+        assert_eq!(table.pager.pages.len(), 1);
+
+        table.pager.get_page(40).unwrap()[0] = 9;
+        assert_eq!(table.pager.pages.len(), 41);
+        assert_eq!(table.pager.allocated_pages(), vec![0, 40]);
+        assert!(table.pager.is_dirty(40));
+        assert!(!table.pager.is_dirty(90));
+        let err = table.pager.page_ref(TABLE_MAX_PAGES).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        // large then small: truncating keeps the capacity until shrink_to_fit
+        table.pager.truncate(2).unwrap();
+        assert!(table.pager.pages.capacity() >= 41);
+        table.shrink_to_fit();
+        assert_eq!(table.pager.pages.len(), 1);
+        assert!(table.pager.pages.capacity() < 41);
+
+        table.pager.get_page(3).unwrap();
+        assert_eq!(table.pager.allocated_pages(), vec![0, 3]);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_walk_depth_first() {
+        let (mut table, path) = open_temp_db("walk_depth_first");
+        build_two_leaf_tree(&mut table, &[1, 2], &[3, 4, 5]);
+
+        let mut visits = vec![];
+        table
+            .walk_depth_first(|page_num, depth, node_type, keys| {
+                visits.push((page_num, depth, node_type, keys.to_vec()))
+            })
+            .unwrap();
+
+        assert_eq!(
+            visits,
+            vec![
+                (0, 0, NodeType::Internal, vec![2]),
+                (1, 1, NodeType::Leaf, vec![1, 2]),
+                (2, 1, NodeType::Leaf, vec![3, 4, 5]),
+            ]
+        );
+        let max_depth = visits.iter().map(|visit| visit.1).max();
+        assert_eq!((visits.len(), max_depth), (3, Some(1)));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_table_name() {
+        let path = temp_db_path("table_name");
+        let mut table = db_open_named(&path, "users").unwrap();
+        assert_eq!(table.name(), Some("users"));
+        table.set_name("accounts");
+        assert_eq!(table.name(), Some("accounts"));
+        drop(table);
+
+        let (table, other) = open_temp_db("table_unnamed");
+        assert_eq!(table.name(), None);
+
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(other);
+    }
+
+    #[test]
+    fn test_leaf_value_is_one_row() {
+        let mut node = [0u8; PAGE_SIZE];
+        for cell_num in 0..LEAF_NODE_MAX_CELLS as u32 {
+            assert_eq!(leaf_node_value(&mut node, cell_num).len(), ROW_SIZE);
+        }
+        assert_eq!(LEAF_NODE_CELL_SIZE, LEAF_NODE_KEY_SIZE + ROW_SIZE);
+    }
+
+    #[test]
+    #[should_panic(expected = "a row needs")]
+    #[cfg(debug_assertions)]
+    fn test_serialize_row_short_destination() {
+        let mut short = [0u8; ROW_SIZE - 1];
+        let _ = serialize_row(&test_row(1), &mut short);
+    }
+
+    #[test]
+    fn test_parse_row_fields() {
+        assert!(matches!(
+            parse_row_fields("insert 1 'john' john@x.com on conflict"),
+            Ok((1, "john", "john@x.com"))
+        ));
+        assert!(matches!(
+            parse_row_fields("update x john john@x.com"),
+            Err(PrepareResult::SyntaxError)
+        ));
+        assert!(matches!(
+            parse_row_fields(&format!("update 1 {} john@x.com", "a".repeat(33))),
+            Err(PrepareResult::StringTooLong)
+        ));
+        assert!(matches!(
+            parse_row_fields("update 1"),
+            Err(PrepareResult::SyntaxError)
+        ));
+        assert!(matches!(
+            prepare_statement("update 1 john"),
+            PrepareResult::UnrecognizedStatement
+        ));
+
+        let statement = match prepare_statement("update 2 jane jane@x.com") {
+            PrepareResult::Success(statement) => statement,
+            _ => panic!("expected a parsed update"),
+        };
+        assert!(matches!(statement.statement_type, StatementType::Update));
+        assert_eq!(
+            statement.row_to_insert.unwrap(),
+            Row {
+                id: 2,
+                username: "jane".to_string(),
+                email: "jane@x.com".to_string(),
+            }
+        );
+        assert!(matches!(
+            prepare_statement("insert 1 john john@x.com on conflict do nothing"),
+            PrepareResult::UnrecognizedStatement
+        ));
+        assert!(matches!(
+            prepare_statement_with_mode("update 1 john john@x.com now", ParseMode::Strict),
+            PrepareResult::UnexpectedToken(token) if token == "now"
+        ));
+    }
+
+    #[test]
+    fn test_prepare_statement_never_panics() {
+        // regressions: each of these once sat one token short of an index the parser read
+        for input in [
+            "insert",
+            "update",
+            "insert 1",
+            "insert 1 john john@x.com on",
+            "insert 1 john john@x.com on conflict do update set email =",
+            "select sample",
+            "select sample 3 seed",
+            "select where",
+            "select where prefix =",
+            "'--",
+            "insert 1 'jo--hn' -- x",
+            "select where prefix = \u{e9}\u{e9}",
+        ] {
+            let _ = prepare_statement(input);
+            let _ = prepare_statement_with_mode(input, ParseMode::Strict);
+        }
+
+        let vocabulary = [
+            "insert",
+            "update",
+            "select",
+            "sample",
+            "seed",
+            "where",
+            "prefix",
+            "=",
+            "on",
+            "conflict",
+            "do",
+            "set",
+            "email",
+            "order",
+            "by",
+            "id",
+            "desc",
+            "last_insert_id()",
+            "1",
+            "-1",
+            "4294967296",
+            "'",
+            "--",
+            "'a--b'",
+            "\u{e9}",
+            "",
+            " ",
+            "\t",
+        ];
+        let mut state = 0x5eed;
+        for _ in 0..20_000 {
+            let len = splitmix64(&mut state) % 16;
+            let mut input = String::new();
+            for _ in 0..len {
+                let word = vocabulary[splitmix64(&mut state) as usize % vocabulary.len()];
+                // glue tokens together now and then so keywords get mangled too
+                if !splitmix64(&mut state).is_multiple_of(4) {
+                    input.push(' ');
+                }
+                input.push_str(word);
+            }
+            let _ = prepare_statement(&input);
+            let _ = prepare_statement_with_mode(&input, ParseMode::Strict);
+        }
+    }
+
+    #[test]
+    fn test_normalize_strings() {
+        let (mut table, path) = open_temp_db("normalize_strings");
+        let messy = Row {
+            id: 1,
+            username: "alice\n\t".to_string(),
+            email: "a b@x.com \r\u{7f}".to_string(),
+        };
+
+        // off by default: stored as given
+        insert_rows(&mut table, std::slice::from_ref(&messy));
+        assert_eq!(table.get(1).unwrap(), Some(messy.clone()));
+
+        table.normalize_strings = true;
+        insert_rows(
+            &mut table,
+            &[Row {
+                id: 2,
+                ..messy.clone()
+            }],
+        );
+        let stored = table.get(2).unwrap().unwrap();
+        assert_eq!(stored.username, "alice");
+        assert_eq!(stored.email, "a b@x.com");
+
+        let update = Statement {
+            statement_type: StatementType::Update,
+            row_to_insert: Some(messy.clone()),
+            on_conflict: None,
+        };
+        execute_statement(&update, &mut table).unwrap();
+        assert_eq!(table.get(1).unwrap().unwrap().username, "alice");
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_exists_where_stops_early() {
+        let (mut table, path) = open_temp_db("exists_where");
+        insert_rows(&mut table, &(1..=10).map(test_row).collect::<Vec<_>>());
+
+        table.cursor_advances = 0;
+        assert!(table.exists_where(|row| row.id == 3).unwrap());
+        // next_row advances past each row it returns, the match included
+        assert_eq!(table.cursor_advances, 3);
+
+        table.cursor_advances = 0;
+        assert!(
+            !table
+                .exists_where(|row| row.email == "nobody@example.com")
+                .unwrap()
+        );
+        assert_eq!(table.cursor_advances, 10);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let (mut table, path) = open_temp_db("iter_from");
+        build_two_leaf_tree(&mut table, &[1, 3, 5], &[7, 9]);
+        let ids = |table: &mut Table, id| {
+            table
+                .iter_from(id)
+                .unwrap()
+                .map(|row| row.id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids(&mut table, 3), vec![3, 5, 7, 9]);
+        // 6 falls between the two leaves
+        assert_eq!(ids(&mut table, 6), vec![7, 9]);
+        assert_eq!(ids(&mut table, 8), vec![9]);
+        assert!(ids(&mut table, 10).is_empty());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_pages_iter() {
+        let (mut table, path) = open_temp_db("pages_iter");
+        build_two_leaf_tree(&mut table, &[1, 2], &[3]);
+        db_close(&mut table).unwrap();
+        drop(table);
+        let mut table = db_open(&path).unwrap();
+
+        let mut seen = vec![];
+        for page in table.pages_iter() {
+            let (page_num, page) = page.unwrap();
+            seen.push((page_num, node_type_of(page).unwrap()));
+        }
+        assert_eq!(
+            seen,
+            vec![
+                (0, NodeType::Internal),
+                (1, NodeType::Leaf),
+                (2, NodeType::Leaf)
+            ]
+        );
+        assert!((0..3).all(|page_num| table.pager.is_dirty(page_num)));
+
+        // writes go straight into the cached pages
+        for page in table.pages_iter() {
+            let (page_num, page) = page.unwrap();
+            if page_num == 2 {
+                set_leaf_node_num_cells(page, 0);
+            }
+        }
+        assert_eq!(
+            select_all(&mut table)
+                .iter()
+                .map(|row| row.id)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_initialize_internal_node() {
+        // start from garbage so every header field has something to clear
+        let mut node = [0xffu8; PAGE_SIZE];
+        initialize_internal_node(&mut node);
+
+        assert_eq!(node_type_of(&node).unwrap(), NodeType::Internal);
+        assert!(!node_is_root(&node));
+        assert_eq!(
+            node[PARENT_POINTER_OFFSET..PARENT_POINTER_OFFSET + PARENT_POINTER_SIZE],
+            [0; PARENT_POINTER_SIZE]
+        );
+        assert_eq!(internal_node_num_keys(&node), 0);
+        assert_eq!(internal_node_child(&node, 0), 0);
+    }
+
+    #[test]
+    fn test_leaf_node_bsearch() {
+        let mut node = [0u8; PAGE_SIZE];
+        initialize_leaf_node(&mut node);
+        assert_eq!(leaf_node_bsearch(&node, 5), (0, false));
+
+        let keys: [u32; 13] = [2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 22, 24, 26];
+        for (i, &key) in keys.iter().enumerate() {
+            leaf_node_cell(&mut node, i as u32)[..LEAF_NODE_KEY_SIZE]
+                .copy_from_slice(&key.to_le_bytes());
+        }
+        set_leaf_node_num_cells(&mut node, keys.len() as u32);
+
+        for (i, &key) in keys.iter().enumerate() {
+            assert_eq!(leaf_node_bsearch(&node, key), (i, true));
+            assert_eq!(leaf_node_bsearch(&node, key - 1), (i, false));
+        }
+        assert_eq!(leaf_node_bsearch(&node, 27), (keys.len(), false));
+    }
+
+    #[test]
+    fn test_repair_leaf_chain() {
+        let (mut table, path) = open_temp_db("repair_leaf_chain");
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5]);
+        assert_eq!(table.repair_leaf_chain().unwrap(), 0);
+
+        // swap the root's children so a scan visits the right leaf first
+        let root = table.pager.get_page(0).unwrap();
+        set_internal_node_child(root, 0, 2);
+        set_internal_node_child(root, 1, 1);
+        let ids = |table: &mut Table| {
+            select_all(table)
+                .iter()
+                .map(|row| row.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&mut table), vec![4, 5, 1, 2, 3]);
+
+        assert_eq!(table.repair_leaf_chain().unwrap(), 1);
+        assert_eq!(ids(&mut table), vec![1, 2, 3, 4, 5]);
+        assert_eq!(table.get(3).unwrap(), Some(test_row(3)));
+        assert_eq!(table.get(4).unwrap(), Some(test_row(4)));
+        assert!(table.integrity_check().unwrap().is_empty());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_insert_stats_flag_the_overflowing_insert() {
+        let (mut table, path) = open_temp_db("insert_stats");
+        let insert = |table: &mut Table, id| {
+            let statement = format!("insert {} user{} person{}@example.com", id, id, id)
+                .parse::<Statement>()
+                .unwrap();
+            execute_statement(&statement, table).unwrap();
+            table.last_insert_stats()
+        };
+
+        // nothing is recorded unless debug is on
+        insert(&mut table, 1);
+        assert_eq!(table.last_insert_stats(), None);
+
+        table.debug = true;
+        for id in 2..LEAF_NODE_MAX_CELLS as u32 {
+            insert(&mut table, id);
+        }
+        table.flush_all().unwrap();
+        assert_eq!(table.pager.dirty_page_count(), 0);
+
+        // the last cell that fits dirties just its leaf
+        let fits = insert(&mut table, LEAF_NODE_MAX_CELLS as u32).unwrap();
+        assert!(!fits.needed_split);
+        assert_eq!(fits.pages_dirtied, 1);
+
+        // the one that overflows is flagged, refused, and writes nothing
+        let overflows = insert(&mut table, LEAF_NODE_MAX_CELLS as u32 + 1).unwrap();
+        assert!(overflows.needed_split);
+        assert_eq!(overflows.pages_dirtied, 0);
+        assert_eq!(table.row_count().unwrap(), LEAF_NODE_MAX_CELLS as u64);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_pager_evict() {
+        let (mut table, path) = open_temp_db("pager_evict");
+        insert_rows(&mut table, &[test_row(1), test_row(2)]);
+        assert!(table.pager.is_dirty(0));
+
+        table.pager.evict(0).unwrap();
+        assert!(!table.pager.is_dirty(0));
+        assert!(table.pager.allocated_pages().is_empty());
 
-    cell[..LEAF_NODE_KEY_SIZE].copy_from_slice(&key.to_le_bytes());
-    serialize_row(value, &mut cell[LEAF_NODE_VALUE_OFFSET..]);
+        // written back before it was dropped, so reading it again goes to disk
+        let misses = table.pager.cache_misses;
+        assert_eq!(select_all(&mut table), vec![test_row(1), test_row(2)]);
+        assert_eq!(table.pager.cache_misses, misses + 1);
 
-    set_leaf_node_num_cells(page, num_cells + 1);
+        // a page that isn't cached is already evicted
+        table.pager.evict(5).unwrap();
 
-    Ok(())
-}
+        let mut pinned = table.pin_page(0).unwrap();
+        assert_eq!(
+            pinned.pager().evict(0).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert_eq!(node_type_of(&pinned).unwrap(), NodeType::Leaf);
+        drop(pinned);
+        table.pager.evict(0).unwrap();
 
-// --- debug meta commands ---
+        let _ = std::fs::remove_file(path);
+    }
 
-pub fn print_constants() {
-    println!("ROW_SIZE: {}", ROW_SIZE);
-    println!("LEAF_NODE_HEADER_SIZE: {}", LEAF_NODE_HEADER_SIZE);
-    println!("LEAF_NODE_CELL_SIZE: {}", LEAF_NODE_CELL_SIZE);
-    println!("PAGE_SIZE: {}", PAGE_SIZE);
-    println!(
-        "LEAF_NODE_SPACE_FOR_CELLS: {}",
-        PAGE_SIZE - LEAF_NODE_HEADER_SIZE
-    );
-    println!("LEAF_NODE_MAX_CELLS: {}", LEAF_NODE_MAX_CELLS);
-}
+    #[test]
+    fn test_pager_evict_read_only() {
+        let (mut table, path) = open_temp_db("pager_evict_read_only");
+        insert_rows(&mut table, &[test_row(1), test_row(2)]);
+        db_close(&mut table).unwrap();
+        drop(table);
 
-pub fn print_btree(table: &mut Table) -> Result<()> {
-    let root = table.pager.get_page(table.root_page_num)?;
-    print_leaf_node(root);
-    Ok(())
-}
+        let mut table = db_open_with_pager(Pager::new_read_only(&path).unwrap()).unwrap();
+        assert_eq!(select_all(&mut table).len(), 2);
+        // only read, so there is nothing to write back on the way out
+        table.pager.evict(0).unwrap();
+        assert!(table.pager.allocated_pages().is_empty());
+        assert_eq!(table.get(2).unwrap(), Some(test_row(2)));
+        table.flush_page(0).unwrap();
+        drop(table);
 
-fn print_leaf_node(node: &mut [u8; PAGE_SIZE]) {
-    let num_cells = leaf_node_num_cells(node);
-    println!("*---*");
-    for i in 0..num_cells {
-        let key = leaf_node_key(node, i);
-        println!("  - {}: {}", i, key);
+        let _ = std::fs::remove_file(path);
     }
-    println!("*---*");
-}
 
-#[derive(Debug)]
-pub enum StatementType {
-    Insert,
-    Select,
-}
+    #[test]
+    fn test_with_temp() {
+        let (mut table, guard) = Table::with_temp().unwrap();
+        let (_, other) = Table::with_temp().unwrap();
+        assert_ne!(guard.path(), other.path());
 
-#[derive(Debug)]
-pub struct Statement {
-    pub statement_type: StatementType,
-    pub row_to_insert: Option<Row>,
-}
+        insert_rows(&mut table, &[test_row(1)]);
+        db_close(&mut table).unwrap();
+        let path = guard.path().to_path_buf();
+        assert!(path.exists());
 
-pub enum PrepareResult {
-    Success(Statement),
-    UnrecognizedStatement,
-    SyntaxError,
-    StringTooLong,
-    NegativeId,
-}
+        drop(table);
+        drop(guard);
+        assert!(!path.exists());
+    }
 
-pub enum ExecuteResult {
-    Success,
-}
+    #[test]
+    fn test_watch_page() {
+        let (mut table, path) = open_temp_db("watch_page");
+        let seen = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let recorder = seen.clone();
+        table.watch_page(0, move |page| {
+            recorder.borrow_mut().push(leaf_node_num_cells(page))
+        });
+        table.watch_page(1, |_| panic!("page 1 is never written"));
 
-pub enum MetaCommandResult {
-    Exit,
-    PrintConstants,
-    PrintBtree,
-    UnrecognizedCommand,
-}
+        insert_rows(&mut table, &[test_row(1), test_row(2)]);
+        assert!(seen.borrow().is_empty());
+        table.flush_all().unwrap();
+        assert_eq!(*seen.borrow(), vec![2]);
 
-pub fn do_meta_command(input: &str) -> MetaCommandResult {
-    match input {
-        ".exit" => MetaCommandResult::Exit,
-        ".constants" => MetaCommandResult::PrintConstants,
-        ".btree" => MetaCommandResult::PrintBtree,
-        _ => MetaCommandResult::UnrecognizedCommand,
+        // a clean page isn't written again
+        table.flush_all().unwrap();
+        insert_rows(&mut table, &[test_row(3)]);
+        table.flush_page(0).unwrap();
+        assert_eq!(*seen.borrow(), vec![2, 3]);
+
+        // nor is one that was only read
+        let select = "select".parse::<Statement>().unwrap();
+        execute_statement(&select, &mut table).unwrap();
+        assert_eq!(table.get(3).unwrap(), Some(test_row(3)));
+        table.flush_all().unwrap();
+        assert_eq!(*seen.borrow(), vec![2, 3]);
+
+        let _ = std::fs::remove_file(path);
     }
-}
 
-pub fn prepare_statement(input: &str) -> PrepareResult {
-    if input.starts_with("select") {
-        PrepareResult::Success(Statement {
-            statement_type: StatementType::Select,
-            row_to_insert: None,
-        })
-    } else if input.starts_with("insert") {
-        let parts = input.split_whitespace().collect::<Vec<_>>();
+    #[test]
+    fn test_interior_nul_round_trip() {
+        let row = Row {
+            id: 1,
+            username: "ab\0cd".to_string(),
+            email: "\0x@example.com\0".to_string(),
+        };
+        let mut buf = [0u8; ROW_SIZE];
+        serialize_row(&row, &mut buf).unwrap();
+        assert_eq!(deserialize_row(&buf), row);
+        assert!(is_valid_serialized_row(&buf));
+
+        // a field wider than its length byte can count holds u8::MAX bytes, not a wrapped length
+        let mut field = [0u8; FIELD_LEN_SIZE + 300];
+        write_field(&mut field, 0, 300, &"x".repeat(300));
+        assert_eq!(field[0], u8::MAX);
+        assert_eq!(read_field(&field, 0, 300).unwrap().len(), u8::MAX as usize);
+
+        let (mut table, path) = open_temp_db("interior_nul");
+        insert_rows(&mut table, std::slice::from_ref(&row));
+        db_close(&mut table).unwrap();
+        drop(table);
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(table.get(1).unwrap(), Some(row));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_flush_logs_every_dirty_page_before_writing_any() {
+        let (mut table, path) = open_temp_db("wal_batch");
+        build_two_leaf_tree(&mut table, &[1, 2], &[3, 4]);
+        assert_eq!(table.write_order().unwrap(), vec![1, 2, 0]);
+
+        // the first page to reach the main file finds all three already in the wal
+        let wal_len = std::rc::Rc::new(std::cell::Cell::new(0));
+        let seen = std::rc::Rc::clone(&wal_len);
+        let wal_path = format!("{}.wal", path);
+        table.watch_page(1, move |_| {
+            seen.set(std::fs::metadata(&wal_path).unwrap().len());
+        });
+        table.flush_all().unwrap();
+        assert_eq!(wal_len.get(), 3 * WAL_ENTRY_SIZE as u64);
+        assert!(!std::path::Path::new(&format!("{}.wal", path)).exists());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_write_ahead_append() {
+        let path = temp_db_path("write_ahead_append");
+        let wal_path = format!("{}.wal", path);
+
+        let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        insert_rows(&mut table, &[test_row(1)]);
+        table.flush_all().unwrap();
+        // a finished flush leaves no wal behind
+        assert!(!std::path::Path::new(&wal_path).exists());
+
+        // the writer logs a newer image of page 0, then dies before writing it
+        insert_rows(&mut table, &[test_row(2)]);
+        let image = *table.pager.page_ref(0).unwrap();
+        table.write_ahead_append(0, &image).unwrap();
+        assert_eq!(
+            table
+                .write_ahead_append(TABLE_MAX_PAGES, &image)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            std::fs::metadata(&wal_path).unwrap().len(),
+            WAL_ENTRY_SIZE as u64
+        );
+        drop(table);
+
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(select_all(&mut table), vec![test_row(1), test_row(2)]);
+        assert!(!std::path::Path::new(&wal_path).exists());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let (mut table, path) = open_temp_db("last_modified");
+        assert_eq!(table.last_modified().unwrap(), None);
+        insert_rows(&mut table, &[test_row(1)]);
+        db_close(&mut table).unwrap();
+        drop(table);
+
+        let mut table = db_open(&path).unwrap();
+        let first = table.last_modified().unwrap().unwrap();
+        // a read-only session leaves it alone
+        select_all(&mut table);
+        db_close(&mut table).unwrap();
+        drop(table);
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(table.last_modified().unwrap(), Some(first));
+
+        insert_rows(&mut table, &[test_row(2)]);
+        db_close(&mut table).unwrap();
+        drop(table);
+        let mut table = db_open(&path).unwrap();
+        assert!(table.last_modified().unwrap().unwrap() > first);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_clear() {
+        let (mut table, path) = open_temp_db("clear");
+        build_two_leaf_tree(
+            &mut table,
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+            &[11, 12, 13, 14, 15],
+        );
+        db_close(&mut table).unwrap();
+        assert_eq!(table.actual_file_size().unwrap(), 3 * PAGE_SIZE as u64);
+        assert_eq!(table.get(12).unwrap(), Some(test_row(12)));
+
+        // the empty root reaches the file while the old pages are still there
+        let len_at_root_write = std::rc::Rc::new(std::cell::Cell::new(0));
+        let seen = std::rc::Rc::clone(&len_at_root_write);
+        let watched = path.clone();
+        table.watch_page(0, move |_| {
+            seen.set(std::fs::metadata(&watched).unwrap().len());
+        });
+        table.clear().unwrap();
+        assert_eq!(len_at_root_write.get(), 3 * PAGE_SIZE as u64);
+        let on_disk: [u8; PAGE_SIZE] = std::fs::read(&path).unwrap()[..].try_into().unwrap();
+        assert_eq!(node_type_of(&on_disk).unwrap(), NodeType::Leaf);
+        assert_eq!(leaf_node_num_cells(&on_disk), 0);
+        assert!(select_all(&mut table).is_empty());
+        assert_eq!(table.get(12).unwrap(), None);
+        assert_eq!(table.actual_file_size().unwrap(), PAGE_SIZE as u64);
+        db_close(&mut table).unwrap();
+        drop(table);
+
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(table.row_count().unwrap(), 0);
+        assert_eq!(table.actual_file_size().unwrap(), PAGE_SIZE as u64);
+        assert!(table.integrity_check().unwrap().is_empty());
+        insert_rows(&mut table, &[test_row(1)]);
+        assert_eq!(select_all(&mut table), vec![test_row(1)]);
 
-        if parts.len() != 4 {
-            return PrepareResult::UnrecognizedStatement;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_with_transaction() {
+        let (mut table, path) = open_temp_db("with_transaction");
+        insert_rows(&mut table, &[test_row(1)]);
+        table.flush_all().unwrap();
+        let on_disk = || std::fs::read(&path).unwrap();
+        let before = on_disk();
+
+        let result: Result<()> = table.with_transaction(|txn| {
+            txn.execute(&"insert 2 bob bob@example.com".parse().unwrap())?;
+            txn.execute(&"update 1 alice alice@example.com".parse().unwrap())?;
+            assert_eq!(txn.row_count()?, 2);
+            assert_eq!(txn.get(1)?.unwrap().username, "alice");
+            Err(Error::other("abort"))
+        });
+        assert_eq!(result.unwrap_err().to_string(), "abort");
+        assert_eq!(select_all(&mut table), vec![test_row(1)]);
+        assert_eq!(table.get(1).unwrap(), Some(test_row(1)));
+        assert_eq!(on_disk(), before);
+
+        let inserted = table
+            .with_transaction(|txn| {
+                txn.execute(&"insert 2 bob bob@example.com".parse().unwrap())?;
+                txn.execute(&"insert 3 carol carol@example.com".parse().unwrap())?;
+                txn.row_count()
+            })
+            .unwrap();
+        assert_eq!(inserted, 3);
+        assert_eq!(table.pager.dirty_page_count(), 0);
+
+        // a panic rolls back too, and leaves the table usable afterwards
+        let ratio = table.auto_compact_ratio;
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _: Result<()> = table.with_transaction(|txn| {
+                txn.execute(&"insert 4 dave dave@example.com".parse().unwrap())?;
+                panic!("boom");
+            });
+        }));
+        assert!(panicked.is_err());
+        assert!(table.pager.shadow.is_none());
+        assert_eq!(table.auto_compact_ratio, ratio);
+        assert_eq!(table.get(4).unwrap(), None);
+        assert_eq!(table.pager.dirty_page_count(), 0);
+        drop(table);
+
+        let mut table = db_open(&path).unwrap();
+        let ids: Vec<u32> = select_all(&mut table).iter().map(|row| row.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_cost_estimate() {
+        let (mut table, path) = open_temp_db("cost_estimate");
+        let leaves: Vec<Vec<u32>> = (0..20)
+            .map(|leaf| (leaf * 5 + 1..=leaf * 5 + 5).collect())
+            .collect();
+        build_wide_tree(&mut table, &leaves);
+        let mut cost = |input: &str| {
+            input
+                .parse::<Statement>()
+                .unwrap()
+                .cost_estimate(&mut table)
+                .unwrap()
+        };
+
+        let lookup = cost("select where id in (42)");
+        let scan = cost("select");
+        assert_eq!((lookup, scan), (2, 20));
+        assert!(lookup * 5 <= scan);
+
+        // a range is priced by the share of keys it covers
+        assert_eq!(cost("select where id > 90"), 3);
+        assert_eq!(cost("select where id < 26"), 6);
+        assert_eq!(cost("select where id < 0"), 2);
+        assert_eq!(cost("select where id > 100"), 2);
+        assert_eq!(cost("explain analyze select"), scan);
+        assert_eq!(cost("insert 101 a a@example.com"), 2);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_bad_length_file_is_not_left_registered() {
+        let path = temp_db_path("bad_length");
+        std::fs::write(&path, [0u8; 100]).unwrap();
+
+        // both opens get the real error; a leaked registry entry would turn the second
+        // into "already open"
+        for _ in 0..2 {
+            assert_eq!(
+                Pager::new(&path).err().unwrap().kind(),
+                ErrorKind::InvalidData
+            );
         }
 
-        let id = match parts[1].parse::<u32>() {
-            Ok(id) => id,
-            Err(_) => return PrepareResult::SyntaxError,
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_import_fixed_sorts_and_skips_duplicates() {
+        let (mut table, path) = open_temp_db("import_fixed_unordered");
+        insert_rows(&mut table, &[test_row(2)]);
+        let dump_path = temp_db_path("import_fixed_unordered_dump");
+        let write_dump = |ids: &[u32]| {
+            let mut dump = vec![];
+            for &id in ids {
+                let mut record = [0u8; ROW_SIZE];
+                serialize_row(&test_row(id), &mut record).unwrap();
+                dump.extend_from_slice(&record);
+            }
+            std::fs::write(&dump_path, dump).unwrap();
         };
 
-        if parts[2].len() > COLUMN_USERNAME_SIZE || parts[3].len() > COLUMN_EMAIL_SIZE {
-            return PrepareResult::StringTooLong;
+        // 2 is already in the table, the second 5 repeats the first
+        write_dump(&[5, 1, 2, 3, 5]);
+        assert_eq!(table.import_fixed(&dump_path).unwrap(), (3, 2));
+        let ids: Vec<u32> = select_all(&mut table).iter().map(|row| row.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 5]);
+        for id in [1, 3, 5] {
+            assert_eq!(table.get(id).unwrap(), Some(test_row(id)));
         }
 
-        let row = Row {
-            id,
-            username: parts[2].to_string(),
-            email: parts[3].to_string(),
+        // ten more don't fit in the single leaf: nothing is written
+        write_dump(&(10..20).collect::<Vec<_>>());
+        assert!(table.import_fixed(&dump_path).is_err());
+        assert_eq!(table.row_count().unwrap(), 4);
+
+        let _ = std::fs::remove_file(dump_path);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_row_builder() {
+        let row = Row::builder()
+            .id(1)
+            .username("john")
+            .unwrap()
+            .email("john@test.com")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            row,
+            Row {
+                id: 1,
+                username: "john".to_string(),
+                email: "john@test.com".to_string(),
+            }
+        );
+
+        let err = Row::builder()
+            .username(&"a".repeat(COLUMN_USERNAME_SIZE + 1))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let err = Row::builder()
+            .username("john")
+            .unwrap()
+            .email("john@test.com")
+            .unwrap()
+            .build()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "missing id");
+    }
+
+    #[test]
+    fn test_strip_comment() {
+        assert_eq!(
+            strip_comment("insert 1 john john@x.com -- new user"),
+            "insert 1 john john@x.com"
+        );
+        assert_eq!(
+            strip_comment("insert 1 john 'john--x@x.com'"),
+            "insert 1 john 'john--x@x.com'"
+        );
+        assert_eq!(strip_comment("-- just a note"), "");
+
+        let statement = match prepare_statement("insert 1 john 'a--b@x.com' -- quoted") {
+            PrepareResult::Success(statement) => statement,
+            _ => panic!("expected a parsed insert"),
         };
+        assert_eq!(statement.row_to_insert.unwrap().email, "a--b@x.com");
+    }
 
-        PrepareResult::Success(Statement {
-            statement_type: StatementType::Insert,
-            row_to_insert: Some(row),
-        })
-    } else {
-        PrepareResult::UnrecognizedStatement
+    #[test]
+    fn test_parse_statement() {
+        let statement: Statement = "insert 1 alice a@b.com".parse().unwrap();
+        assert!(matches!(statement.statement_type, StatementType::Insert));
+        assert_eq!(statement.row_to_insert.unwrap().username, "alice");
+
+        assert_eq!(
+            "update 1".parse::<Statement>().unwrap_err(),
+            PrepareError::UnrecognizedStatement
+        );
+        assert_eq!(
+            "insert x alice a@b.com".parse::<Statement>().unwrap_err(),
+            PrepareError::SyntaxError
+        );
+        assert!(matches!(
+            PrepareResult::from("select"),
+            PrepareResult::Success(_)
+        ));
     }
-}
 
-pub fn execute_statement(statement: &Statement, table: &mut Table) -> Result<ExecuteResult> {
-    match statement.statement_type {
-        StatementType::Insert => {
-            let num_cells = {
-                let page = table.pager.get_page(table.root_page_num)?;
-                leaf_node_num_cells(page)
+    #[test]
+    fn test_auto_compact() {
+        let path = temp_db_path("auto_compact");
+        let options = DbOptions {
+            auto_compact_ratio: 0.4,
+            ..DbOptions::default()
+        };
+        let mut table = db_open_with_options(Pager::new_no_sync(&path).unwrap(), options).unwrap();
+        build_two_leaf_tree(&mut table, &[1, 2], &[3, 4]);
+        db_close(&mut table).unwrap();
+        assert_eq!(table.wasted_space_ratio().unwrap(), 0.0);
+
+        // orphan pages 2-4 by moving the right leaf out to page 5
+        let right_leaf = *table.pager.get_page(2).unwrap();
+        *table.pager.get_page(5).unwrap() = right_leaf;
+        set_internal_node_child(table.pager.get_page(0).unwrap(), 1, 5);
+        assert_eq!(table.explain_free_list().unwrap(), vec![2, 3, 4]);
+        assert_eq!(table.wasted_space_ratio().unwrap(), 0.5);
+
+        db_close(&mut table).unwrap();
+        assert_eq!(table.actual_file_size().unwrap(), 3 * PAGE_SIZE as u64);
+        assert_eq!(table.wasted_space_ratio().unwrap(), 0.0);
+        assert_eq!(
+            table
+                .key_iter()
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![1, 2, 3, 4]
+        );
+
+        // a compaction that fails doesn't take the insert that triggered it down too
+        table.auto_compact_ratio = 0.4;
+        table.pager.get_page(2).unwrap()[NODE_TYPE_OFFSET] = 7;
+        table.mutations_since_compact_check = AUTO_COMPACT_INTERVAL - 1;
+        let insert = "insert 0 user0 person0@example.com"
+            .parse::<Statement>()
+            .unwrap();
+        assert!(matches!(
+            execute_statement(&insert, &mut table).unwrap(),
+            ExecuteResult::Success
+        ));
+        assert_eq!(table.get(0).unwrap(), Some(test_row(0)));
+        assert_eq!(table.mutations_since_compact_check, AUTO_COMPACT_INTERVAL);
+        assert_eq!(
+            db_close(&mut table).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        table.pager.get_page(2).unwrap()[NODE_TYPE_OFFSET] = NodeType::Leaf as u8;
+        db_close(&mut table).unwrap();
+
+        // off by default: the same damage survives a close
+        drop(table);
+        let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        let right_leaf = *table.pager.get_page(2).unwrap();
+        *table.pager.get_page(5).unwrap() = right_leaf;
+        set_internal_node_child(table.pager.get_page(0).unwrap(), 1, 5);
+        db_close(&mut table).unwrap();
+        assert_eq!(table.actual_file_size().unwrap(), 6 * PAGE_SIZE as u64);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_compact_step() {
+        let (mut table, path) = open_temp_db("compact_step");
+        build_two_leaf_tree(&mut table, &[1, 2], &[3, 4]);
+
+        // move the right leaf out to page 5, leaving pages 2-4 unreachable
+        let right_leaf = *table.pager.get_page(2).unwrap();
+        *table.pager.get_page(5).unwrap() = right_leaf;
+        set_internal_node_child(table.pager.get_page(0).unwrap(), 1, 5);
+        for page_num in [3, 4] {
+            initialize_leaf_node(table.pager.get_page(page_num).unwrap());
+        }
+        db_close(&mut table).unwrap();
+        assert_eq!(table.actual_file_size().unwrap(), 6 * PAGE_SIZE as u64);
+
+        let expected: Vec<u32> = (1..=4).collect();
+        let mut steps = 0;
+        while table.compact_step().unwrap() {
+            steps += 1;
+            assert_eq!(
+                table
+                    .key_iter()
+                    .unwrap()
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap(),
+                expected
+            );
+            assert_eq!(table.get(4).unwrap(), Some(test_row(4)));
+        }
+
+        // 5 moves into 2, then 4 and 3 are dropped
+        assert_eq!(steps, 3);
+        assert_eq!(table.count_pages_by_type().unwrap(), (2, 1));
+        assert_eq!(table.integrity_check().unwrap(), vec![]);
+        assert_eq!(table.actual_file_size().unwrap(), 3 * PAGE_SIZE as u64);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_total_cells() {
+        let (mut table, path) = open_temp_db("total_cells");
+        assert_eq!(
+            Cursor::table_start(&mut table)
+                .unwrap()
+                .total_cells()
+                .unwrap(),
+            0
+        );
+
+        insert_rows(&mut table, &[test_row(1), test_row(2)]);
+        assert_eq!(table.row_count().unwrap(), 2);
+
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5]);
+        let mut cursor = Cursor::table_end(&mut table).unwrap();
+        assert_eq!(cursor.total_cells().unwrap(), 5);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_page_ref_does_not_dirty() {
+        let path = temp_db_path("page_ref");
+        let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        insert_rows(&mut table, &[test_row(1), test_row(2)]);
+        db_close(&mut table).unwrap();
+        assert!(!table.pager.is_dirty(0));
+
+        let node = table.pager.page_ref(0).unwrap();
+        let keys: Vec<u32> = (0..leaf_node_num_cells(node))
+            .map(|i| leaf_node_key(node, i))
+            .collect();
+        assert_eq!(keys, vec![1, 2]);
+        assert!(!table.pager.is_dirty(0));
+
+        table.pager.get_page(0).unwrap();
+        assert!(table.pager.is_dirty(0));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_reads_leave_pages_clean() {
+        let (mut table, path) = open_temp_db("reads_clean");
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[4, 5]);
+        db_close(&mut table).unwrap();
+        assert_eq!(table.pager.dirty_page_count(), 0);
+
+        let select = "select".parse::<Statement>().unwrap();
+        match execute_statement(&select, &mut table).unwrap() {
+            ExecuteResult::Rows(result) => assert_eq!(result.rows.len(), 5),
+            other => panic!("expected rows, got {:?}", other),
+        }
+        assert_eq!(select_all(&mut table).len(), 5);
+        assert_eq!(table.get(4).unwrap(), Some(test_row(4)));
+        assert!(!table.contains(6).unwrap());
+        assert_eq!(table.key_iter().unwrap().count(), 5);
+        assert_eq!(table.pager.dirty_page_count(), 0);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_explain_leaf() {
+        let (mut table, path) = open_temp_db("explain_leaf");
+        build_two_leaf_tree(&mut table, &[1, 2], &[5]);
+
+        assert_eq!(
+            table.explain_leaf(1).unwrap(),
+            format!(
+                "page 1 | leaf | cells: 2/{} | prev: none | next: 2\n\
+                 \x20 cell 0: key=1 username=user1 email=person1@example.com\n\
+                 \x20 cell 1: key=2 username=user2 email=person2@example.com\n",
+                LEAF_NODE_MAX_CELLS
+            )
+        );
+        assert!(
+            table
+                .explain_leaf(2)
+                .unwrap()
+                .starts_with("page 2 | leaf | cells: 1/13 | prev: 1 | next: none\n")
+        );
+        assert_eq!(
+            table.explain_leaf(0).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert!(table.explain_leaf(3).is_err());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_cursor_walks_a_two_level_tree_by_its_path() {
+        let (mut table, path) = open_temp_db("cursor_two_level");
+        // root 0 over internal nodes 1 and 2, over leaves 3-4 and 5-6
+        let leaves: [(usize, &[u32]); 4] = [(3, &[1, 2]), (4, &[3]), (5, &[]), (6, &[7, 8])];
+        for (page_num, ids) in leaves {
+            let leaf = table.pager.get_page(page_num).unwrap();
+            initialize_leaf_node(leaf);
+            leaf[IS_ROOT_OFFSET] = 0;
+            let mut cursor = Cursor {
+                version: table.structure_version,
+                table: &mut table,
+                page_num,
+                cell_num: 0,
+                end_of_table: true,
+                path: vec![],
             };
+            for &id in ids {
+                Table::insert_at_cursor(&mut cursor, &test_row(id)).unwrap();
+            }
+        }
+        for (page_num, left, right, separator) in [(0, 1, 2, 3), (1, 3, 4, 2), (2, 5, 6, 3)] {
+            let node = table.pager.get_page(page_num).unwrap();
+            initialize_internal_node(node);
+            node[IS_ROOT_OFFSET] = (page_num == 0) as u8;
+            node[INTERNAL_NODE_NUM_KEYS_OFFSET..INTERNAL_NODE_NUM_KEYS_OFFSET + 4]
+                .copy_from_slice(&1u32.to_le_bytes());
+            node[INTERNAL_NODE_RIGHT_CHILD_OFFSET..INTERNAL_NODE_RIGHT_CHILD_OFFSET + 4]
+                .copy_from_slice(&(right as u32).to_le_bytes());
+            let cell = internal_node_cell_offset(0);
+            node[cell..cell + 4].copy_from_slice(&(left as u32).to_le_bytes());
+            node[cell + 4..cell + 8].copy_from_slice(&(separator as u32).to_le_bytes());
+        }
+        table.repair_subtree_sizes().unwrap();
+        assert!(table.integrity_check().unwrap().is_empty());
+
+        let cursor = Cursor::table_start(&mut table).unwrap();
+        assert_eq!(
+            (cursor.page_num, cursor.path.clone()),
+            (3, vec![(0, 0), (1, 0)])
+        );
+        let cursor = Cursor::table_end(&mut table).unwrap();
+        assert_eq!(
+            (cursor.page_num, cursor.path.clone()),
+            (6, vec![(0, 1), (2, 1)])
+        );
+
+        let ids = |rows: Vec<Row>| rows.iter().map(|row| row.id).collect::<Vec<_>>();
+        assert_eq!(ids(select_all(&mut table)), vec![1, 2, 3, 7, 8]);
+        assert_eq!(ids(table.scan_descending().unwrap()), vec![8, 7, 3, 2, 1]);
+
+        // a token carries no path, so the cursor rebuilds it before crossing leaf 5
+        let token = {
+            let (cursor, _) = table_find(&mut table, 3).unwrap();
+            cursor.to_token()
+        };
+        let mut cursor = Cursor::from_token(&mut table, token).unwrap();
+        assert!(cursor.path.is_empty());
+        cursor.advance().unwrap();
+        assert_eq!(
+            (cursor.page_num, cursor.path.clone()),
+            (6, vec![(0, 1), (2, 1)])
+        );
+        assert_eq!(cursor.next_row().unwrap(), Some(test_row(7)));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_cursor_crosses_emptied_leaves() {
+        let (mut table, path) = open_temp_db("cursor_emptied_leaves");
+        build_two_leaf_tree(&mut table, &[1, 2], &[5, 6]);
+
+        // an empty leaf in the middle of the chain
+        {
+            let root = table.pager.get_page(0).unwrap();
+            root[INTERNAL_NODE_NUM_KEYS_OFFSET..INTERNAL_NODE_NUM_KEYS_OFFSET + 4]
+                .copy_from_slice(&2u32.to_le_bytes());
+            let cell = internal_node_cell_offset(1);
+            root[cell..cell + 4].copy_from_slice(&3u32.to_le_bytes());
+            root[cell + 4..cell + 8].copy_from_slice(&4u32.to_le_bytes());
+            let leaf = table.pager.get_page(3).unwrap();
+            initialize_leaf_node(leaf);
+            leaf[IS_ROOT_OFFSET] = 0;
+        }
 
-            if num_cells >= LEAF_NODE_MAX_CELLS as u32 {
-                println!("Error: leaf node full.");
-                return Ok(ExecuteResult::Success);
+        let mut seen = vec![];
+        let mut cursor = Cursor::table_start(&mut table).unwrap();
+        while !cursor.end_of_table {
+            let row = deserialize_row(cursor.value().unwrap());
+            seen.push(row.id);
+            if row.id == 1 {
+                // delete everything in the leaf under the cursor, including the current row
+                set_leaf_node_num_cells(cursor.table.pager.get_page(1).unwrap(), 0);
             }
+            cursor.advance().unwrap();
+        }
+        assert!(cursor.value().is_err());
 
-            let row = statement.row_to_insert.as_ref().unwrap();
-            let mut cursor = Cursor::table_end(table)?;
-            leaf_node_insert(&mut cursor, row.id, row)?;
+        assert_eq!(seen, vec![1, 5, 6]);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let (mut table, path) = open_temp_db("get_many");
+        build_two_leaf_tree(&mut table, &[1, 2, 3], &[7, 9]);
+
+        assert_eq!(
+            table.get_many(&[9, 4, 1, 7, 1, 3]).unwrap(),
+            vec![
+                Some(test_row(9)),
+                None,
+                Some(test_row(1)),
+                Some(test_row(7)),
+                Some(test_row(1)),
+                Some(test_row(3)),
+            ]
+        );
+        assert!(table.get_many(&[]).unwrap().is_empty());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_write_order_survives_crash_at_every_point() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let (mut table, path) = open_temp_db("write_order");
+        insert_rows(&mut table, &[test_row(1), test_row(2)]);
+        db_close(&mut table).unwrap();
+        drop(table);
+
+        let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+        build_two_leaf_tree(&mut table, &[1, 2], &[5]);
+        let order = table.write_order().unwrap();
+        assert_eq!(order, vec![1, 2, 0]);
+
+        for k in 0..=order.len() {
+            let crash_path = temp_db_path(&format!("write_order_crash_{}", k));
+            std::fs::copy(&path, &crash_path).unwrap();
+            {
+                let mut file = OpenOptions::new().write(true).open(&crash_path).unwrap();
+                for &page_num in &order[..k] {
+                    file.seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))
+                        .unwrap();
+                    file.write_all(table.pager.page_ref(page_num).unwrap())
+                        .unwrap();
+                }
+            }
+
+            let mut crashed = db_open_with_pager(Pager::new_no_sync(&crash_path).unwrap()).unwrap();
+            let num_pages = crashed.pager.num_pages;
+            for (page_num, node_type) in crashed.iter_pages_with_type().unwrap() {
+                if node_type == NodeType::Internal {
+                    let node = crashed.pager.page_ref(page_num).unwrap();
+                    for i in 0..=internal_node_num_keys(node) {
+                        assert!(
+                            (internal_node_child(node, i) as usize) < num_pages,
+                            "k = {}",
+                            k
+                        );
+                    }
+                }
+            }
+            assert!(crashed.contains(1).unwrap());
+            assert!(crashed.contains(2).unwrap());
+
+            drop(crashed);
+            let _ = std::fs::remove_file(crash_path);
         }
-        StatementType::Select => {
-            let mut cursor = Cursor::table_start(table)?;
-            while !cursor.end_of_table {
-                let slot = cursor.value()?;
-                let row = deserialize_row(slot);
 
-                println!("({}, {}, {})", row.id, row.username, row.email);
+        table.set_page_write_barrier();
+        db_close(&mut table).unwrap();
+        let (leaves, internal) = table.count_pages_by_type().unwrap();
+        assert_eq!((leaves, internal), (2, 1));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_sample() {
+        let (mut table, path) = open_temp_db("sample");
+        build_two_leaf_tree(&mut table, &[1, 2, 3, 4], &[5, 6, 7, 8, 9]);
+
+        let sample = table.sample(4, Some(42)).unwrap();
+        assert_eq!(sample, table.sample(4, Some(42)).unwrap());
+        assert_eq!(sample.len(), 4);
+        assert!(sample.windows(2).all(|pair| pair[0].id < pair[1].id));
+        assert!(sample.iter().all(|row| *row == test_row(row.id)));
 
-                cursor.advance()?;
+        let everything: Vec<Row> = (1..=9).map(test_row).collect();
+        assert_eq!(table.sample(20, Some(42)).unwrap(), everything);
+        assert!(table.sample(0, None).unwrap().is_empty());
+
+        let statement = match prepare_statement("select sample 5 seed 42") {
+            PrepareResult::Success(statement) => statement,
+            _ => panic!("expected a parsed sample"),
+        };
+        match execute_statement(&statement, &mut table).unwrap() {
+            ExecuteResult::Rows(result) => {
+                assert_eq!(result.rows, table.sample(5, Some(42)).unwrap())
             }
+            other => panic!("unexpected {:?}", other),
         }
+        assert!(matches!(
+            prepare_statement("select sample five"),
+            PrepareResult::SyntaxError
+        ));
+
+        let _ = std::fs::remove_file(path);
     }
-    Ok(ExecuteResult::Success)
 }
 
-#[cfg(test)]
-mod tests {
+// `cargo +nightly bench --features nightly`; benches/serialize.rs covers stable
+#[cfg(all(test, feature = "nightly"))]
+mod benches {
+    extern crate test;
+
     use super::*;
+    use test::{Bencher, black_box};
 
-    #[test]
-    fn test_row_serialization() {
-        let row = Row {
+    fn bench_row() -> Row {
+        Row {
             id: 1,
-            username: "john".to_string(),
-            email: "john@test.com".to_string(),
-        };
+            username: "user1".to_string(),
+            email: "person1@example.com".to_string(),
+        }
+    }
 
-        let mut buffer = [0u8; ROW_SIZE];
-        serialize_row(&row, &mut buffer);
-        let deser_row = deserialize_row(&buffer);
+    #[bench]
+    fn bench_serialize_row(b: &mut Bencher) {
+        let row = bench_row();
+        let mut buf = [0u8; ROW_SIZE];
+        b.bytes = ROW_SIZE as u64;
+        b.iter(|| serialize_row(black_box(&row), black_box(&mut buf)).unwrap());
+    }
 
-        assert_eq!(row, deser_row);
+    #[bench]
+    fn bench_deserialize_row(b: &mut Bencher) {
+        let mut buf = [0u8; ROW_SIZE];
+        serialize_row(&bench_row(), &mut buf).unwrap();
+        b.bytes = ROW_SIZE as u64;
+        b.iter(|| deserialize_row(black_box(&buf)));
+    }
+
+    // the biggest tree a file can hold: an internal root on page 0 over full leaves on
+    // every other page. leaves don't split, so it is built by hand
+    fn full_table(name: &str) -> (Table, String) {
+        let path = std::env::temp_dir()
+            .join(format!("rsdb_bench_{}_{}.db", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&path);
+        let mut table = db_open_with_pager(Pager::new_no_sync(&path).unwrap()).unwrap();
+
+        let per_leaf = LEAF_NODE_MAX_CELLS as u32;
+        for page_num in 1..TABLE_MAX_PAGES {
+            let leaf = table.pager.get_page(page_num).unwrap();
+            initialize_leaf_node(leaf);
+            leaf[IS_ROOT_OFFSET] = 0;
+            let mut cursor = Cursor {
+                version: table.structure_version,
+                table: &mut table,
+                page_num,
+                cell_num: 0,
+                end_of_table: true,
+                path: vec![],
+            };
+            let first = (page_num as u32 - 1) * per_leaf + 1;
+            for id in first..first + per_leaf {
+                let row = Row {
+                    id,
+                    username: format!("user{}", id),
+                    email: format!("person{}@example.com", id),
+                };
+                Table::insert_at_cursor(&mut cursor, &row).unwrap();
+            }
+        }
+
+        let num_keys = TABLE_MAX_PAGES as u32 - 2;
+        let root = table.pager.get_page(0).unwrap();
+        initialize_internal_node(root);
+        root[IS_ROOT_OFFSET] = 1;
+        root[INTERNAL_NODE_NUM_KEYS_OFFSET..INTERNAL_NODE_NUM_KEYS_OFFSET + 4]
+            .copy_from_slice(&num_keys.to_le_bytes());
+        for i in 0..num_keys {
+            let cell = internal_node_cell_offset(i);
+            root[cell..cell + 4].copy_from_slice(&(i + 1).to_le_bytes());
+            root[cell + 4..cell + 8].copy_from_slice(&((i + 1) * per_leaf).to_le_bytes());
+        }
+        root[INTERNAL_NODE_RIGHT_CHILD_OFFSET..INTERNAL_NODE_RIGHT_CHILD_OFFSET + 4]
+            .copy_from_slice(&(TABLE_MAX_PAGES as u32 - 1).to_le_bytes());
+        table.repair_subtree_sizes().unwrap();
+
+        (table, path)
+    }
+
+    // row_count against parallel_count on a full table: TABLE_MAX_PAGES caps a file
+    // at 100 pages, so that is as large as a table gets
+    #[bench]
+    fn bench_row_count_full_table(b: &mut Bencher) {
+        let (mut table, path) = full_table("row_count");
+        b.iter(|| black_box(table.row_count().unwrap()));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[bench]
+    fn bench_parallel_count_full_table(b: &mut Bencher) {
+        let (mut table, path) = full_table("parallel_count");
+        assert_eq!(table.parallel_count(4).unwrap(), table.row_count().unwrap());
+        b.iter(|| black_box(table.parallel_count(4).unwrap()));
+        let _ = std::fs::remove_file(path);
     }
 }
@@ -1,77 +1,341 @@
-use rsdb::{MetaCommandResult, PrepareResult};
-use rsdb::{db_close, db_open, do_meta_command, execute_statement, prepare_statement, print_btree, print_constants};
+use rsdb::{ExecuteResult, MetaCommandResult, OutputMode, ParseMode, PrepareResult};
+use rsdb::{
+    Pager, Table, db_close, db_open_with_pager, do_meta_command, execute_statement, format_rows,
+    format_rows_as_inserts, prepare_statement_with_mode, print_btree, print_constants,
+    print_free_list, print_integrity_check, print_size, print_stats, strip_comment,
+};
 use std::env;
-use std::io::{self, Write};
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::time::Instant;
+
+// exit codes: 0 on success, including `.exit` from the repl. a failed statement only
+// changes the exit code under --exec; the repl reports it and carries on.
+const EXIT_RUNTIME_ERROR: i32 = 1;
+const EXIT_USAGE_ERROR: i32 = 2;
+
+const USAGE: &str = "usage: rsdb <file> [--no-fsync] [--strict] [--readonly] [--exec <statement>]";
+
+// repl state the meta commands can change
+struct Session {
+    parse_mode: ParseMode,
+    align: bool,
+    output_mode: OutputMode,
+    // print page i/o and elapsed time after each statement
+    timer: bool,
+    // where .page keeps its cursor token between sessions, next to the database
+    cursor_path: String,
+}
+
+enum LineOutcome {
+    Ok,
+    Failed,
+    Exit,
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("must supply a database filename.");
-        std::process::exit(1);
+    let mut no_fsync = false;
+    let mut read_only = false;
+    let mut parse_mode = ParseMode::Lenient;
+    let mut exec = None;
+    let mut filename = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-fsync" => no_fsync = true,
+            "--readonly" => read_only = true,
+            "--strict" => parse_mode = ParseMode::Strict,
+            "--exec" => match args.next() {
+                Some(statement) => exec = Some(statement),
+                None => usage_error("--exec needs a statement."),
+            },
+            flag if flag.starts_with("--") => usage_error(&format!("unknown flag {}.", flag)),
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage_error("only one database filename can be given."),
+        }
     }
+    let Some(filename) = filename else {
+        usage_error("must supply a database filename.");
+    };
 
-    let filename = &args[1];
-    let mut table = match db_open(filename) {
+    let pager = if read_only {
+        Pager::new_read_only(&filename)
+    } else if no_fsync {
+        Pager::new_no_sync(&filename)
+    } else {
+        Pager::new(&filename)
+    };
+    let mut table = match pager.and_then(db_open_with_pager) {
         Ok(t) => t,
         Err(e) => {
             eprintln!("error opening database: {}", e);
-            std::process::exit(1);
+            std::process::exit(EXIT_RUNTIME_ERROR);
         }
     };
 
-    loop {
-        print!("db > ");
-        io::stdout().flush().unwrap();
-
-        let mut input = String::new();
-        io::stdin()
-            .read_line(&mut input)
-            .expect("failed to read line");
+    let mut session = Session {
+        parse_mode,
+        align: false,
+        output_mode: OutputMode::Tuples,
+        timer: false,
+        cursor_path: format!("{}.cursor", filename),
+    };
+    let mut failed = false;
+    match exec {
+        Some(input) => {
+            let input = strip_comment(input.trim());
+            failed = matches!(
+                run_line(input, &mut table, &mut session),
+                LineOutcome::Failed
+            );
+        }
+        None => loop {
+            print!("db > ");
+            io::stdout().flush().unwrap();
 
-        let input = input.trim();
+            let mut input = String::new();
+            let bytes_read = io::stdin()
+                .read_line(&mut input)
+                .expect("failed to read line");
+            if bytes_read == 0 {
+                break;
+            }
 
-        if input.starts_with('.') {
-            match do_meta_command(input) {
-                MetaCommandResult::Exit => break,
-                MetaCommandResult::PrintConstants => {
-                    print_constants();
-                    continue;
-                }
-                MetaCommandResult::PrintBtree => {
-                    if let Err(e) = print_btree(&mut table) {
-                        println!("error: {}", e);
-                    }
-                    continue;
-                }
-                MetaCommandResult::UnrecognizedCommand => {
-                    println!("unrecognized command: {}", input);
-                    continue;
-                }
+            let input = strip_comment(input.trim());
+            if input.is_empty() {
+                continue;
             }
+            if let LineOutcome::Exit = run_line(input, &mut table, &mut session) {
+                break;
+            }
+        },
+    }
+
+    if let Err(e) = db_close(&mut table) {
+        eprintln!("error closing database: {}", e);
+        std::process::exit(EXIT_RUNTIME_ERROR);
+    }
+    if failed {
+        std::process::exit(EXIT_RUNTIME_ERROR);
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("{}", USAGE);
+    std::process::exit(EXIT_USAGE_ERROR);
+}
+
+// prints the error if there is one
+fn report(result: io::Result<()>) -> LineOutcome {
+    match result {
+        Ok(()) => LineOutcome::Ok,
+        Err(e) => {
+            println!("error: {}", e);
+            LineOutcome::Failed
         }
+    }
+}
 
-        match prepare_statement(input) {
-            PrepareResult::Success(statement) => match execute_statement(&statement, &mut table) {
-                Ok(_) => println!("executed."),
-                Err(e) => println!("error executing statement: {}", e),
-            },
-            PrepareResult::UnrecognizedStatement => {
-                println!("unrecognized keyword at start of '{}'.", input);
+fn run_line(input: &str, table: &mut Table, session: &mut Session) -> LineOutcome {
+    if input.starts_with('.') {
+        return match do_meta_command(input) {
+            MetaCommandResult::Exit => LineOutcome::Exit,
+            MetaCommandResult::PrintConstants => {
+                print_constants();
+                LineOutcome::Ok
+            }
+            MetaCommandResult::PrintBtree => report(print_btree(table)),
+            MetaCommandResult::PrintStats => report(print_stats(table)),
+            MetaCommandResult::PrintFreeList => report(print_free_list(table)),
+            MetaCommandResult::CheckIntegrity => report(print_integrity_check(table)),
+            MetaCommandResult::PrintSize => report(print_size(table)),
+            MetaCommandResult::ImportFixed(path) => {
+                report(table.import_fixed(&path).map(|(imported, skipped)| {
+                    println!("imported {} rows, skipped {}.", imported, skipped)
+                }))
+            }
+            MetaCommandResult::Dump(page_num) => {
+                report(table.explain_leaf(page_num).map(|dump| print!("{}", dump)))
+            }
+            MetaCommandResult::Page(n) => report(next_page(table, session, n)),
+            MetaCommandResult::PageReset => report(forget_page(session)),
+            MetaCommandResult::Seed(n) => {
+                let start = Instant::now();
+                let seeded = table.seed(n);
+                let elapsed = start.elapsed();
+                report(
+                    seeded
+                        .and_then(|seeded| Ok((seeded, table.row_count()?)))
+                        .map(|(seeded, total)| {
+                            println!(
+                                "seeded {} rows in {:.2?}, {} rows total.",
+                                seeded, elapsed, total
+                            )
+                        }),
+                )
             }
-            PrepareResult::SyntaxError => {
-                println!("syntax error. could not parse statement.");
+            MetaCommandResult::DebugOn => {
+                table.debug = true;
+                LineOutcome::Ok
             }
-            PrepareResult::StringTooLong => {
-                println!("string is too long.");
+            MetaCommandResult::DebugOff => {
+                table.debug = false;
+                LineOutcome::Ok
             }
-            PrepareResult::NegativeId => {
-                println!("id must be positive.");
+            MetaCommandResult::TimerOn => {
+                session.timer = true;
+                LineOutcome::Ok
             }
+            MetaCommandResult::TimerOff => {
+                session.timer = false;
+                LineOutcome::Ok
+            }
+            MetaCommandResult::AlignOn => {
+                session.align = true;
+                LineOutcome::Ok
+            }
+            MetaCommandResult::AlignOff => {
+                session.align = false;
+                LineOutcome::Ok
+            }
+            MetaCommandResult::SetMode(mode) => {
+                session.output_mode = mode;
+                LineOutcome::Ok
+            }
+            MetaCommandResult::UnrecognizedCommand => {
+                println!("unrecognized command: {}", input);
+                LineOutcome::Failed
+            }
+        };
+    }
+
+    let before = table.pager.page_io();
+    let start = Instant::now();
+    let outcome = run_statement(input, table, session);
+    if session.timer {
+        let io = table.pager.page_io().since(&before);
+        println!(
+            "pages read: {} ({} from disk), pages written: {}, elapsed: {:.2?}",
+            io.reads,
+            io.disk_reads,
+            io.writes,
+            start.elapsed()
+        );
+    }
+    outcome
+}
+
+// prints the next `n` rows from where the last .page, in this session or an earlier
+// one, left off, and saves the new position. the saved token is dropped once the table
+// runs out, so the following .page starts over
+fn next_page(table: &mut Table, session: &Session, n: usize) -> io::Result<()> {
+    let token = match fs::read(&session.cursor_path) {
+        Ok(bytes) => Some(bytes.try_into().map_err(|_| {
+            io::Error::new(ErrorKind::InvalidData, "saved cursor token is corrupt")
+        })?),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    let (rows, next) = table.page_from_token(token, n)?;
+    let lines = match session.output_mode {
+        OutputMode::Tuples => format_rows(&rows, session.align),
+        OutputMode::Insert => format_rows_as_inserts(&rows),
+    };
+    for line in lines {
+        println!("{}", line);
+    }
+    match next {
+        Some(token) => fs::write(&session.cursor_path, token),
+        None => {
+            println!("end of table.");
+            forget_page(session)
         }
     }
+}
 
-    if let Err(e) = db_close(&mut table) {
-        eprintln!("error closing database: {}", e);
-        std::process::exit(1);
+fn forget_page(session: &Session) -> io::Result<()> {
+    match fs::remove_file(&session.cursor_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn run_statement(input: &str, table: &mut Table, session: &Session) -> LineOutcome {
+    match prepare_statement_with_mode(input, session.parse_mode) {
+        PrepareResult::Success(statement) => match execute_statement(&statement, table) {
+            Ok(ExecuteResult::Rows(result)) => {
+                let lines = match session.output_mode {
+                    OutputMode::Tuples => format_rows(&result.rows, session.align),
+                    OutputMode::Insert => format_rows_as_inserts(&result.rows),
+                };
+                for line in lines {
+                    println!("{}", line);
+                }
+                println!("executed.");
+                LineOutcome::Ok
+            }
+            Ok(ExecuteResult::Success) => {
+                println!("executed.");
+                LineOutcome::Ok
+            }
+            Ok(ExecuteResult::DuplicateKey) => {
+                println!("error: duplicate key.");
+                LineOutcome::Failed
+            }
+            Ok(ExecuteResult::LeafFull) => {
+                println!("error: leaf node full.");
+                LineOutcome::Failed
+            }
+            Ok(ExecuteResult::NotFound) => {
+                println!("error: row not found.");
+                LineOutcome::Failed
+            }
+            Ok(ExecuteResult::Analyze(stats)) => {
+                println!("plan: {}", stats.plan);
+                println!("rows: {}", stats.rows);
+                println!(
+                    "pages read: {} ({} from disk), pages written: {}",
+                    stats.io.reads, stats.io.disk_reads, stats.io.writes
+                );
+                println!("elapsed: {:.2?}", stats.elapsed);
+                println!("executed.");
+                LineOutcome::Ok
+            }
+            Ok(ExecuteResult::LastInsertId(id)) => {
+                match id {
+                    Some(id) => println!("{}", id),
+                    None => println!("null"),
+                }
+                println!("executed.");
+                LineOutcome::Ok
+            }
+            Err(e) => {
+                println!("error executing statement: {}", e);
+                LineOutcome::Failed
+            }
+        },
+        PrepareResult::UnrecognizedStatement => {
+            println!("unrecognized keyword at start of '{}'.", input);
+            LineOutcome::Failed
+        }
+        PrepareResult::SyntaxError => {
+            println!("syntax error. could not parse statement.");
+            LineOutcome::Failed
+        }
+        PrepareResult::StringTooLong => {
+            println!("string is too long.");
+            LineOutcome::Failed
+        }
+        PrepareResult::NegativeId => {
+            println!("id must be positive.");
+            LineOutcome::Failed
+        }
+        PrepareResult::UnexpectedToken(token) => {
+            println!("syntax error near '{}'.", token);
+            LineOutcome::Failed
+        }
     }
 }
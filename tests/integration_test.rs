@@ -1,3 +1,6 @@
+// drives the repl binary, which only exists with the `cli` feature
+#![cfg(feature = "cli")]
+
 use std::io::Write;
 use std::process::{Command, Stdio};
 
@@ -10,36 +13,9 @@ fn run_script(commands: Vec<&str>) -> Vec<String> {
         .as_nanos();
     let db_file = format!("test_{}.db", timestamp);
 
-    let mut child = Command::new("cargo")
-        .args(&["run", "--quiet", "--", &db_file])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .expect("failed to spawn child process");
-
-    {
-        let stdin = child.stdin.as_mut().expect("failed to open stdin");
-        for cmd in commands {
-            writeln!(stdin, "{}", cmd).expect("failed to write to stdin");
-        }
-    }
-
-    let output = child.wait_with_output().expect("failed to wait on child");
-    let stdout = String::from_utf8_lossy(&output.stdout);
-
-    stdout
-        .lines()
-        .map(|line| {
-            if line.starts_with("db > ") {
-                &line[5..]
-            } else {
-                line
-            }
-        })
-        .filter(|line| !line.trim().is_empty())
-        .map(|s| s.to_string())
-        .collect()
+    let result = run_script_with_file(commands, &db_file);
+    let _ = std::fs::remove_file(&db_file);
+    result
 }
 
 #[test]
@@ -184,9 +160,559 @@ fn test_persistence_multiple_sessions() {
     let _ = fs::remove_file(&db_file);
 }
 
+#[test]
+fn test_stats_page_counts() {
+    let mut commands = vec![];
+    for i in 1..=13 {
+        commands.push(format!("insert {} user{} person{}@example.com", i, i, i));
+    }
+    commands.push(".stats".to_string());
+    commands.push(".exit".to_string());
+
+    let script: Vec<&str> = commands.iter().map(|s| s.as_str()).collect();
+    let result = run_script(script);
+
+    let stat = |name: &str| -> usize {
+        result
+            .iter()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap()
+    };
+
+    // every leaf holds at most LEAF_NODE_MAX_CELLS rows
+    assert!(stat("leaf_pages:") >= 13usize.div_ceil(rsdb::LEAF_NODE_MAX_CELLS));
+    assert_eq!(stat("internal_pages:"), 0);
+}
+
+#[test]
+fn test_import_fixed() {
+    use rsdb::{ROW_SIZE, Row, serialize_row};
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dump_file = format!("test_import_{}.bin", timestamp);
+
+    let mut dump = vec![];
+    for id in [1, 2] {
+        let row = Row {
+            id,
+            username: format!("user{}", id),
+            email: format!("person{}@example.com", id),
+        };
+        let mut record = [0u8; ROW_SIZE];
+        serialize_row(&row, &mut record).unwrap();
+        dump.extend_from_slice(&record);
+    }
+    // invalid utf-8 in the username field, right after its length byte
+    let mut bad = [0u8; ROW_SIZE];
+    bad[4] = 1;
+    bad[5] = 0xff;
+    dump.extend_from_slice(&bad);
+    fs::write(&dump_file, &dump).unwrap();
+
+    let import = format!(".import-fixed {}", dump_file);
+    let result = run_script(vec![&import, "select", ".exit"]);
+
+    assert_eq!(result[0], "imported 2 rows, skipped 1.");
+    assert_eq!(result[1], "(1, user1, person1@example.com)");
+    assert_eq!(result[2], "(2, user2, person2@example.com)");
+
+    let _ = fs::remove_file(&dump_file);
+}
+
+#[test]
+fn test_duplicate_key_and_upsert() {
+    let result = run_script(vec![
+        "insert 1 user1 person1@example.com",
+        "insert 1 user1 other@example.com",
+        "insert 1 ignored ignored@example.com on conflict do update set email = 'new@example.com'",
+        "insert 2 user2 person2@example.com on conflict do update set email = unused@example.com",
+        "select",
+        ".exit",
+    ]);
+
+    assert_eq!(result[0], "executed.");
+    assert_eq!(result[1], "error: duplicate key.");
+    assert_eq!(result[2], "executed.");
+    assert_eq!(result[3], "executed.");
+    assert_eq!(result[4], "(1, user1, new@example.com)");
+    assert_eq!(result[5], "(2, user2, person2@example.com)");
+}
+
+#[test]
+fn test_debug_mode() {
+    let result = run_script(vec![
+        ".debug on",
+        "insert 1 user1 person1@example.com",
+        "insert 2 user2 person2@example.com",
+        "select",
+        ".debug off",
+        "insert 3 user3 person3@example.com",
+        ".exit",
+    ]);
+
+    assert_eq!(
+        result,
+        vec![
+            "page_num: 0",
+            "cell_num: 0",
+            "num_pages: 1",
+            "split: no",
+            "pages_dirtied: 0",
+            "executed.",
+            "page_num: 0",
+            "cell_num: 1",
+            "num_pages: 1",
+            "split: no",
+            "pages_dirtied: 0",
+            "executed.",
+            "pages_scanned: 1",
+            "(1, user1, person1@example.com)",
+            "(2, user2, person2@example.com)",
+            "executed.",
+            "executed.",
+        ]
+    );
+}
+
+#[test]
+fn test_integrity_check_after_inserts() {
+    let result = run_script(vec![
+        "insert 3 user3 person3@example.com",
+        "insert 1 user1 person1@example.com",
+        "insert 2 user2 person2@example.com",
+        ".check",
+        "select",
+        ".exit",
+    ]);
+
+    assert_eq!(result[3], "ok.");
+    assert_eq!(result[4], "(1, user1, person1@example.com)");
+    assert_eq!(result[5], "(2, user2, person2@example.com)");
+    assert_eq!(result[6], "(3, user3, person3@example.com)");
+}
+
+#[test]
+fn test_last_insert_id() {
+    let result = run_script(vec![
+        "select last_insert_id()",
+        "insert 5 user5 person5@example.com",
+        "insert 3 user3 person3@example.com",
+        "insert 3 user3 person3@example.com",
+        "select last_insert_id()",
+        ".exit",
+    ]);
+
+    assert_eq!(result[0], "null");
+    assert_eq!(result[4], "error: duplicate key.");
+    assert_eq!(result[5], "3");
+    assert_eq!(result[6], "executed.");
+}
+
+#[test]
+fn test_size_command() {
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let db_file = format!("test_size_{}.db", timestamp);
+
+    run_script_with_file(
+        vec!["insert 1 user1 person1@example.com", ".exit"],
+        &db_file,
+    );
+    let result = run_script_with_file(vec![".size", ".exit"], &db_file);
+
+    assert_eq!(
+        result,
+        vec![
+            format!("row_size_bytes: {}", rsdb::ROW_SIZE),
+            format!("estimated_file_size: {}", rsdb::PAGE_SIZE),
+            format!("actual_file_size: {}", rsdb::PAGE_SIZE),
+        ]
+    );
+
+    let _ = fs::remove_file(&db_file);
+}
+
+#[test]
+fn test_constants_command() {
+    use rsdb::{
+        LEAF_NODE_CELL_SIZE, LEAF_NODE_HEADER_SIZE, LEAF_NODE_MAX_CELLS, LEAF_NODE_SPACE_FOR_CELLS,
+        PAGE_SIZE, ROW_SIZE,
+    };
+    use std::collections::HashMap;
+
+    let result = run_script(vec![".constants", ".exit"]);
+    let printed: HashMap<&str, usize> = result
+        .iter()
+        .map(|line| {
+            let (name, value) = line.split_once(": ").expect("NAME: value");
+            (name, value.parse().expect("a number"))
+        })
+        .collect();
+
+    let expected = HashMap::from([
+        ("ROW_SIZE", ROW_SIZE),
+        ("LEAF_NODE_HEADER_SIZE", LEAF_NODE_HEADER_SIZE),
+        ("LEAF_NODE_CELL_SIZE", LEAF_NODE_CELL_SIZE),
+        ("PAGE_SIZE", PAGE_SIZE),
+        ("LEAF_NODE_SPACE_FOR_CELLS", LEAF_NODE_SPACE_FOR_CELLS),
+        ("LEAF_NODE_MAX_CELLS", LEAF_NODE_MAX_CELLS),
+    ]);
+    assert_eq!(printed, expected);
+    assert_eq!(result.len(), expected.len());
+}
+
+#[test]
+fn test_comments() {
+    let result = run_script(vec![
+        "-- seed data",
+        "insert 1 john john@x.com -- new user",
+        "insert 2 jane 'jane--work@x.com'",
+        "select",
+        ".exit",
+    ]);
+
+    assert_eq!(
+        result,
+        vec![
+            "executed.",
+            "executed.",
+            "(1, john, john@x.com)",
+            "(2, jane, jane--work@x.com)",
+            "executed.",
+        ]
+    );
+}
+
+#[test]
+fn test_seed() {
+    let result = run_script(vec![
+        "insert 1 user1 user1@example.com",
+        ".seed 3",
+        "select",
+        ".exit",
+    ]);
+
+    assert_eq!(result[0], "executed.");
+    assert!(result[1].starts_with("seeded 3 rows in "));
+    assert!(result[1].ends_with(", 4 rows total."));
+    assert_eq!(
+        result[2..],
+        [
+            "(1, user1, user1@example.com)",
+            "(2, user2, user2@example.com)",
+            "(3, user3, user3@example.com)",
+            "(4, user4, user4@example.com)",
+            "executed.",
+        ]
+    );
+}
+
+#[test]
+fn test_update() {
+    let result = run_script(vec![
+        "insert 1 user1 person1@example.com",
+        "update 1 renamed renamed@example.com",
+        "update 2 nobody nobody@example.com",
+        "select",
+        ".exit",
+    ]);
+
+    assert_eq!(
+        result,
+        vec![
+            "executed.",
+            "executed.",
+            "error: row not found.",
+            "(1, renamed, renamed@example.com)",
+            "executed.",
+        ]
+    );
+}
+
+#[test]
+fn test_align() {
+    let result = run_script(vec![
+        "insert 1 al a@x.com",
+        "insert 20 bartholomew bart@example.com",
+        "insert 300 cy cy@y.org",
+        ".align on",
+        "select",
+        ".align off",
+        "select",
+        ".exit",
+    ]);
+
+    assert_eq!(
+        result[3..7],
+        [
+            "(  1, al,          a@x.com)",
+            "( 20, bartholomew, bart@example.com)",
+            "(300, cy,          cy@y.org)",
+            "executed.",
+        ]
+    );
+    assert_eq!(result[7], "(1, al, a@x.com)");
+}
+
+#[test]
+fn test_mode_insert() {
+    let setup = [
+        "insert 1 alice alice@example.com",
+        "insert 2 bob bob@example.com",
+        "insert 3 alicia 'alicia--home@example.com'",
+    ];
+    let mut commands = setup.to_vec();
+    commands.extend([
+        ".mode insert",
+        "select where prefix = ali",
+        ".mode tuples",
+        "select where prefix = ali",
+        ".exit",
+    ]);
+    let result = run_script(commands);
+
+    let inserts = &result[3..5];
+    assert_eq!(
+        inserts,
+        [
+            "insert 1 'alice' 'alice@example.com'",
+            "insert 3 'alicia' 'alicia--home@example.com'",
+        ]
+    );
+    let selected = &result[6..8];
+
+    // replaying the export into an empty db gives back exactly the selected rows
+    let mut replay: Vec<&str> = inserts.iter().map(|s| s.as_str()).collect();
+    replay.extend(["select", ".exit"]);
+    let replayed = run_script(replay);
+    assert_eq!(replayed[2..4], *selected);
+    assert_eq!(replayed[4], "executed.");
+}
+
+#[test]
+fn test_keyset_pagination() {
+    use rsdb::{LEAF_NODE_MAX_CELLS, Table};
+
+    // leaves don't split yet, so one full leaf is the largest table there is
+    let (mut table, _guard) = Table::with_temp().unwrap();
+    table.seed(LEAF_NODE_MAX_CELLS).unwrap();
+
+    let mut pages = vec![];
+    let mut next_id = 0;
+    loop {
+        let page = table.scan_forward_from(next_id, 3).unwrap();
+        let Some(last) = page.last() else {
+            break;
+        };
+        next_id = last.id + 1;
+        pages.push(page.iter().map(|row| row.id).collect::<Vec<_>>());
+    }
+
+    assert_eq!(pages.len(), LEAF_NODE_MAX_CELLS.div_ceil(3));
+    assert!(pages[..pages.len() - 1].iter().all(|page| page.len() == 3));
+    assert_eq!(
+        pages.concat(),
+        (1..=LEAF_NODE_MAX_CELLS as u32).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_page_resumes_across_sessions() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let db_file = format!("test_page_{}.db", timestamp);
+    let cursor_file = format!("{}.cursor", db_file);
+    let row = |id: u32| format!("({}, user{}, user{}@example.com)", id, id, id);
+
+    run_script_with_file(vec![".seed 5", ".exit"], &db_file);
+    let first = run_script_with_file(vec![".page 2", ".exit"], &db_file);
+    assert_eq!(first, vec![row(1), row(2)]);
+    assert!(std::path::Path::new(&cursor_file).exists());
+
+    // a new process picks up where the last one stopped
+    let second = run_script_with_file(vec![".page 2", ".page 2", ".exit"], &db_file);
+    assert_eq!(
+        second,
+        vec![row(3), row(4), row(5), "end of table.".to_string()]
+    );
+    assert!(!std::path::Path::new(&cursor_file).exists());
+
+    run_script_with_file(vec![".page 1", ".exit"], &db_file);
+    let reset = run_script_with_file(vec![".page reset", ".page 1", ".exit"], &db_file);
+    assert_eq!(reset, vec![row(1)]);
+
+    let _ = std::fs::remove_file(&cursor_file);
+    let _ = std::fs::remove_file(&db_file);
+}
+
+#[test]
+fn test_readonly_flag() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let db_file = format!("test_readonly_{}.db", timestamp);
+
+    run_script_with_file(vec!["insert 1 alice alice@x.com", ".exit"], &db_file);
+    let result = run_script_with_args(
+        vec![
+            "select",
+            "insert 2 bob bob@x.com",
+            "update 1 alice new@x.com",
+            ".exit",
+        ],
+        &db_file,
+        &["--readonly"],
+    );
+    assert_eq!(
+        result,
+        vec![
+            "(1, alice, alice@x.com)",
+            "executed.",
+            "error executing statement: database is open read-only",
+            "error executing statement: database is open read-only",
+        ]
+    );
+
+    let result = run_script_with_file(vec!["select", ".exit"], &db_file);
+    assert_eq!(result, vec!["(1, alice, alice@x.com)", "executed."]);
+
+    let _ = std::fs::remove_file(&db_file);
+}
+
+#[test]
+fn test_row_at_rank() {
+    use rsdb::{LEAF_NODE_MAX_CELLS, Table};
+
+    let (mut table, _guard) = Table::with_temp().unwrap();
+    assert_eq!(table.row_at_rank(0).unwrap(), None);
+
+    table.seed(LEAF_NODE_MAX_CELLS).unwrap();
+    let ranked: Vec<u32> = (0..LEAF_NODE_MAX_CELLS)
+        .map(|rank| table.row_at_rank(rank).unwrap().unwrap().id)
+        .collect();
+    assert_eq!(ranked, (1..=LEAF_NODE_MAX_CELLS as u32).collect::<Vec<_>>());
+    assert_eq!(table.row_at_rank(LEAF_NODE_MAX_CELLS).unwrap(), None);
+}
+
+#[test]
+fn test_timer() {
+    let result = run_script(vec![
+        ".timer on",
+        "insert 1 alice alice@x.com",
+        "select",
+        ".timer off",
+        "select",
+        ".exit",
+    ]);
+    let timings: Vec<&String> = result
+        .iter()
+        .filter(|line| line.starts_with("pages read: "))
+        .collect();
+    assert_eq!(timings.len(), 2, "{:?}", result);
+    assert!(timings.iter().all(|line| line.contains("pages written: 0")));
+    assert_eq!(result.last().unwrap(), "executed.");
+}
+
+// runs the binary without stdin, returning its exit code and stdout lines
+fn run_binary(args: &[&str]) -> (Option<i32>, Vec<String>) {
+    let output = Command::new("cargo")
+        .args(["run", "--quiet", "--"])
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to run child process");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    (
+        output.status.code(),
+        stdout.lines().map(|s| s.to_string()).collect(),
+    )
+}
+
+#[test]
+fn test_exit_codes() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let db_file = format!("test_exit_codes_{}.db", timestamp);
+    let exec = |statement: &str| run_binary(&[&db_file, "--no-fsync", "--exec", statement]);
+
+    assert_eq!(
+        exec("insert 1 alice alice@x.com"),
+        (Some(0), vec!["executed.".to_string()])
+    );
+    assert_eq!(
+        exec("select"),
+        (
+            Some(0),
+            vec![
+                "(1, alice, alice@x.com)".to_string(),
+                "executed.".to_string()
+            ]
+        )
+    );
+    assert_eq!(
+        exec("insert 1 alice alice@x.com"),
+        (Some(1), vec!["error: duplicate key.".to_string()])
+    );
+    assert_eq!(
+        exec(&format!(".seed {}", rsdb::LEAF_NODE_MAX_CELLS - 1)).0,
+        Some(0)
+    );
+    assert_eq!(
+        exec("insert 100 bob bob@x.com"),
+        (Some(1), vec!["error: leaf node full.".to_string()])
+    );
+    assert_eq!(exec("delete 1").0, Some(1));
+    assert_eq!(exec(".nope").0, Some(1));
+
+    // usage errors
+    assert_eq!(run_binary(&[]).0, Some(2));
+    assert_eq!(run_binary(&[&db_file, "--exec"]).0, Some(2));
+    assert_eq!(run_binary(&[&db_file, "--bogus"]).0, Some(2));
+
+    // the repl exits 0 even after a failed statement
+    let mut child = Command::new("cargo")
+        .args(["run", "--quiet", "--", &db_file, "--no-fsync"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to spawn child process");
+    writeln!(child.stdin.as_mut().unwrap(), "delete 1\n.exit").unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+
+    let _ = std::fs::remove_file(&db_file);
+}
+
 fn run_script_with_file(commands: Vec<&str>, db_file: &str) -> Vec<String> {
+    run_script_with_args(commands, db_file, &[])
+}
+
+fn run_script_with_args(commands: Vec<&str>, db_file: &str, args: &[&str]) -> Vec<String> {
     let mut child = Command::new("cargo")
-        .args(&["run", "--quiet", "--", db_file])
+        .args(["run", "--quiet", "--", db_file, "--no-fsync"])
+        .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -205,13 +731,7 @@ fn run_script_with_file(commands: Vec<&str>, db_file: &str) -> Vec<String> {
 
     stdout
         .lines()
-        .map(|line| {
-            if line.starts_with("db > ") {
-                &line[5..]
-            } else {
-                line
-            }
-        })
+        .map(|line| line.trim_start_matches("db > "))
         .filter(|line| !line.trim().is_empty())
         .map(|s| s.to_string())
         .collect()